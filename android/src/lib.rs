@@ -73,8 +73,10 @@ impl eframe::App for App {
                 });
 
                 LOADED_FILE.lock().unwrap().take().map(|(name, data)| {
-                    RawResult::load_from_reader(Cursor::new(data))
-                        .map(|data| self.tester.load_file(Path::new(&name).to_owned(), data));
+                    match RawResult::load_from_reader(Cursor::new(data)) {
+                        Ok(data) => self.tester.load_file(Path::new(&name).to_owned(), data),
+                        Err(error) => self.tester.load_failed(Path::new(&name), error),
+                    }
                 });
 
                 self.tester.show(ctx, ui);
@@ -0,0 +1,169 @@
+use crate::Tester;
+use crusader_lib::discovery::{self, Server};
+use eframe::egui::{self, Button, Grid, RichText, Ui};
+use std::{net::SocketAddr, thread};
+use tokio::sync::oneshot;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BrowseTarget {
+    Client,
+    Monitor,
+}
+
+type Servers = Result<Vec<Server>, String>;
+
+pub struct ServerBrowser {
+    target: BrowseTarget,
+    servers: Option<Servers>,
+    done: Option<oneshot::Receiver<Servers>>,
+}
+
+impl ServerBrowser {
+    fn new(ctx: &egui::Context, target: BrowseTarget) -> Self {
+        let (tx, rx) = oneshot::channel();
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            tx.send(
+                discovery::discover(false, discovery::DISCOVER_PORT)
+                    .map_err(|error| error.to_string()),
+            )
+            .ok();
+            ctx.request_repaint();
+        });
+        ServerBrowser {
+            target,
+            servers: None,
+            done: Some(rx),
+        }
+    }
+}
+
+/// Formats the address of a server for the server address field.
+fn server_address(socket: SocketAddr) -> String {
+    match socket {
+        SocketAddr::V6(socket) if socket.scope_id() != 0 => {
+            format!("{}%{}", socket.ip(), socket.scope_id())
+        }
+        socket => socket.ip().to_string(),
+    }
+}
+
+impl Tester {
+    pub(crate) fn browse_button(&mut self, ctx: &egui::Context, ui: &mut Ui, target: BrowseTarget) {
+        if ui
+            .button("Browse")
+            .on_hover_text("List the servers on the local network")
+            .clicked()
+        {
+            self.browser = Some(ServerBrowser::new(ctx, target));
+        }
+    }
+
+    pub(crate) fn server_browser(&mut self, ctx: &egui::Context) {
+        let Some(browser) = self.browser.as_mut() else {
+            return;
+        };
+
+        if let Some(done) = browser.done.as_mut() {
+            if let Ok(servers) = done.try_recv() {
+                browser.servers = Some(servers);
+                browser.done = None;
+            }
+        }
+
+        let mut open = true;
+        let mut refresh = false;
+        let mut selected = None;
+
+        egui::Window::new("Local servers")
+            .open(&mut open)
+            .collapsible(false)
+            .show(ctx, |ui| {
+                match &browser.servers {
+                    None => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label("Searching..");
+                        });
+                    }
+                    Some(Err(error)) => {
+                        ui.label(format!("Error: {}", error));
+                    }
+                    Some(Ok(servers)) if servers.is_empty() => {
+                        ui.label("No servers found");
+                    }
+                    Some(Ok(servers)) => {
+                        Grid::new("server-browser")
+                            .striped(true)
+                            .num_columns(6)
+                            .show(ui, |ui| {
+                                ui.strong("Host");
+                                ui.strong("Label");
+                                ui.strong("Version");
+                                ui.strong("Addresses");
+                                ui.strong("Latency");
+                                ui.end_row();
+
+                                for server in servers {
+                                    ui.label(server.hostname.as_deref().unwrap_or("-"));
+                                    ui.label(server.label.as_deref().unwrap_or("-"));
+                                    if server.compatible() {
+                                        ui.label(&server.software_version);
+                                    } else {
+                                        ui.label(
+                                            RichText::new(&server.software_version)
+                                                .color(ui.visuals().warn_fg_color),
+                                        )
+                                        .on_hover_text(
+                                            format!(
+                                                "Incompatible protocol version {}",
+                                                server.protocol_version
+                                            ),
+                                        );
+                                    }
+                                    ui.label(
+                                        server
+                                            .addresses
+                                            .iter()
+                                            .map(|ip| ip.to_string())
+                                            .collect::<Vec<_>>()
+                                            .join("\n"),
+                                    );
+                                    ui.label(format!(
+                                        "{:.02} ms",
+                                        server.latency.as_secs_f64() * 1000.0
+                                    ));
+                                    if ui
+                                        .add_enabled(server.compatible(), Button::new("Use"))
+                                        .clicked()
+                                    {
+                                        selected = Some(server_address(server.socket));
+                                    }
+                                    ui.end_row();
+                                }
+                            });
+                    }
+                }
+
+                ui.separator();
+                ui.add_enabled_ui(browser.done.is_none(), |ui| {
+                    if ui.button("Refresh").clicked() {
+                        refresh = true;
+                    }
+                });
+            });
+
+        let target = browser.target;
+        if let Some(server) = selected {
+            match target {
+                BrowseTarget::Client => self.settings.client.server = server,
+                BrowseTarget::Monitor => self.settings.latency_monitor.server = server,
+            }
+            self.browser = None;
+        } else if !open {
+            self.browser = None;
+        } else if refresh {
+            self.browser = Some(ServerBrowser::new(ctx, target));
+        }
+    }
+}
@@ -1,8 +1,11 @@
-use crate::{Tab, Tester};
+use crate::{browser::BrowseTarget, Tab, Tester};
 use crusader_lib::{
+    auth::Secret,
+    discovery,
     file_format::RawResult,
     protocol,
     test::{self},
+    tls::{parse_fingerprint, Verification},
     with_time, Config,
 };
 use eframe::{
@@ -20,6 +23,10 @@ use tokio::sync::{
 #[serde(default)]
 pub struct ClientSettings {
     pub server: String,
+    pub token: String,
+    pub tls: bool,
+    pub tls_fingerprint: String,
+    pub lowest_latency: bool,
     pub download: bool,
     pub upload: bool,
     pub bidirectional: bool,
@@ -31,9 +38,14 @@ pub struct ClientSettings {
     pub throughput_sample_interval: u64,
     pub latency_peer: bool,
     pub latency_peer_server: String,
+    pub peer_tls: bool,
+    pub peer_tls_fingerprint: String,
     pub advanced: bool,
     pub idle_test: bool,
     pub idle_duration: f64,
+    pub notes: String,
+    /// Comma separated tags stored with results.
+    pub tags: String,
 }
 
 impl ClientSettings {
@@ -49,14 +61,60 @@ impl ClientSettings {
             bidirectional: self.bidirectional,
             ping_interval: Duration::from_millis(self.latency_sample_interval),
             throughput_interval: Duration::from_millis(self.throughput_sample_interval),
+            secret: Secret::from_token(&self.token),
+            tls: tls_verification(self.tls, &self.tls_fingerprint)
+                .ok()
+                .flatten(),
+            peer_tls: tls_verification(self.peer_tls, &self.peer_tls_fingerprint)
+                .ok()
+                .flatten(),
+            lowest_latency: self.lowest_latency,
+            discovery_port: discovery::DISCOVER_PORT,
         }
     }
 }
 
+pub(crate) fn tls_verification(
+    tls: bool,
+    fingerprint: &str,
+) -> Result<Option<Verification>, String> {
+    if !tls {
+        return Ok(None);
+    }
+    let fingerprint = fingerprint.trim();
+    if fingerprint.is_empty() {
+        Ok(Some(Verification::WebPki))
+    } else {
+        parse_fingerprint(fingerprint)
+            .map(|fingerprint| Some(Verification::Fingerprint(fingerprint)))
+            .map_err(|error| error.to_string())
+    }
+}
+
+pub(crate) fn tls_settings(ui: &mut Ui, tls: &mut bool, fingerprint: &mut String) {
+    ui.checkbox(tls, "TLS")
+        .on_hover_text("Use TLS for the connections to the server");
+    ui.add_enabled_ui(*tls, |ui| {
+        ui.add(
+            TextEdit::singleline(fingerprint)
+                .hint_text("(Certificate fingerprint)")
+                .desired_width(140.0),
+        )
+        .on_hover_text(
+            "Only accept a server certificate with this SHA-256 fingerprint. \
+            If empty the certificate is verified using the Mozilla root certificates",
+        );
+    });
+}
+
 impl Default for ClientSettings {
     fn default() -> Self {
         Self {
             server: String::new(),
+            token: String::new(),
+            tls: false,
+            tls_fingerprint: String::new(),
+            lowest_latency: false,
             download: true,
             upload: true,
             bidirectional: true,
@@ -68,9 +126,13 @@ impl Default for ClientSettings {
             throughput_sample_interval: 60,
             latency_peer: false,
             latency_peer_server: String::new(),
+            peer_tls: false,
+            peer_tls_fingerprint: String::new(),
             advanced: false,
             idle_test: false,
             idle_duration: 10.0,
+            notes: String::new(),
+            tags: String::new(),
         }
     }
 }
@@ -94,6 +156,20 @@ impl Tester {
         self.msgs.clear();
         self.msg_scrolled = 0;
 
+        if let Err(error) = tls_verification(
+            self.settings.client.tls,
+            &self.settings.client.tls_fingerprint,
+        )
+        .and_then(|_| {
+            tls_verification(
+                self.settings.client.peer_tls,
+                &self.settings.client.peer_tls_fingerprint,
+            )
+        }) {
+            self.msgs.push(with_time(&format!("Error: {error}")));
+            return;
+        }
+
         let (signal_done, done) = oneshot::channel();
         let (tx, rx) = mpsc::unbounded_channel();
 
@@ -108,6 +184,10 @@ impl Tester {
             config.bidirectional = false;
             config.download = false;
             config.upload = false;
+            config.secret = Secret::from_token(&self.settings.client.token);
+            config.tls = self.settings.client.config().tls;
+            config.peer_tls = self.settings.client.config().peer_tls;
+            config.lowest_latency = self.settings.client.lowest_latency;
             config
         } else {
             self.settings.client.config()
@@ -171,6 +251,11 @@ impl Tester {
                         TextEdit::singleline(&mut self.settings.client.latency_peer_server)
                             .hint_text("(Locate local peer)"),
                     );
+                    tls_settings(
+                        ui,
+                        &mut self.settings.client.peer_tls,
+                        &mut self.settings.client.peer_tls_fingerprint,
+                    );
                 });
             });
         }
@@ -352,6 +437,11 @@ impl Tester {
                         TextEdit::singleline(&mut self.settings.client.latency_peer_server)
                             .hint_text("(Locate local peer)"),
                     );
+                    tls_settings(
+                        ui,
+                        &mut self.settings.client.peer_tls,
+                        &mut self.settings.client.peer_tls_fingerprint,
+                    );
                 });
             });
         }
@@ -424,6 +514,25 @@ impl Tester {
                 {
                     self.start_client(ctx)
                 }
+                self.browse_button(ctx, ui, BrowseTarget::Client);
+                ui.add_enabled_ui(self.settings.client.server.trim().is_empty(), |ui| {
+                    ui.checkbox(&mut self.settings.client.lowest_latency, "Lowest latency")
+                        .on_hover_text(
+                            "Use the local server with the lowest latency instead of the first one to reply",
+                        );
+                });
+                ui.label("Token:");
+                ui.add(
+                    TextEdit::singleline(&mut self.settings.client.token)
+                        .password(true)
+                        .hint_text("(None)")
+                        .desired_width(100.0),
+                );
+                tls_settings(
+                    ui,
+                    &mut self.settings.client.tls,
+                    &mut self.settings.client.tls_fingerprint,
+                );
             });
 
             match self.client_state {
@@ -471,13 +580,40 @@ impl Tester {
                         self.latency_under_load_settings(ui, compact);
                     }
 
+                    ui.horizontal_wrapped(|ui| {
+                        ui.label("Notes:");
+                        ui.add(
+                            TextEdit::singleline(&mut self.settings.client.notes)
+                                .hint_text("(None)")
+                                .desired_width(200.0),
+                        )
+                        .on_hover_text("Notes stored with the result");
+                        ui.label("Tags:");
+                        ui.add(
+                            TextEdit::singleline(&mut self.settings.client.tags)
+                                .hint_text("(None)")
+                                .desired_width(140.0),
+                        )
+                        .on_hover_text("Comma separated tags stored with the result");
+                    });
+
+                    ui.separator();
+
                     ui.horizontal(|ui| {
                         let mut default = ClientSettings::default();
                         default.idle_test = self.settings.client.idle_test;
                         default.advanced = self.settings.client.advanced;
                         default.server = self.settings.client.server.clone();
+                        default.token = self.settings.client.token.clone();
+                        default.tls = self.settings.client.tls;
+                        default.tls_fingerprint = self.settings.client.tls_fingerprint.clone();
                         default.latency_peer_server =
                             self.settings.client.latency_peer_server.clone();
+                        default.peer_tls = self.settings.client.peer_tls;
+                        default.peer_tls_fingerprint =
+                            self.settings.client.peer_tls_fingerprint.clone();
+                        default.notes = self.settings.client.notes.clone();
+                        default.tags = self.settings.client.tags.clone();
 
                         let parameters_changed = self.settings.client != default;
 
@@ -503,8 +639,18 @@ impl Tester {
 
                     if let Ok(result) = client.done.as_mut().unwrap().try_recv() {
                         match result {
-                            Some(Ok(result)) => {
+                            Some(Ok(mut result)) => {
                                 self.msgs.push(with_time("Test complete"));
+                                result.annotate(
+                                    Some(self.settings.client.notes.clone()),
+                                    self.settings
+                                        .client
+                                        .tags
+                                        .split(',')
+                                        .map(|tag| tag.trim().to_owned())
+                                        .filter(|tag| !tag.is_empty())
+                                        .collect(),
+                                );
                                 let result = result.to_test_result();
                                 self.set_result(result);
                                 if self.tab == Tab::Client {
@@ -14,11 +14,15 @@ use std::{
     time::Duration,
 };
 
+use browser::{BrowseTarget, ServerBrowser};
 use client::{Client, ClientSettings, ClientState};
 use crusader_lib::plot::LatencySummary;
 use crusader_lib::test::timed;
 use crusader_lib::{
-    file_format::{RawPing, RawResult, TestKind},
+    auth::Secret,
+    compare::{self, Run},
+    discovery,
+    file_format::{LoadError, RawMetadata, RawPing, RawResult, TestKind},
     latency,
     plot::{self, float_max, to_rates},
     protocol, remote, serve,
@@ -45,6 +49,7 @@ use tokio::sync::{
     oneshot,
 };
 
+mod browser;
 mod client;
 
 struct Server {
@@ -80,6 +85,10 @@ enum Tab {
 #[serde(default)]
 pub struct LatencyMonitorSettings {
     pub server: String,
+    pub token: String,
+    pub tls: bool,
+    pub tls_fingerprint: String,
+    pub lowest_latency: bool,
     pub history: f64,
     pub latency_sample_interval: u64,
 }
@@ -88,16 +97,28 @@ impl Default for LatencyMonitorSettings {
     fn default() -> Self {
         Self {
             server: "".to_owned(),
+            token: "".to_owned(),
+            tls: false,
+            tls_fingerprint: "".to_owned(),
+            lowest_latency: false,
             history: 60.0,
             latency_sample_interval: 5,
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct ServerSettings {
+    /// Clients must authenticate with this token if it's not empty.
+    pub token: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Default)]
 #[serde(default)]
 pub struct Settings {
     pub client: ClientSettings,
+    pub server: ServerSettings,
     pub latency_monitor: LatencyMonitorSettings,
 }
 
@@ -124,6 +145,13 @@ pub struct Tester {
     result_plot_reset: bool,
     result: Option<TestResult>,
     raw_result_saved: Option<PathBuf>,
+    load_error: Option<String>,
+    /// Results compared with the current result and their names.
+    comparison: Vec<(String, TestResult)>,
+    comparison_table: String,
+    /// The window to trim the result to, in seconds from the start of the test.
+    trim_from: f64,
+    trim_to: f64,
     open_result: Vec<PathBuf>,
     result_name: String,
     msgs: Vec<String>,
@@ -138,6 +166,7 @@ pub struct Tester {
     latency_stop: Duration,
     latency_error: Option<String>,
     latency_plot_reset: bool,
+    browser: Option<ServerBrowser>,
 }
 
 pub struct LatencyResult {
@@ -216,6 +245,8 @@ pub struct TestResult {
     download: Vec<(f64, f64)>,
     upload: Vec<(f64, f64)>,
     both: Vec<(f64, f64)>,
+    /// The throughput of each test, with the aggregate for the bidirectional test.
+    loads: Vec<Vec<(f64, f64)>>,
     local_latency: LatencyResult,
     peer_latency: Option<LatencyResult>,
     throughput_max: f64,
@@ -227,6 +258,15 @@ impl TestResult {
         let download = handle_bytes(&result.combined_download_bytes, start);
         let upload = handle_bytes(&result.combined_upload_bytes, start);
         let both = handle_bytes(result.both_bytes.as_deref().unwrap_or(&[]), start);
+        let loads = [
+            &result.download_bytes,
+            &result.upload_bytes,
+            &result.both_bytes,
+        ]
+        .into_iter()
+        .flatten()
+        .map(|bytes| handle_bytes(bytes, start))
+        .collect();
 
         let download_max = float_max(download.iter().map(|v| v.1));
         let upload_max = float_max(upload.iter().map(|v| v.1));
@@ -237,6 +277,7 @@ impl TestResult {
             download,
             upload,
             both,
+            loads,
             throughput_max,
             local_latency: LatencyResult::new(&result, &result.pings),
             peer_latency: result
@@ -256,6 +297,49 @@ pub fn handle_bytes(data: &[(u64, f64)], start: f64) -> Vec<(f64, f64)> {
         .collect()
 }
 
+/// Shows where, when and how a test was run.
+fn test_details(ui: &mut Ui, metadata: &RawMetadata, generated_by: &str) {
+    ui.collapsing("Test details", |ui| {
+        Grid::new("test-details").num_columns(2).show(ui, |ui| {
+            let mut row = |name: &str, value: &str| {
+                ui.label(name);
+                ui.label(value);
+                ui.end_row();
+            };
+
+            row("Server:", metadata.server_name());
+            row(
+                "Server address:",
+                &format!("{} port {}", metadata.server_address, metadata.server_port),
+            );
+            row("Server version:", &metadata.server_version);
+            row(
+                "Started:",
+                &metadata
+                    .start_time()
+                    .map(|time| time.format("%Y-%m-%d %H:%M:%S %:z").to_string())
+                    .unwrap_or_else(|| metadata.start_time.clone()),
+            );
+            row("Client:", generated_by);
+            row("Client OS:", &metadata.client_os);
+            row(
+                "Interface:",
+                &match &metadata.interface {
+                    Some(interface) => format!("{} ({})", interface, metadata.local_address),
+                    None => metadata.local_address.clone(),
+                },
+            );
+            row("Streams:", &metadata.streams.to_string());
+            if let Some(notes) = &metadata.notes {
+                row("Notes:", notes);
+            }
+            if !metadata.tags.is_empty() {
+                row("Tags:", &metadata.tags.join(", "));
+            }
+        });
+    });
+}
+
 fn hover_popup(
     ui: &mut Ui,
     id_source: impl Hash,
@@ -395,6 +479,11 @@ impl Tester {
             result: None,
             result_plot_reset: false,
             raw_result_saved: None,
+            load_error: None,
+            comparison: Vec::new(),
+            comparison_table: String::new(),
+            trim_from: 0.0,
+            trim_to: 0.0,
             result_name: "".to_string(),
             open_result: Vec::new(),
             msgs: Vec::new(),
@@ -412,10 +501,13 @@ impl Tester {
             latency_stop: Duration::from_secs(0),
             latency_error: None,
             latency_plot_reset: false,
+            browser: None,
         }
     }
 
     pub fn set_result(&mut self, result: plot::TestResult) {
+        self.load_error = None;
+        self.comparison.clear();
         self.result = Some(TestResult::new(result));
         self.result_name = "test".to_owned();
         self.result_plot_reset = true;
@@ -427,6 +519,67 @@ impl Tester {
         self.raw_result_saved = Some(name);
     }
 
+    /// Shows why a result file could not be opened on the Result tab.
+    pub fn load_failed(&mut self, name: &Path, error: LoadError) {
+        self.load_error = Some(format!(
+            "Unable to open {}: {}",
+            name.file_name().unwrap_or_default().to_string_lossy(),
+            error
+        ));
+    }
+
+    fn open_file(&mut self, name: PathBuf) {
+        match RawResult::load(&name) {
+            Ok(raw) => self.load_file(name, raw),
+            Err(error) => self.load_failed(&name, error),
+        }
+    }
+
+    /// The name of the current result in comparisons.
+    fn result_label(&self) -> String {
+        self.raw_result_saved
+            .as_ref()
+            .and_then(|file| file.file_stem())
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or("Current result".to_owned())
+    }
+
+    /// Adds results to compare with the current result.
+    pub fn compare_files(&mut self, files: Vec<PathBuf>) {
+        for file in files {
+            match RawResult::load(&file) {
+                Ok(raw) => {
+                    let name = file
+                        .file_stem()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned();
+                    self.comparison
+                        .push((name, TestResult::new(raw.to_test_result())));
+                }
+                Err(error) => self.load_failed(&file, error),
+            }
+        }
+
+        let Some(result) = self.result.as_ref() else {
+            return;
+        };
+        let runs: Vec<_> = [(self.result_label(), result)]
+            .into_iter()
+            .chain(
+                self.comparison
+                    .iter()
+                    .map(|(name, result)| (name.clone(), result)),
+            )
+            .map(|(name, result)| Run {
+                name,
+                result: &result.result,
+            })
+            .collect();
+        self.comparison_table = compare::table(&runs).unwrap_or_default();
+        self.result_plot_reset = true;
+    }
+
     pub fn save_raw(&mut self, name: PathBuf) {
         self.raw_result_saved = Some(name);
     }
@@ -447,13 +600,10 @@ impl Tester {
         {
             FileDialog::new()
                 .add_filter("Crusader Raw Result", &["crr"])
+                .add_filter("Crusader JSON Export", &["json"])
                 .add_filter("All files", &["*"])
                 .pick_file()
-                .map(|file| {
-                    RawResult::load(&file).map(|raw| {
-                        self.load_file(file, raw);
-                    })
-                });
+                .map(|file| self.open_file(file));
         }
         let file_loader = self.file_loader.take();
         file_loader.as_ref().map(|loader| loader(self));
@@ -750,9 +900,10 @@ impl Tester {
                             .ok()
                             .map(|dir| {
                                 dir.filter_map(|file| {
-                                    file.ok()
-                                        .map(|file| file.path())
-                                        .filter(|path| path.extension() == Some(OsStr::new("crr")))
+                                    file.ok().map(|file| file.path()).filter(|path| {
+                                        path.extension() == Some(OsStr::new("crr"))
+                                            || path.extension() == Some(OsStr::new("json"))
+                                    })
                                 })
                                 .collect()
                             })
@@ -784,9 +935,7 @@ impl Tester {
                                 {
                                     if ui.toggle_value(&mut false, prefix).clicked() {
                                         ui.memory_mut(|mem| mem.close_popup());
-                                        RawResult::load(&file).map(|raw| {
-                                            self.load_file(file, raw);
-                                        });
+                                        self.open_file(file);
                                     }
                                 }
                             }
@@ -797,6 +946,207 @@ impl Tester {
         }
     }
 
+    #[cfg(not(target_os = "android"))]
+    fn export_csv(&mut self) {
+        let name = self
+            .raw_result_saved
+            .as_ref()
+            .and_then(|file| file.file_stem()?.to_str().map(|s| s.to_owned()))
+            .unwrap_or(timed("test"));
+
+        let mut dialog = FileDialog::new()
+            .add_filter("CSV", &["csv"])
+            .set_file_name(&format!("{}.csv", name));
+
+        if let Some(parent) = self
+            .raw_result_saved
+            .as_ref()
+            .and_then(|file| file.parent())
+        {
+            dialog = dialog.set_directory(parent);
+        }
+
+        if let Some(file) = dialog.save_file() {
+            // Both files are named after the chosen file, with `.latency.csv` and `.throughput.csv`
+            let stem = file.with_extension("");
+            crusader_lib::export::save_csv(&self.result.as_ref().unwrap().result, &stem, true).ok();
+        }
+    }
+
+    /// Lets the user select a time window and replaces the result with the data within it.
+    fn trim_popup(&mut self, ui: &mut Ui) {
+        let popup_id = ui.make_persistent_id("Trim-Popup");
+
+        let duration = self.result.as_ref().unwrap().result.duration.as_secs_f64();
+
+        let button = ui
+            .button("Trim")
+            .on_hover_text("Keep only the data within a time window");
+
+        if button.clicked() {
+            ui.memory_mut(|mem| {
+                mem.toggle_popup(popup_id);
+            });
+            self.trim_from = 0.0;
+            self.trim_to = duration;
+        }
+
+        egui::popup::popup_below_widget(
+            ui,
+            popup_id,
+            &button,
+            PopupCloseBehavior::CloseOnClickOutside,
+            |ui| {
+                ui.set_min_width(250.0);
+                ui.label("Keep the data between these times. The result can be saved afterwards.");
+                Grid::new("trim-window").show(ui, |ui| {
+                    ui.label("From: ");
+                    ui.add(
+                        egui::DragValue::new(&mut self.trim_from)
+                            .range(0.0..=duration)
+                            .speed(0.05),
+                    );
+                    ui.label("seconds");
+                    ui.end_row();
+                    ui.label("To: ");
+                    ui.add(
+                        egui::DragValue::new(&mut self.trim_to)
+                            .range(0.0..=duration)
+                            .speed(0.05),
+                    );
+                    ui.label("seconds");
+                    ui.end_row();
+                });
+                if ui.button("Trim").clicked() {
+                    let raw = &self.result.as_ref().unwrap().result.raw_result;
+                    match raw.trim(
+                        Duration::from_secs_f64(self.trim_from),
+                        Duration::from_secs_f64(self.trim_to),
+                    ) {
+                        Ok(trimmed) => self.set_result(trimmed.to_test_result()),
+                        Err(error) => self.load_error = Some(error.to_string()),
+                    }
+                    ui.memory_mut(|mem| {
+                        mem.close_popup();
+                    });
+                }
+            },
+        );
+    }
+
+    /// Shows the differences to the compared results and plots their throughput and latency
+    /// with the current result.
+    fn comparison_plots(&mut self, ui: &mut Ui) {
+        ui.collapsing("Comparison", |ui| {
+            ScrollArea::horizontal().show(ui, |ui| {
+                ui.label(RichText::new(&self.comparison_table).monospace());
+            });
+        });
+        ui.separator();
+
+        let reset = mem::take(&mut self.result_plot_reset);
+        let label = self.result_label();
+        let runs: Vec<_> = [(&label, self.result.as_ref().unwrap())]
+            .into_iter()
+            .chain(self.comparison.iter().map(|(name, result)| (name, result)))
+            .collect();
+
+        let color = |i| {
+            let (r, g, b) = plot::run_color(i);
+            Color32::from_rgb(r, g, b)
+        };
+        let duration = float_max(
+            runs.iter()
+                .map(|(_, result)| result.result.duration.as_secs_f64()),
+        ) * 1.1;
+        let loads = runs
+            .iter()
+            .any(|(_, result)| result.result.raw_result.streams() > 0);
+
+        let link = ui.id().with("comparison-link");
+        let y_axis_size = 30.0;
+
+        let mut strip = StripBuilder::new(ui);
+        if loads {
+            strip = strip.size(Size::remainder());
+        }
+        strip.size(Size::remainder()).vertical(|mut strip| {
+            if loads {
+                strip.cell(|ui| {
+                    ui.label("Throughput");
+
+                    let mut plot = Plot::new("comparison-throughput")
+                        .legend(Legend::default())
+                        .y_axis_min_width(y_axis_size)
+                        .link_axis(link, true, false)
+                        .link_cursor(link, true, false)
+                        .include_x(0.0)
+                        .include_x(duration)
+                        .include_y(0.0)
+                        .include_y(
+                            float_max(runs.iter().map(|(_, result)| result.throughput_max)) * 1.1,
+                        )
+                        .height(ui.available_height())
+                        .label_formatter(|_, value| {
+                            format!("Throughput = {:.2} Mbps\nTime = {:.2} s", value.y, value.x)
+                        });
+
+                    if reset {
+                        plot = plot.reset();
+                    }
+
+                    plot.show(ui, |plot_ui| {
+                        for (i, (name, result)) in runs.iter().enumerate() {
+                            for load in &result.loads {
+                                let load = load.iter().map(|v| [v.0, v.1]);
+                                plot_ui.line(
+                                    Line::new(PlotPoints::from_iter(load))
+                                        .color(color(i))
+                                        .name(name),
+                                );
+                            }
+                        }
+                    });
+                });
+            }
+
+            strip.cell(|ui| {
+                ui.label("Latency");
+
+                let mut plot = Plot::new("comparison-latency")
+                    .legend(Legend::default())
+                    .y_axis_min_width(y_axis_size)
+                    .link_axis(link, true, false)
+                    .link_cursor(link, true, false)
+                    .include_x(0.0)
+                    .include_x(duration)
+                    .include_y(0.0)
+                    .include_y(
+                        float_max(runs.iter().map(|(_, result)| result.local_latency.max)) * 1.1,
+                    )
+                    .height(ui.available_height())
+                    .label_formatter(|_, value| {
+                        format!("Latency = {:.2} ms\nTime = {:.2} s", value.y, value.x)
+                    });
+
+                if reset {
+                    plot = plot.reset();
+                }
+
+                plot.show(ui, |plot_ui| {
+                    for (i, (name, result)) in runs.iter().enumerate() {
+                        let latency = result.local_latency.total.iter().map(|v| [v.0, v.1]);
+                        plot_ui.line(
+                            Line::new(PlotPoints::from_iter(latency))
+                                .color(color(i))
+                                .name(name),
+                        );
+                    }
+                });
+            });
+        });
+    }
+
     fn result(&mut self, _ctx: &egui::Context, ui: &mut Ui) {
         if self.result.is_none() {
             ui.horizontal_wrapped(|ui| {
@@ -806,6 +1156,10 @@ impl Tester {
                 self.load_popup(ui);
             });
             ui.separator();
+            if let Some(error) = self.load_error.as_ref() {
+                ui.label(format!("Error: {}", error));
+                ui.separator();
+            }
             ui.label("No result.");
             return;
         }
@@ -846,6 +1200,38 @@ impl Tester {
                 }
             }
 
+            if ui
+                .button("Save anonymised")
+                .on_hover_text(
+                    "Save a copy without addresses, host names or build details for sharing",
+                )
+                .clicked()
+            {
+                let anonymized = self
+                    .result
+                    .as_ref()
+                    .unwrap()
+                    .result
+                    .raw_result
+                    .anonymize(None);
+                match self.raw_saver.as_ref() {
+                    Some(saver) => {
+                        saver(&anonymized);
+                    }
+                    None => {
+                        #[cfg(not(target_os = "android"))]
+                        {
+                            FileDialog::new()
+                                .add_filter("Crusader Raw Result", &["crr"])
+                                .add_filter("All files", &["*"])
+                                .set_file_name(&format!("{}.crr", timed("test anonymized")))
+                                .save_file()
+                                .map(|file| anonymized.save(&file).ok());
+                        }
+                    }
+                }
+            }
+
             self.load_popup(ui);
 
             if cfg!(not(target_os = "android")) {
@@ -957,6 +1343,43 @@ impl Tester {
                     }
                 }
             }
+
+            #[cfg(not(target_os = "android"))]
+            {
+                ui.add_space(10.0);
+
+                if ui
+                    .button("Export CSV")
+                    .on_hover_text("Save the latency and throughput samples as CSV files")
+                    .clicked()
+                {
+                    self.export_csv();
+                }
+
+                ui.add_space(10.0);
+
+                if ui
+                    .button("Compare")
+                    .on_hover_text("Plot other results together with this result")
+                    .clicked()
+                {
+                    FileDialog::new()
+                        .add_filter("Crusader Raw Result", &["crr"])
+                        .add_filter("Crusader JSON Export", &["json"])
+                        .add_filter("All files", &["*"])
+                        .pick_files()
+                        .map(|files| self.compare_files(files));
+                }
+            }
+
+            ui.add_space(10.0);
+
+            self.trim_popup(ui);
+
+            if !self.comparison.is_empty() && ui.button("Clear comparison").clicked() {
+                self.comparison.clear();
+                self.result_plot_reset = true;
+            }
         });
         ui.separator();
 
@@ -973,6 +1396,11 @@ impl Tester {
                 ui.separator();
             });
 
+        if let Some(error) = self.load_error.as_ref() {
+            ui.label(format!("Error: {}", error));
+            ui.separator();
+        }
+
         let result = self.result.as_ref().unwrap();
 
         if result.result.raw_result.server_overload {
@@ -980,11 +1408,36 @@ impl Tester {
             ui.separator();
         }
 
+        if result.result.raw_result.concurrent_load {
+            ui.label("Warning: The server was loaded by other clients during the test. Result is affected by concurrent usage.");
+            ui.separator();
+        }
+
         if result.result.raw_result.load_termination_timeout {
             ui.label("Warning: Load termination timed out. There may be residual untracked traffic in the background.");
             ui.separator();
         }
 
+        if let Some(trim) = &result.result.raw_result.trim {
+            ui.label(format!(
+                "Trimmed to {:.2} s - {:.2} s of the original {:.2} s test.",
+                trim.from.as_secs_f64(),
+                trim.to.as_secs_f64(),
+                trim.original_duration.as_secs_f64()
+            ));
+            ui.separator();
+        }
+
+        if let Some(metadata) = &result.result.raw_result.metadata {
+            test_details(ui, metadata, &result.result.raw_result.generated_by);
+            ui.separator();
+        }
+
+        if !self.comparison.is_empty() {
+            self.comparison_plots(ui);
+            return;
+        }
+
         let packet_loss_size = 75.0;
 
         let result = self.result.as_ref().unwrap();
@@ -1207,7 +1660,18 @@ impl Tester {
         match self.server_state {
             ServerState::Stopped(ref error) => {
                 let (server_button, peer_button) = ui
-                    .horizontal_wrapped(|ui| (ui.button("Start server"), ui.button("Start peer")))
+                    .horizontal_wrapped(|ui| {
+                        let buttons = (ui.button("Start server"), ui.button("Start peer"));
+                        ui.label("Token:");
+                        ui.add(
+                            TextEdit::singleline(&mut self.settings.server.token)
+                                .password(true)
+                                .hint_text("(None)")
+                                .desired_width(100.0),
+                        )
+                        .on_hover_text("Require clients to authenticate with this token");
+                        buttons
+                    })
                     .inner;
 
                 if let Some(error) = error {
@@ -1224,8 +1688,11 @@ impl Tester {
                     let (signal_done, done) = oneshot::channel();
 
                     let stop = serve::serve_until(
-                        protocol::PORT,
-                        peer_button.clicked(),
+                        serve::Config {
+                            peer_server: peer_button.clicked(),
+                            secret: Secret::from_token(&self.settings.server.token),
+                            ..Default::default()
+                        },
                         Box::new(move |msg| {
                             tx.send(with_time(msg)).ok();
                             ctx.request_repaint();
@@ -1255,7 +1722,8 @@ impl Tester {
                 ui.separator();
                 ui.label(format!(
                     "A server listens on TCP and UDP port {}. It allows clients \
-                    to run tests and measure latency against it. It can also act as a latency peer for tests connecting to another server.",
+                    to run tests and measure latency against it. It can also act as a latency peer for tests connecting to another server. \
+                    If a token is set, clients must use the same token.",
                     protocol::PORT
                 ));
             }
@@ -1356,6 +1824,7 @@ impl Tester {
 
                     let stop = remote::serve_until(
                         protocol::PORT + 1,
+                        Vec::new(),
                         Box::new(move |msg| {
                             tx.send(with_time(msg)).ok();
                             ctx.request_repaint();
@@ -1468,6 +1937,17 @@ impl Tester {
     fn start_monitor(&mut self, ctx: &egui::Context) {
         self.save_settings();
 
+        let tls = match client::tls_verification(
+            self.settings.latency_monitor.tls,
+            &self.settings.latency_monitor.tls_fingerprint,
+        ) {
+            Ok(tls) => tls,
+            Err(error) => {
+                self.latency_error = Some(error);
+                return;
+            }
+        };
+
         let (signal_done, done) = oneshot::channel();
 
         let ctx_ = ctx.clone();
@@ -1487,6 +1967,10 @@ impl Tester {
                 ping_interval: Duration::from_millis(
                     self.settings.latency_monitor.latency_sample_interval,
                 ),
+                secret: Secret::from_token(&self.settings.latency_monitor.token),
+                tls,
+                lowest_latency: self.settings.latency_monitor.lowest_latency,
+                discovery_port: discovery::DISCOVER_PORT,
             },
             (!self.settings.latency_monitor.server.trim().is_empty())
                 .then_some(&self.settings.latency_monitor.server),
@@ -1518,6 +2002,28 @@ impl Tester {
                         .hint_text("(Locate local server)"),
                 );
                 let enter = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                self.browse_button(ctx, ui, BrowseTarget::Monitor);
+                ui.add_enabled_ui(self.settings.latency_monitor.server.trim().is_empty(), |ui| {
+                    ui.checkbox(
+                        &mut self.settings.latency_monitor.lowest_latency,
+                        "Lowest latency",
+                    )
+                    .on_hover_text(
+                        "Use the local server with the lowest latency instead of the first one to reply",
+                    );
+                });
+                ui.label("Token:");
+                ui.add(
+                    TextEdit::singleline(&mut self.settings.latency_monitor.token)
+                        .password(true)
+                        .hint_text("(None)")
+                        .desired_width(100.0),
+                );
+                client::tls_settings(
+                    ui,
+                    &mut self.settings.latency_monitor.tls,
+                    &mut self.settings.latency_monitor.tls_fingerprint,
+                );
 
                 if ui.button("Start test").clicked() || enter {
                     self.start_monitor(ctx)
@@ -1753,10 +2259,8 @@ impl Tester {
                 .first()
                 .and_then(|file| file.path.as_deref())
             {
-                RawResult::load(file).map(|raw| {
-                    self.load_file(file.to_owned(), raw);
-                    self.tab = Tab::Result;
-                });
+                self.open_file(file.to_owned());
+                self.tab = Tab::Result;
             }
         });
 
@@ -1777,5 +2281,7 @@ impl Tester {
             Tab::Monitor => self.monitor(ctx, ui),
             Tab::Result => self.result(ctx, ui),
         }
+
+        self.server_browser(ctx);
     }
 }
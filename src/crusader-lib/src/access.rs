@@ -0,0 +1,137 @@
+use anyhow::{bail, Context};
+use parking_lot::{Mutex, RwLock};
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+pub use ipnet::IpNet;
+
+/// Parses a network in CIDR notation. A single address is also accepted.
+pub fn parse_net(net: &str) -> Result<IpNet, anyhow::Error> {
+    let net = net.trim();
+    net.parse::<IpNet>()
+        .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+        .with_context(|| format!("Invalid network `{}`", net))
+}
+
+/// CIDR based lists of addresses which may use the server.
+///
+/// An address matching the deny list is always rejected.
+/// If the allow list is not empty, only addresses matching it are accepted.
+#[derive(Clone, Default, Debug)]
+pub struct AccessList {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    /// Parses rules with one `allow <CIDR>` or `deny <CIDR>` entry per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn parse(rules: &str) -> Result<Self, anyhow::Error> {
+        let mut result = AccessList::default();
+        for (i, line) in rules.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (kind, net) = line
+                .split_once(char::is_whitespace)
+                .map(|(kind, net)| parse_net(net).map(|net| (kind, net)))
+                .unwrap_or_else(|| bail!("Expected `allow <CIDR>` or `deny <CIDR>`"))
+                .with_context(|| format!("Line {}", i + 1))?;
+            match kind {
+                "allow" => result.allow.push(net),
+                "deny" => result.deny.push(net),
+                _ => bail!("Line {}: Unknown rule `{}`", i + 1, kind),
+            }
+        }
+        Ok(result)
+    }
+
+    fn extend(&mut self, other: &AccessList) {
+        self.allow.extend_from_slice(&other.allow);
+        self.deny.extend_from_slice(&other.deny);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Restricts which addresses may use the server.
+#[derive(Clone, Default, Debug)]
+pub struct Access {
+    pub list: AccessList,
+    /// A file with additional rules in the format of [`AccessList::parse`].
+    /// It is reloaded when it changes.
+    pub file: Option<PathBuf>,
+}
+
+pub(crate) struct AccessControl {
+    access: Access,
+    last_modified: Mutex<Option<SystemTime>>,
+    current: RwLock<AccessList>,
+}
+
+impl AccessControl {
+    pub(crate) fn new(access: Access) -> Result<Self, anyhow::Error> {
+        let control = AccessControl {
+            current: RwLock::new(access.list.clone()),
+            access,
+            last_modified: Mutex::new(None),
+        };
+        control.load()?;
+        Ok(control)
+    }
+
+    pub(crate) fn file(&self) -> Option<&PathBuf> {
+        self.access.file.as_ref()
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.access.file.is_some() || !self.access.list.is_empty()
+    }
+
+    pub(crate) fn allows(&self, ip: IpAddr) -> bool {
+        self.current.read().allows(ip)
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        let path = self.access.file.as_ref()?;
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    fn load(&self) -> Result<(), anyhow::Error> {
+        let Some(path) = &self.access.file else {
+            return Ok(());
+        };
+        *self.last_modified.lock() = self.modified();
+        let rules = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read access list `{}`", path.display()))?;
+        let file = AccessList::parse(&rules)
+            .with_context(|| format!("Invalid access list `{}`", path.display()))?;
+        let mut list = self.access.list.clone();
+        list.extend(&file);
+        *self.current.write() = list;
+        Ok(())
+    }
+
+    /// Loads the rules file again if it has changed. Returns `true` if the rules were updated.
+    pub(crate) fn reload(&self) -> Result<bool, anyhow::Error> {
+        if *self.last_modified.lock() == self.modified() {
+            return Ok(false);
+        }
+        self.load().map(|_| true)
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::auth::Secret;
+use crate::common::hello;
+use crate::metrics::Metrics;
+use crate::protocol::{codec, receive, send};
+use crate::serve::{challenge, handshake, Handshake, State};
+
+/// The default address used to reach the admin interface of a server.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:35484";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AdminRequest {
+    Status,
+    /// Terminates the client using this slot.
+    Kill {
+        slot: u64,
+    },
+    /// Terminates a latency peer session.
+    KillPeer {
+        id: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AdminResponse {
+    Status(Status),
+    Killed,
+    NotFound,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Status {
+    pub clients: Vec<ClientStatus>,
+    pub peers: Vec<PeerStatus>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientStatus {
+    pub slot: u64,
+    pub addr: SocketAddr,
+    /// The start time in milliseconds since the Unix epoch.
+    pub started: u64,
+    /// The number of load streams currently transferring data.
+    pub loads: u64,
+    /// The server was unable to forward latency measurements quickly enough.
+    pub overload: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerStatus {
+    pub id: u64,
+    pub addr: SocketAddr,
+    /// The server the peer measures latency to.
+    pub server: SocketAddr,
+    /// The start time in milliseconds since the Unix epoch.
+    pub started: u64,
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn status(state: &State) -> Status {
+    let clients = state
+        .clients
+        .lock()
+        .iter()
+        .enumerate()
+        .filter_map(|(slot, client)| {
+            client.as_ref().map(|client| ClientStatus {
+                slot: slot as u64,
+                addr: client.addr,
+                started: unix_millis(client.started),
+                loads: client.active_loads.load(Ordering::Relaxed),
+                overload: client.overload.load(Ordering::SeqCst),
+            })
+        })
+        .collect();
+
+    let mut peers: Vec<_> = state
+        .peers
+        .lock()
+        .iter()
+        .map(|(&id, peer)| PeerStatus {
+            id,
+            addr: peer.addr,
+            server: peer.server,
+            started: unix_millis(peer.started),
+        })
+        .collect();
+    peers.sort_by_key(|peer| peer.id);
+
+    Status { clients, peers }
+}
+
+fn respond(state: &State, request: &AdminRequest) -> AdminResponse {
+    match *request {
+        AdminRequest::Status => AdminResponse::Status(status(state)),
+        AdminRequest::Kill { slot } => {
+            let client = state
+                .clients
+                .lock()
+                .get(slot as usize)
+                .and_then(|client| client.clone());
+            match client {
+                Some(client) => {
+                    (state.msg)(&format!(
+                        "Terminating client {} in slot {} by admin request",
+                        client.addr, slot
+                    ));
+                    client.kill();
+                    AdminResponse::Killed
+                }
+                None => AdminResponse::NotFound,
+            }
+        }
+        AdminRequest::KillPeer { id } => match state.peers.lock().get(&id) {
+            Some(peer) => {
+                (state.msg)(&format!(
+                    "Terminating peer session {} for {} by admin request",
+                    id, peer.addr
+                ));
+                peer.connection.abort();
+                AdminResponse::Killed
+            }
+            None => AdminResponse::NotFound,
+        },
+    }
+}
+
+async fn handle(state: &State, stream: TcpStream) -> Result<(), anyhow::Error> {
+    let addr = stream.peer_addr()?;
+    stream.set_nodelay(true)?;
+
+    let (rx, tx) = stream.into_split();
+    let mut stream_rx = FramedRead::new(rx, codec());
+    let mut stream_tx = FramedWrite::new(tx, codec());
+
+    let handshake = time::timeout(
+        Duration::from_secs(5),
+        handshake(state, false, &mut stream_tx, &mut stream_rx),
+    )
+    .await??;
+    if let Handshake::Rejected { reason, .. } = handshake {
+        bail!("Rejected admin {}: {}", addr, reason);
+    }
+
+    if !challenge(state.admin_secret, &mut stream_tx, &mut stream_rx).await? {
+        (state.msg)(&format!("Admin {} failed authentication", addr));
+        return Ok(());
+    }
+
+    loop {
+        let request: AdminRequest = match receive(&mut stream_rx).await {
+            Ok(request) => request,
+            Err(_) => return Ok(()),
+        };
+        send(&mut stream_tx, &respond(state, &request)).await?;
+    }
+}
+
+pub(crate) async fn serve(state: Arc<State>, addr: SocketAddr) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::bind(addr).await?;
+    (state.msg)(&format!(
+        "Admin interface available at {}",
+        listener.local_addr()?
+    ));
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    if !state.access.allows(addr.ip()) {
+                        state.rejected(&format!("Rejected admin {}", addr));
+                        Metrics::inc(&state.metrics.rejected_connections);
+                        continue;
+                    }
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(error) = handle(&state, stream).await {
+                            (state.msg)(&format!("Error serving admin {}: {:?}", addr, error));
+                        }
+                    });
+                }
+                Err(error) => {
+                    (state.msg)(&format!("Error accepting admin client: {}", error));
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Sends a request to the admin interface of a running server.
+pub fn request(
+    addr: SocketAddr,
+    secret: Option<Secret>,
+    request: AdminRequest,
+) -> Result<AdminResponse, anyhow::Error> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async move {
+        let stream = time::timeout(Duration::from_secs(8), TcpStream::connect(addr))
+            .await
+            .context("Timed out connecting to the admin interface")?
+            .with_context(|| format!("Unable to connect to the admin interface at {}", addr))?;
+        stream.set_nodelay(true)?;
+
+        let (rx, tx) = stream.into_split();
+        let mut stream_rx = FramedRead::new(rx, codec());
+        let mut stream_tx = FramedWrite::new(tx, codec());
+
+        hello(&mut stream_tx, &mut stream_rx, secret).await?;
+
+        send(&mut stream_tx, &request).await?;
+        receive(&mut stream_rx).await
+    })
+}
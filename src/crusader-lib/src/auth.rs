@@ -0,0 +1,51 @@
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const CONTEXT: &[u8] = b"crusader-auth";
+
+/// A shared secret used to authenticate clients to a server.
+///
+/// Only a hash of the token is kept so the type can be cheaply copied around.
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Secret([u8; 32]);
+
+impl Secret {
+    pub fn new(token: &str) -> Self {
+        Secret(Sha256::digest(token.as_bytes()).into())
+    }
+
+    /// Returns `None` for an empty token.
+    pub fn from_token(token: &str) -> Option<Self> {
+        (!token.is_empty()).then(|| Secret::new(token))
+    }
+
+    fn hmac(&self, nonce: &[u8; 32]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).unwrap();
+        mac.update(CONTEXT);
+        mac.update(nonce);
+        mac
+    }
+
+    pub(crate) fn respond(&self, nonce: &[u8; 32]) -> [u8; 32] {
+        self.hmac(nonce).finalize().into_bytes().into()
+    }
+
+    pub(crate) fn verify(&self, nonce: &[u8; 32], response: &[u8; 32]) -> bool {
+        self.hmac(nonce).verify_slice(response).is_ok()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+pub(crate) fn nonce() -> [u8; 32] {
+    rand::thread_rng().gen()
+}
@@ -1,16 +1,22 @@
 use crate::{
-    protocol::{receive, send, ClientMessage, Hello, Ping, ServerMessage},
+    auth::Secret,
+    protocol::{
+        self, receive, send, ClientMessage, Hello, Negotiate, Ping, ServerMessage, Session,
+    },
     serve::OnDrop,
+    tls::Connection,
+    version,
 };
 use anyhow::{anyhow, bail, Context};
 use bytes::{Bytes, BytesMut};
 use futures::{pin_mut, select, FutureExt, Sink, Stream};
 use rand::Rng;
 use rand::{rngs::StdRng, SeedableRng};
+use socket2::{Domain, Protocol, Socket};
 use std::{
     error::Error,
-    io::Cursor,
-    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    io::{Cursor, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     sync::{
         atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
@@ -18,12 +24,9 @@ use std::{
     time::Duration,
 };
 use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
     join,
-    net::{
-        self,
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream, ToSocketAddrs, UdpSocket,
-    },
+    net::{self, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
     sync::{
         oneshot,
         watch::{self, error::RecvError},
@@ -33,6 +36,9 @@ use tokio::{
 };
 use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 
+#[cfg(feature = "client")]
+use crate::tls::Verification;
+
 #[cfg(feature = "client")]
 pub(crate) type Msg = Arc<dyn Fn(&str) + Send + Sync>;
 
@@ -64,6 +70,15 @@ pub struct Config {
     pub stream_stagger: Duration,
     pub ping_interval: Duration,
     pub throughput_interval: Duration,
+    pub secret: Option<Secret>,
+    pub tls: Option<Verification>,
+    /// How the certificate of the latency peer is verified. The peer is connected to
+    /// without TLS if this is not set.
+    pub peer_tls: Option<Verification>,
+    /// Use the local server with the lowest latency instead of the first one to reply.
+    pub lowest_latency: bool,
+    /// The UDP port local servers and peers receive discovery requests on.
+    pub discovery_port: u16,
 }
 
 pub async fn connect<A: ToSocketAddrs>(addr: A, name: &str) -> Result<TcpStream, anyhow::Error> {
@@ -130,6 +145,45 @@ pub fn is_unicast_link_local(ip: Ipv6Addr) -> bool {
     (ip.segments()[0] & 0xffc0) == 0xfe80
 }
 
+/// The addresses to listen on. All addresses are used if `listen` is empty.
+pub(crate) fn listen_ips(listen: &[IpAddr]) -> Vec<IpAddr> {
+    if listen.is_empty() {
+        vec![
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        ]
+    } else {
+        listen.to_vec()
+    }
+}
+
+/// Binds a TCP listener with clear errors for ports in use and unavailable addresses.
+pub(crate) fn bind_tcp(addr: SocketAddr) -> Result<TcpListener, anyhow::Error> {
+    let socket = Socket::new(
+        Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(not(target_family = "windows"))]
+    socket.set_reuse_address(true)?;
+    socket
+        .bind(&addr.into())
+        .map_err(|error| match error.kind() {
+            ErrorKind::AddrInUse => anyhow!(
+                "Failed to bind TCP port, maybe another Crusader instance is already running"
+            ),
+            ErrorKind::AddrNotAvailable => anyhow!("Address {} is not available", addr.ip()),
+            _ => anyhow::Error::from(error).context(format!("Failed to bind TCP socket {}", addr)),
+        })?;
+    socket.listen(1024)?;
+    let listener: std::net::TcpListener = socket.into();
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener)?)
+}
+
 pub fn fresh_socket_addr(socket: SocketAddr, port: u16) -> SocketAddr {
     match socket {
         SocketAddr::V4(socket) => SocketAddr::V4(SocketAddrV4::new(*socket.ip(), port)),
@@ -166,14 +220,19 @@ pub(crate) fn data() -> Vec<u8> {
     vec
 }
 
+fn connection_lost(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::ConnectionReset
+        || err.kind() == std::io::ErrorKind::ConnectionAborted
+}
+
 pub(crate) async fn read_data(
-    stream: TcpStream,
+    stream: Connection,
     buffer: &mut [u8],
     bytes: Arc<AtomicU64>,
     until: Instant,
     writer_done: oneshot::Receiver<()>,
 ) -> Result<bool, anyhow::Error> {
-    stream.set_linger(Some(Duration::from_secs(0))).ok();
+    stream.tcp().set_linger(Some(Duration::from_secs(0))).ok();
 
     let reading_done = Arc::new(AtomicBool::new(false));
 
@@ -221,11 +280,44 @@ pub(crate) async fn read_data(
         reading_done_.store(true, Ordering::Release);
     });
 
+    match stream {
+        Connection::Tcp(stream) => read_tcp(&stream, buffer, &bytes, &reading_done).await,
+        Connection::Tls(mut stream) => {
+            loop {
+                if reading_done.load(Ordering::Acquire) {
+                    return Ok(true);
+                }
+
+                match time::timeout(Duration::from_millis(50), stream.read(buffer)).await {
+                    Err(_) => (),
+                    Ok(Ok(0)) => return Ok(false),
+                    Ok(Ok(n)) => {
+                        bytes.fetch_add(n as u64, Ordering::Release);
+                    }
+                    Ok(Err(err)) => {
+                        // The writer closes TLS connections without notifying the reader.
+                        if connection_lost(&err) || err.kind() == std::io::ErrorKind::UnexpectedEof
+                        {
+                            return Ok(false);
+                        } else {
+                            return Err(err.into());
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn read_tcp(
+    stream: &TcpStream,
+    buffer: &mut [u8],
+    bytes: &AtomicU64,
+    reading_done: &AtomicBool,
+) -> Result<bool, anyhow::Error> {
     loop {
         if let Ok(Err(err)) = time::timeout(Duration::from_millis(50), stream.readable()).await {
-            if err.kind() == std::io::ErrorKind::ConnectionReset
-                || err.kind() == std::io::ErrorKind::ConnectionAborted
-            {
+            if connection_lost(&err) {
                 return Ok(false);
             } else {
                 return Err(err.into());
@@ -246,9 +338,7 @@ pub(crate) async fn read_data(
                 Err(err) => {
                     if err.kind() == std::io::ErrorKind::WouldBlock {
                         break;
-                    } else if err.kind() == std::io::ErrorKind::ConnectionReset
-                        || err.kind() == std::io::ErrorKind::ConnectionAborted
-                    {
+                    } else if connection_lost(&err) {
                         return Ok(false);
                     } else {
                         return Err(err.into());
@@ -259,13 +349,14 @@ pub(crate) async fn read_data(
     }
 }
 
+/// Writes `data` repeatedly until `until`. Returns the number of bytes written.
 pub(crate) async fn write_data(
-    stream: TcpStream,
+    stream: Connection,
     data: &[u8],
     until: Instant,
-) -> Result<(), anyhow::Error> {
-    stream.set_nodelay(false).ok();
-    stream.set_linger(Some(Duration::from_secs(0))).ok();
+) -> Result<u64, anyhow::Error> {
+    stream.tcp().set_nodelay(false).ok();
+    stream.tcp().set_linger(Some(Duration::from_secs(0))).ok();
 
     let done = Arc::new(AtomicBool::new(false));
     let done_ = done.clone();
@@ -275,27 +366,55 @@ pub(crate) async fn write_data(
         done.store(true, Ordering::Release);
     });
 
+    let mut written = 0;
+
+    match stream {
+        Connection::Tcp(stream) => write_tcp(&stream, data, &done_, &mut written).await?,
+        Connection::Tls(mut stream) => loop {
+            if done_.load(Ordering::Acquire) {
+                break;
+            }
+
+            match time::timeout(Duration::from_millis(50), stream.write(data)).await {
+                Err(_) => (),
+                Ok(Ok(n)) => written += n as u64,
+                Ok(Err(err)) => {
+                    if connection_lost(&err) {
+                        break;
+                    } else {
+                        return Err(err.into());
+                    }
+                }
+            }
+        },
+    }
+
+    Ok(written)
+}
+
+async fn write_tcp(
+    stream: &TcpStream,
+    data: &[u8],
+    done: &AtomicBool,
+    written: &mut u64,
+) -> Result<(), anyhow::Error> {
     loop {
         if let Ok(Err(err)) = time::timeout(Duration::from_millis(50), stream.writable()).await {
-            if err.kind() == std::io::ErrorKind::ConnectionReset
-                || err.kind() == std::io::ErrorKind::ConnectionAborted
-            {
+            if connection_lost(&err) {
                 break;
             } else {
                 return Err(err.into());
             }
         }
 
-        if done_.load(Ordering::Acquire) {
+        if done.load(Ordering::Acquire) {
             break;
         }
         match stream.try_write(data) {
-            Ok(_) => (),
+            Ok(n) => *written += n as u64,
             Err(err) => {
                 if err.kind() == std::io::ErrorKind::WouldBlock {
-                } else if err.kind() == std::io::ErrorKind::ConnectionReset
-                    || err.kind() == std::io::ErrorKind::ConnectionAborted
-                {
+                } else if connection_lost(&err) {
                     break;
                 } else {
                     return Err(err.into());
@@ -306,11 +425,11 @@ pub(crate) async fn write_data(
         yield_now().await;
     }
 
-    std::mem::drop(stream);
-
     Ok(())
 }
 
+/// Performs the hello, version negotiation and authentication with a server.
+/// Returns the negotiation message of the server.
 pub(crate) async fn hello<
     T: Sink<Bytes> + Unpin,
     R: Stream<Item = Result<BytesMut, RE>> + Unpin,
@@ -318,27 +437,98 @@ pub(crate) async fn hello<
 >(
     tx: &mut T,
     rx: &mut R,
-) -> Result<(), anyhow::Error>
+    secret: Option<Secret>,
+) -> Result<Negotiate, anyhow::Error>
 where
     T::Error: Error + Send + Sync + 'static,
     RE: Error + Send + Sync + 'static,
 {
-    let hello = Hello::new();
-
-    send(tx, &hello).await.context("Sending hello")?;
+    send(tx, &Hello::new()).await.context("Sending hello")?;
     let server_hello: Hello = receive(rx).await.context("Receiving hello")?;
+    check_server_hello(&server_hello)?;
 
-    if hello != server_hello {
+    let server: Negotiate = receive(rx).await.context("Receiving version negotiation")?;
+    let ours = client_negotiation(secret);
+    send(tx, &ours).await?;
+    check_negotiation(&ours, &server)?;
+
+    let challenge: ServerMessage = receive(rx).await.context("Receiving challenge")?;
+    if let Some(response) = challenge_response(challenge, secret)? {
+        send(tx, &response).await?;
+        authenticated(receive(rx).await?, secret)?;
+    }
+
+    Ok(server)
+}
+
+pub(crate) fn check_server_hello(server_hello: &Hello) -> Result<(), anyhow::Error> {
+    if !server_hello.valid_magic() {
+        bail!("Invalid server hello {:?}", server_hello);
+    }
+    if server_hello.version < protocol::NEGOTIATION_VERSION {
         bail!(
-            "Mismatched server hello, got {:?}, expected {:?}",
-            server_hello,
-            hello
+            "The server uses protocol version {} from an older release of Crusader. \
+                This client is version {} and requires protocol version {} or newer, \
+                the server must be updated",
+            server_hello.version,
+            version(),
+            protocol::NEGOTIATION_VERSION
         );
     }
+    Ok(())
+}
 
+/// The negotiation message of clients. Clients only support the newest protocol version.
+pub(crate) fn client_negotiation(secret: Option<Secret>) -> Negotiate {
+    let capabilities = if secret.is_some() {
+        protocol::capability::AUTHENTICATION
+    } else {
+        0
+    };
+    Negotiate::new(protocol::VERSION, capabilities)
+}
+
+pub(crate) fn check_negotiation(ours: &Negotiate, server: &Negotiate) -> Result<(), anyhow::Error> {
+    if ours.agree(server).is_none() {
+        bail!(
+            "Incompatible server. The server is {}, this client is {}",
+            server.describe(),
+            ours.describe()
+        );
+    }
     Ok(())
 }
 
+pub(crate) fn challenge_response(
+    challenge: ServerMessage,
+    secret: Option<Secret>,
+) -> Result<Option<ClientMessage>, anyhow::Error> {
+    match challenge {
+        ServerMessage::Challenge(None) => Ok(None),
+        ServerMessage::Challenge(Some(nonce)) => Ok(Some(ClientMessage::ChallengeResponse(
+            secret.map(|secret| secret.respond(&nonce)),
+        ))),
+        _ => bail!("Unexpected message {:?}", challenge),
+    }
+}
+
+pub(crate) fn authenticated(
+    reply: ServerMessage,
+    secret: Option<Secret>,
+) -> Result<(), anyhow::Error> {
+    match reply {
+        ServerMessage::Authenticated => Ok(()),
+        ServerMessage::AuthenticationFailed => {
+            if secret.is_some() {
+                bail!("Authentication failed, the token does not match the server's token")
+            } else {
+                bail!("The server requires an authentication token")
+            }
+        }
+        _ => bail!("Unexpected message {:?}", reply),
+    }
+}
+
 pub(crate) fn udp_handle(result: std::io::Result<()>) -> std::io::Result<()> {
     match result {
         Ok(v) => Ok(v),
@@ -354,7 +544,7 @@ pub(crate) fn udp_handle(result: std::io::Result<()>) -> std::io::Result<()> {
 
 async fn ping_measure_send(
     mut index: u64,
-    id: u64,
+    session: Session,
     setup_start: Instant,
     socket: Arc<UdpSocket>,
     samples: u32,
@@ -369,7 +559,11 @@ async fn ping_measure_send(
 
         let current = setup_start.elapsed();
 
-        let ping = Ping { id, index };
+        let ping = Ping {
+            id: session.id,
+            token: session.token,
+            index,
+        };
 
         index += 1;
 
@@ -421,10 +615,10 @@ async fn ping_measure_recv(
 }
 
 pub(crate) async fn measure_latency(
-    id: u64,
+    session: Session,
     ping_index: &mut u64,
-    mut control_tx: &mut FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>,
-    mut control_rx: FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
+    mut control_tx: &mut FramedWrite<WriteHalf<Connection>, LengthDelimitedCodec>,
+    mut control_rx: FramedRead<ReadHalf<Connection>, LengthDelimitedCodec>,
     server: SocketAddr,
     local_udp: SocketAddr,
     setup_start: Instant,
@@ -432,7 +626,7 @@ pub(crate) async fn measure_latency(
     (
         Duration,
         u64,
-        FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
+        FramedRead<ReadHalf<Connection>, LengthDelimitedCodec>,
     ),
     anyhow::Error,
 > {
@@ -464,7 +658,7 @@ pub(crate) async fn measure_latency(
     let ping_start_index = *ping_index;
     let ping_send = tokio::spawn(ping_measure_send(
         ping_start_index,
-        id,
+        session,
         setup_start,
         udp_socket,
         samples,
@@ -518,7 +712,7 @@ pub(crate) async fn measure_latency(
 
 pub(crate) async fn ping_send(
     mut ping_index: u64,
-    id: u64,
+    session: Session,
     state_rx: watch::Receiver<(TestState, Instant)>,
     setup_start: Instant,
     socket: Arc<UdpSocket>,
@@ -543,7 +737,8 @@ pub(crate) async fn ping_send(
         let current = setup_start.elapsed();
 
         let ping = Ping {
-            id,
+            id: session.id,
+            token: session.token,
             index: ping_index,
         };
 
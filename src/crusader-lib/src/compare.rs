@@ -0,0 +1,149 @@
+//! Comparisons of the summaries of several results, such as before and after a network change.
+
+use std::fmt::Write;
+
+use crate::file_format::TestKind;
+use crate::info::{summaries, PhaseSummary};
+use crate::plot::TestResult;
+
+/// A result being compared, with the name it's labeled with.
+pub struct Run<'a> {
+    pub name: String,
+    pub result: &'a TestResult,
+}
+
+struct Row {
+    label: &'static str,
+    value: fn(&PhaseSummary) -> Option<f64>,
+}
+
+const ROWS: &[Row] = &[
+    Row {
+        label: "Throughput (Mbps)",
+        value: |s| s.throughput,
+    },
+    Row {
+        label: "Throughput down (Mbps)",
+        value: |s| s.throughput_down,
+    },
+    Row {
+        label: "Throughput up (Mbps)",
+        value: |s| s.throughput_up,
+    },
+    Row {
+        label: "Latency (ms)",
+        value: |s| s.latency.map(|latency| latency.total),
+    },
+    Row {
+        label: "Latency down (ms)",
+        value: |s| s.latency.map(|latency| latency.down),
+    },
+    Row {
+        label: "Latency up (ms)",
+        value: |s| s.latency.map(|latency| latency.up),
+    },
+    Row {
+        label: "Median latency (ms)",
+        value: |s| s.median_latency,
+    },
+    Row {
+        label: "Packet loss down (%)",
+        value: |s| s.loss.map(|loss| loss.down * 100.0),
+    },
+    Row {
+        label: "Packet loss up (%)",
+        value: |s| s.loss.map(|loss| loss.up * 100.0),
+    },
+    Row {
+        label: "Peer latency (ms)",
+        value: |s| s.peer_latency.map(|latency| latency.total),
+    },
+    Row {
+        label: "Peer median latency (ms)",
+        value: |s| s.peer_median_latency,
+    },
+    Row {
+        label: "Peer packet loss down (%)",
+        value: |s| s.peer_loss.map(|loss| loss.down * 100.0),
+    },
+    Row {
+        label: "Peer packet loss up (%)",
+        value: |s| s.peer_loss.map(|loss| loss.up * 100.0),
+    },
+];
+
+/// Returns a table with the summary of each test of the runs. Every run after the first
+/// also shows the difference to the first run.
+pub fn table(runs: &[Run]) -> Result<String, anyhow::Error> {
+    let mut o = String::new();
+
+    let label_width = 26;
+    let width = 22;
+
+    for (i, run) in runs.iter().enumerate() {
+        writeln!(
+            &mut o,
+            "Run {}: {}{}",
+            i + 1,
+            run.name,
+            if i == 0 { " (baseline)" } else { "" }
+        )?;
+    }
+
+    let summaries: Vec<_> = runs.iter().map(|run| summaries(run.result)).collect();
+
+    let kinds = [
+        Some(TestKind::Download),
+        Some(TestKind::Upload),
+        Some(TestKind::Bidirectional),
+        None,
+    ];
+
+    for kind in kinds {
+        let phases: Vec<_> = summaries
+            .iter()
+            .map(|summaries| summaries.iter().find(|summary| summary.kind == kind))
+            .collect();
+        if phases.iter().all(|phase| phase.is_none()) {
+            continue;
+        }
+
+        writeln!(&mut o)?;
+        writeln!(
+            &mut o,
+            "-- {} test --",
+            kind.map(|kind| kind.name()).unwrap_or("Idle")
+        )?;
+
+        write!(&mut o, "{:>label_width$}", "")?;
+        for i in 0..runs.len() {
+            write!(&mut o, "{:>width$}", format!("Run {}", i + 1))?;
+        }
+        writeln!(&mut o)?;
+
+        for row in ROWS {
+            let values: Vec<_> = phases
+                .iter()
+                .map(|phase| phase.and_then(row.value))
+                .collect();
+            if values.iter().all(|value| value.is_none()) {
+                continue;
+            }
+
+            write!(&mut o, "{:>label_width$}", row.label)?;
+            for (i, value) in values.iter().enumerate() {
+                let cell = match (value, values[0]) {
+                    (None, _) => "-".to_owned(),
+                    (Some(value), Some(base)) if i > 0 => {
+                        format!("{:.02} ({:+.02})", value, value - base)
+                    }
+                    (Some(value), _) => format!("{:.02}", value),
+                };
+                write!(&mut o, "{:>width$}", cell)?;
+            }
+            writeln!(&mut o)?;
+        }
+    }
+
+    Ok(o)
+}
@@ -1,17 +1,38 @@
-use crate::{common::is_unicast_link_local, protocol, serve::State, version};
+#[cfg(feature = "client")]
+use crate::common::fresh_socket_addr;
+use crate::{
+    common::{interface_ips, is_unicast_link_local},
+    mdns,
+    metrics::Metrics,
+    protocol,
+    serve::State,
+    version,
+};
 #[cfg(feature = "client")]
 use anyhow::anyhow;
 use anyhow::bail;
+use ipnet::Ipv4Net;
 #[cfg(target_family = "unix")]
 use nix::net::if_::InterfaceFlags;
 use serde::{Deserialize, Serialize};
 use socket2::{Domain, Protocol, Socket};
+#[cfg(target_family = "unix")]
+use std::collections::HashSet;
 use std::{
-    net::{IpAddr, Ipv6Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     str::FromStr,
     sync::Arc,
 };
 use tokio::net::UdpSocket;
+#[cfg(feature = "client")]
+use {
+    std::time::Duration,
+    tokio::{
+        sync::mpsc,
+        task::JoinSet,
+        time::{timeout, Instant},
+    },
+};
 
 pub const DISCOVER_PORT: u16 = protocol::PORT + 2;
 pub const DISCOVER_VERSION: u64 = 0;
@@ -54,19 +75,66 @@ enum Message {
 }
 
 #[cfg(feature = "client")]
+#[derive(Clone, Debug)]
 pub struct Server {
     pub at: String,
     pub socket: SocketAddr,
     pub software_version: String,
+    /// The newest protocol version supported by the server.
+    pub protocol_version: u64,
+    /// The oldest protocol version supported by the server.
+    pub min_protocol_version: u64,
+    pub hostname: Option<String>,
+    pub label: Option<String>,
+    /// The addresses of the server found by the discovery mechanisms which replied.
+    pub addresses: Vec<IpAddr>,
+    /// The round trip time of the discovery request.
+    pub latency: Duration,
+}
+
+#[cfg(feature = "client")]
+impl Server {
+    /// Returns `true` if the server supports a protocol version this client supports.
+    pub fn compatible(&self) -> bool {
+        self.min_protocol_version <= protocol::VERSION
+            && self.protocol_version >= protocol::MIN_VERSION
+    }
 }
 
-fn interfaces() -> Vec<u32> {
+/// Returns the interfaces to use for multicast. If `only` is not empty, only interfaces
+/// with one of those addresses are used.
+fn interfaces(only: &[IpAddr]) -> Vec<u32> {
     let mut _result = vec![0];
 
     #[cfg(target_family = "unix")]
     {
+        let names: Option<HashSet<String>> = (!only.is_empty()).then(|| {
+            _result.clear();
+            nix::ifaddrs::getifaddrs()
+                .map(|interfaces| {
+                    interfaces
+                        .filter(|interface| {
+                            interface.address.as_ref().is_some_and(|addr| {
+                                addr.as_sockaddr_in()
+                                    .map(|addr| IpAddr::V4(addr.ip()))
+                                    .or(addr.as_sockaddr_in6().map(|addr| IpAddr::V6(addr.ip())))
+                                    .is_some_and(|ip| only.contains(&ip))
+                            })
+                        })
+                        .map(|interface| interface.interface_name)
+                        .collect()
+                })
+                .unwrap_or_default()
+        });
+
         if let Ok(interfaces) = nix::ifaddrs::getifaddrs() {
             for interface in interfaces {
+                if names
+                    .as_ref()
+                    .is_some_and(|names| !names.contains(&interface.interface_name))
+                {
+                    continue;
+                }
                 if interface.flags.contains(InterfaceFlags::IFF_LOOPBACK) {
                     continue;
                 }
@@ -86,189 +154,785 @@ fn interfaces() -> Vec<u32> {
     _result
 }
 
+/// Returns the IPv4 networks of the interfaces. If `only` is not empty, only networks
+/// containing one of those addresses are used. This is empty if the networks are unknown.
+fn ipv4_networks(only: &[IpAddr]) -> Vec<Ipv4Net> {
+    let mut _result = Vec::new();
+
+    #[cfg(target_family = "unix")]
+    if let Ok(interfaces) = nix::ifaddrs::getifaddrs() {
+        for interface in interfaces {
+            if interface.flags.contains(InterfaceFlags::IFF_LOOPBACK) {
+                continue;
+            }
+            let addr = interface.address.as_ref().and_then(|i| i.as_sockaddr_in());
+            let netmask = interface.netmask.as_ref().and_then(|i| i.as_sockaddr_in());
+            let (Some(addr), Some(netmask)) = (addr, netmask) else {
+                continue;
+            };
+            if !only.is_empty() && !only.contains(&IpAddr::V4(addr.ip())) {
+                continue;
+            }
+            if let Ok(net) = Ipv4Net::with_netmask(addr.ip(), netmask.ip()) {
+                _result.push(net);
+            }
+        }
+    }
+
+    _result
+}
+
+/// Returns `true` if `ip` is on one of the local `networks`. Private and link-local
+/// addresses are accepted if the networks are unknown.
+fn is_local_ipv4(ip: Ipv4Addr, networks: &[Ipv4Net]) -> bool {
+    if networks.is_empty() {
+        ip.is_private() || ip.is_link_local()
+    } else {
+        networks.iter().any(|net| net.contains(&ip))
+    }
+}
+
+fn udp_socket(addr: SocketAddr, reuse: bool) -> Result<Socket, anyhow::Error> {
+    let socket = Socket::new(
+        Domain::for_address(addr),
+        socket2::Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    if reuse {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+fn into_tokio(socket: Socket) -> Result<UdpSocket, anyhow::Error> {
+    let socket: std::net::UdpSocket = socket.into();
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket)?)
+}
+
 #[cfg(feature = "client")]
-pub async fn locate(peer_server: bool) -> Result<Server, anyhow::Error> {
-    use crate::common::fresh_socket_addr;
-    use std::{net::SocketAddrV6, time::Duration};
-    use tokio::time::timeout;
+fn describe(hostname: &Option<String>, socket: SocketAddr) -> String {
+    hostname
+        .as_ref()
+        .map(|hostname| format!("`{hostname}` {socket}"))
+        .unwrap_or(socket.to_string())
+}
 
-    fn handle_packet(
-        peer_server: bool,
-        packet: &[u8],
-        src: SocketAddr,
-    ) -> Result<Server, anyhow::Error> {
-        let data: Data = bincode::deserialize(packet)?;
-        if data.hello != Hello::new() {
-            bail!("Wrong hello");
+#[cfg(feature = "client")]
+fn handle_reply(
+    peer_server: bool,
+    packet: &[u8],
+    src: SocketAddr,
+    latency: Duration,
+) -> Result<Vec<Server>, anyhow::Error> {
+    let data: Data = bincode::deserialize(packet)?;
+    if data.hello != Hello::new() {
+        bail!("Wrong hello");
+    }
+    // Servers supporting version negotiation append the newest version they support
+    let max_protocol_version = bincode::serialized_size(&data)
+        .ok()
+        .and_then(|size| packet.get(size as usize..))
+        .and_then(|rest| bincode::deserialize::<u64>(rest).ok());
+    if let Message::Server {
+        peer,
+        port,
+        protocol_version,
+        software_version,
+        hostname,
+        ips,
+        label,
+    } = data.message
+    {
+        if peer != peer_server {
+            bail!("Wrong server kind");
         }
-        if let Message::Server {
-            peer,
-            port,
-            protocol_version,
+
+        // Prefer the addresses the server listens on
+        let socket = ips
+            .first()
+            .map(|&ip| SocketAddr::new(Ipv6Addr::from(ip).to_canonical(), port))
+            .unwrap_or_else(|| fresh_socket_addr(src, port));
+        let addresses: Vec<IpAddr> = if ips.is_empty() {
+            vec![socket.ip()]
+        } else {
+            ips.iter()
+                .map(|&ip| Ipv6Addr::from(ip).to_canonical())
+                .collect()
+        };
+
+        Ok(vec![Server {
+            at: describe(&hostname, socket),
+            socket,
             software_version,
+            protocol_version: max_protocol_version.unwrap_or(protocol_version),
+            min_protocol_version: protocol_version,
             hostname,
-            ips: _,
-            label: _,
-        } = data.message
-        {
-            if peer != peer_server {
-                bail!("Wrong server kind");
-            }
-            if protocol_version != protocol::VERSION {
-                bail!("Wrong protocol");
-            }
-            let socket = fresh_socket_addr(src, port);
+            label,
+            addresses,
+            latency,
+        }])
+    } else {
+        bail!("Wrong message")
+    }
+}
 
-            let at = hostname
-                .map(|hostname| format!("`{hostname}` {socket}"))
-                .unwrap_or(socket.to_string());
+#[cfg(feature = "client")]
+fn handle_mdns_reply(
+    peer_server: bool,
+    packet: &[u8],
+    src: SocketAddr,
+    latency: Duration,
+) -> Result<Vec<Server>, anyhow::Error> {
+    let servers = mdns::parse_response(packet)?
+        .into_iter()
+        .filter(|service| (service.txt("peer") == Some("1")) == peer_server)
+        .filter_map(|service| {
+            let protocol_version = service.txt("protocol")?.parse().ok()?;
+            let min_protocol_version = service
+                .txt("protocol-min")
+                .and_then(|version| version.parse().ok())
+                .unwrap_or(protocol_version);
+
+            // Prefer the address the reply came from, then one of the same family
+            let addresses = service.addresses.clone();
+            let socket = if addresses.is_empty() || addresses.contains(&src.ip()) {
+                fresh_socket_addr(src, service.port)
+            } else {
+                let ip = addresses
+                    .iter()
+                    .find(|ip| ip.is_ipv4() == src.is_ipv4())
+                    .unwrap_or(&addresses[0]);
+                SocketAddr::new(*ip, service.port)
+            };
+            let hostname = service.txt("hostname").map(str::to_owned);
 
-            Ok(Server {
-                at,
+            Some(Server {
+                at: describe(&hostname, socket),
                 socket,
-                software_version,
+                software_version: service.txt("version").unwrap_or_default().to_owned(),
+                protocol_version,
+                min_protocol_version,
+                label: service.txt("label").map(str::to_owned),
+                hostname,
+                addresses: if addresses.is_empty() {
+                    vec![socket.ip()]
+                } else {
+                    addresses
+                },
+                latency,
             })
-        } else {
-            bail!("Wrong message")
+        })
+        .collect();
+    Ok(servers)
+}
+
+/// Adds a server to `servers`, merging it with a reply from the same server
+/// received over another discovery mechanism.
+#[cfg(feature = "client")]
+fn merge(servers: &mut Vec<Server>, server: Server) {
+    let same = |other: &Server| {
+        other.socket.port() == server.socket.port()
+            && (other.socket == server.socket
+                || (server.hostname.is_some() && other.hostname == server.hostname)
+                || other
+                    .addresses
+                    .iter()
+                    .any(|ip| server.addresses.contains(ip)))
+    };
+    match servers.iter_mut().find(|other| same(other)) {
+        Some(other) => {
+            for ip in server.addresses {
+                if !other.addresses.contains(&ip) {
+                    other.addresses.push(ip);
+                }
+            }
         }
+        None => servers.push(server),
     }
+}
 
-    let socket = Socket::new(Domain::IPV6, socket2::Type::DGRAM, Some(Protocol::UDP))?;
-    socket.set_only_v6(true)?;
-    socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0).into())?;
-    let socket: std::net::UdpSocket = socket.into();
-    socket.set_nonblocking(true)?;
-    let socket = UdpSocket::from_std(socket)?;
+/// The replies to a discovery request from all discovery mechanisms.
+#[cfg(feature = "client")]
+struct Discovery {
+    replies: mpsc::UnboundedReceiver<Server>,
+    _receivers: JoinSet<()>,
+}
+
+#[cfg(feature = "client")]
+impl Discovery {
+    async fn next(&mut self) -> Option<Server> {
+        self.replies.recv().await
+    }
+}
+
+/// Sends a discovery request using IPv6 multicast, IPv4 broadcast and mDNS.
+#[cfg(feature = "client")]
+async fn send_discover(peer_server: bool, port: u16) -> Result<Discovery, anyhow::Error> {
+    type Handler = fn(bool, &[u8], SocketAddr, Duration) -> Result<Vec<Server>, anyhow::Error>;
+
+    let sent = Instant::now();
+    let (tx, replies) = mpsc::unbounded_channel();
+    let mut receivers = JoinSet::new();
 
-    socket.set_broadcast(true)?;
+    let mut receive = |socket: Socket, handler: Handler| -> Result<(), anyhow::Error> {
+        let socket = into_tokio(socket)?;
+        let tx = tx.clone();
+        receivers.spawn(async move {
+            let mut buf = [0; 1500];
+            loop {
+                if let Ok((len, src)) = socket.recv_from(&mut buf).await {
+                    let servers = handler(peer_server, &buf[..len], src, sent.elapsed());
+                    for server in servers.into_iter().flatten() {
+                        if tx.send(server).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        Ok(())
+    };
 
     let data = Data {
         hello: Hello::new(),
         message: Message::Discover { peer: peer_server },
     };
-
     let buf = bincode::serialize(&data)?;
+    let query = mdns::query();
 
-    let ip = Ipv6Addr::from_str("ff02::1").unwrap();
+    let ipv4_any = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+    let ipv6_any = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0);
+    let networks = ipv4_networks(&[]);
+    let interfaces = interfaces(&[]);
 
     let mut any = false;
-    for interface in interfaces() {
-        if socket
-            .send_to(&buf, SocketAddrV6::new(ip, DISCOVER_PORT, 0, interface))
-            .await
-            .is_ok()
-        {
+
+    // IPv6 multicast
+    if let Ok(socket) = udp_socket(ipv6_any, false) {
+        let ip = Ipv6Addr::from_str("ff02::1").unwrap();
+        let mut sent = false;
+        for &interface in &interfaces {
+            let addr = SocketAddrV6::new(ip, port, 0, interface);
+            sent |= socket.send_to(&buf, &addr.into()).is_ok();
+        }
+        if sent && receive(socket, handle_reply).is_ok() {
             any = true;
         }
     }
-    if !any {
-        bail!("Failed to send any discovery multicast packets");
+
+    // IPv4 subnet broadcast
+    if let Ok(socket) = udp_socket(ipv4_any, false) {
+        let mut sent = false;
+        if socket.set_broadcast(true).is_ok() {
+            let broadcasts = networks
+                .iter()
+                .map(|net| net.broadcast())
+                .chain([Ipv4Addr::BROADCAST]);
+            for ip in broadcasts {
+                let addr = SocketAddrV4::new(ip, port);
+                sent |= socket.send_to(&buf, &addr.into()).is_ok();
+            }
+        }
+        if sent && receive(socket, handle_reply).is_ok() {
+            any = true;
+        }
     }
 
-    let find = async {
-        let mut buf = [0; 1500];
-        loop {
-            if let Ok((len, src)) = socket.recv_from(&mut buf).await {
-                if let Ok(server) = handle_packet(peer_server, &buf[..len], src) {
-                    return server;
-                }
+    // mDNS over IPv4
+    if let Ok(socket) = udp_socket(ipv4_any, false) {
+        let addr = SocketAddrV4::new(mdns::IPV4, mdns::PORT).into();
+        let mut sent = false;
+        for net in &networks {
+            if socket.set_multicast_if_v4(&net.addr()).is_ok() {
+                sent |= socket.send_to(&query, &addr).is_ok();
             }
         }
+        if !sent {
+            sent = socket.send_to(&query, &addr).is_ok();
+        }
+        if sent && receive(socket, handle_mdns_reply).is_ok() {
+            any = true;
+        }
+    }
+
+    // mDNS over IPv6
+    if let Ok(socket) = udp_socket(ipv6_any, false) {
+        let mut sent = false;
+        for &interface in &interfaces {
+            let addr = SocketAddrV6::new(mdns::IPV6, mdns::PORT, 0, interface);
+            sent |= socket.send_to(&query, &addr.into()).is_ok();
+        }
+        if sent && receive(socket, handle_mdns_reply).is_ok() {
+            any = true;
+        }
+    }
+
+    if !any {
+        bail!("Failed to send any discovery packets");
+    }
+
+    Ok(Discovery {
+        replies,
+        _receivers: receivers,
+    })
+}
+
+/// Collects the servers replying to a discovery request sent to `port` within `window`,
+/// ordered by latency. Servers with an incompatible protocol version are included.
+#[cfg(feature = "client")]
+pub async fn discover_async(
+    peer_server: bool,
+    port: u16,
+    window: Duration,
+) -> Result<Vec<Server>, anyhow::Error> {
+    let mut discovery = send_discover(peer_server, port).await?;
+
+    let mut servers: Vec<Server> = Vec::new();
+    let collect = async {
+        while let Some(server) = discovery.next().await {
+            merge(&mut servers, server);
+        }
     };
+    timeout(window, collect).await.ok();
+
+    servers.sort_by_key(|server| server.latency);
+    Ok(servers)
+}
+
+/// Collects the servers replying to a discovery request sent to `port` within 1 second.
+#[cfg(feature = "client")]
+pub fn discover(peer_server: bool, port: u16) -> Result<Vec<Server>, anyhow::Error> {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(discover_async(peer_server, port, Duration::from_secs(1)))
+}
 
-    timeout(Duration::from_secs(1), find).await.map_err(|_| {
-        if peer_server {
-            anyhow!("Failed to locate local latency peer")
+/// Locates a server on the local network. With `lowest_latency` all replies within 1 second
+/// are collected and the server with the lowest latency is used. Otherwise the first
+/// compatible server to reply is used. Discovery requests are sent to `port`.
+#[cfg(feature = "client")]
+pub async fn locate(
+    peer_server: bool,
+    lowest_latency: bool,
+    port: u16,
+) -> Result<Server, anyhow::Error> {
+    let not_found = |incompatible: Option<&Server>| {
+        let kind = if peer_server {
+            "local latency peer"
         } else {
-            anyhow!("Failed to locate local server")
+            "local server"
+        };
+        match incompatible {
+            Some(server) => anyhow!(
+                "Failed to locate {}. Found {} running incompatible version {}, \
+                    this client is version {}",
+                kind,
+                server.at,
+                server.software_version,
+                version()
+            ),
+            None => anyhow!("Failed to locate {}", kind),
         }
-    })
+    };
+
+    if lowest_latency {
+        let servers = discover_async(peer_server, port, Duration::from_secs(1)).await?;
+        return servers
+            .iter()
+            .find(|server| server.compatible())
+            .cloned()
+            .ok_or_else(|| not_found(servers.first()));
+    }
+
+    let mut discovery = send_discover(peer_server, port).await?;
+
+    let mut incompatible = None;
+    let find = async {
+        while let Some(server) = discovery.next().await {
+            if server.compatible() {
+                return Some(server);
+            }
+            incompatible = Some(server);
+        }
+        None
+    };
+
+    match timeout(Duration::from_secs(1), find).await {
+        Ok(Some(server)) => Ok(server),
+        _ => Err(not_found(incompatible.as_ref())),
+    }
+}
+
+/// The information a server replies to discovery requests with.
+struct Responder {
+    state: Arc<State>,
+    port: u16,
+    hostname: Option<String>,
+    /// The specific addresses the server listens on.
+    listen: Vec<IpAddr>,
+    /// The server listens on all IPv4 addresses.
+    all_ipv4: bool,
+    /// The server listens on all IPv6 addresses.
+    all_ipv6: bool,
 }
 
-pub fn serve(state: Arc<State>, port: u16) -> Result<(), anyhow::Error> {
+impl Responder {
+    fn listens_on(&self, ip: IpAddr) -> bool {
+        let all = match ip {
+            IpAddr::V4(_) => self.all_ipv4,
+            IpAddr::V6(_) => self.all_ipv6,
+        };
+        all || self.listen.contains(&ip)
+    }
+
+    fn reply(&self) -> Result<Vec<u8>, anyhow::Error> {
+        let ips = self
+            .listen
+            .iter()
+            .map(|ip| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+                IpAddr::V6(ip) => ip.octets(),
+            })
+            .collect();
+        let data = Data {
+            hello: Hello::new(),
+            message: Message::Server {
+                peer: self.state.peer_server,
+                port: self.port,
+                // Clients before version negotiation require an exact match
+                protocol_version: protocol::MIN_VERSION,
+                software_version: version(),
+                hostname: self.hostname.clone(),
+                label: self.state.label.clone(),
+                ips,
+            },
+        };
+        // Older clients ignore the trailing newest supported version
+        let mut reply = bincode::serialize(&data)?;
+        reply.extend(bincode::serialize(&protocol::VERSION)?);
+        Ok(reply)
+    }
+
     async fn handle_packet(
-        port: u16,
-        peer_server: bool,
-        hostname: &Option<String>,
+        &self,
         packet: &[u8],
         socket: &UdpSocket,
         src: SocketAddr,
     ) -> Result<(), anyhow::Error> {
-        match src {
-            SocketAddr::V6(src) if is_unicast_link_local(*src.ip()) => (),
-            _ => bail!("Unexpected source"),
-        }
         let data: Data = bincode::deserialize(packet)?;
         if data.hello != Hello::new() {
             bail!("Wrong hello");
         }
         if let Message::Discover { peer } = data.message {
-            if peer != peer_server {
+            if peer != self.state.peer_server || !self.allowed(src) {
                 return Ok(());
             }
-            let data = Data {
-                hello: Hello::new(),
-                message: Message::Server {
-                    peer,
-                    port,
-                    protocol_version: protocol::VERSION,
-                    software_version: version(),
-                    hostname: hostname.clone(),
-                    label: None,
-                    ips: Vec::new(),
-                },
-            };
-            let buf = bincode::serialize(&data)?;
-            socket.send_to(&buf, src).await?;
+            socket.send_to(&self.reply()?, src).await?;
         }
         Ok(())
     }
 
+    fn service(&self) -> mdns::Service {
+        let name = self
+            .state
+            .label
+            .clone()
+            .or(self.hostname.clone())
+            .unwrap_or("Crusader".to_owned());
+        let instance = if self.state.peer_server {
+            format!("{name} (peer)")
+        } else {
+            name
+        };
+        let host = self
+            .hostname
+            .as_deref()
+            .and_then(|hostname| hostname.split('.').next())
+            .unwrap_or("crusader");
+
+        let mut txt = vec![
+            ("protocol".to_owned(), protocol::VERSION.to_string()),
+            ("protocol-min".to_owned(), protocol::MIN_VERSION.to_string()),
+            ("version".to_owned(), version()),
+            (
+                "peer".to_owned(),
+                (self.state.peer_server as u8).to_string(),
+            ),
+        ];
+        if let Some(hostname) = &self.hostname {
+            txt.push(("hostname".to_owned(), hostname.clone()));
+        }
+        if let Some(label) = &self.state.label {
+            txt.push(("label".to_owned(), label.clone()));
+        }
+
+        mdns::Service {
+            instance: mdns::label(&instance),
+            host: mdns::label(host),
+            port: self.port,
+            txt,
+            addresses: interface_ips()
+                .into_iter()
+                .map(|(_, ip)| ip)
+                .filter(|&ip| self.listens_on(ip))
+                .collect(),
+        }
+    }
+
+    async fn handle_mdns_packet(
+        &self,
+        packet: &[u8],
+        socket: &UdpSocket,
+        src: SocketAddr,
+    ) -> Result<(), anyhow::Error> {
+        // Other mDNS traffic on the network is ignored
+        let Ok(Some(query)) = mdns::parse_query(packet) else {
+            return Ok(());
+        };
+        if !self.allowed(src) {
+            return Ok(());
+        }
+        // Queries from other ports are one-shot queries expecting a direct response
+        let legacy = src.port() != mdns::PORT;
+        let dest = match src {
+            _ if legacy || query.unicast => src,
+            SocketAddr::V4(_) => SocketAddrV4::new(mdns::IPV4, mdns::PORT).into(),
+            SocketAddr::V6(src) => {
+                SocketAddrV6::new(mdns::IPV6, mdns::PORT, 0, src.scope_id()).into()
+            }
+        };
+        let response = mdns::response(&self.service(), legacy.then_some(&query));
+        socket.send_to(&response, dest).await?;
+        Ok(())
+    }
+
+    /// Returns `true` if `src` is on a local network the server listens on.
+    fn is_local(&self, src: SocketAddr, interfaces: &[u32], networks: &[Ipv4Net]) -> bool {
+        match src {
+            SocketAddr::V6(src) => {
+                is_unicast_link_local(*src.ip())
+                    && (self.all_ipv6 || interfaces.contains(&src.scope_id()))
+            }
+            SocketAddr::V4(src) => is_local_ipv4(*src.ip(), networks),
+        }
+    }
+
+    fn allowed(&self, src: SocketAddr) -> bool {
+        let allowed = self.state.access.allows(src.ip());
+        if !allowed {
+            Metrics::inc(&self.state.metrics.rejected_discovery_requests);
+            self.state
+                .rejected(&format!("Rejected discovery request from {}", src));
+        }
+        allowed
+    }
+}
+
+pub(crate) fn serve(
+    state: Arc<State>,
+    port: u16,
+    discovery_port: u16,
+    listen: &[IpAddr],
+) -> Result<(), anyhow::Error> {
     let hostname = hostname::get()
         .ok()
         .and_then(|n| n.into_string().ok())
         .filter(|n| n != "localhost");
 
-    let socket = Socket::new(Domain::IPV6, socket2::Type::DGRAM, Some(Protocol::UDP))?;
-    socket.set_only_v6(true)?;
-    socket.set_reuse_address(true)?;
-    socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), DISCOVER_PORT).into())?;
-    let socket: std::net::UdpSocket = socket.into();
-    socket.set_nonblocking(true)?;
-    let socket = UdpSocket::from_std(socket)?;
+    let responder = Arc::new(Responder {
+        state: state.clone(),
+        port,
+        hostname,
+        listen: listen
+            .iter()
+            .copied()
+            .filter(|ip| !ip.is_unspecified())
+            .collect(),
+        // Unspecified addresses listen on all interfaces
+        all_ipv4: listen.is_empty() || listen.contains(&IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        all_ipv6: listen.is_empty() || listen.contains(&IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    });
 
-    let ip = Ipv6Addr::from_str("ff02::1").unwrap();
+    let all: &[IpAddr] = &[];
+    let interfaces: Arc<[u32]> = interfaces(if responder.all_ipv6 {
+        all
+    } else {
+        &responder.listen
+    })
+    .into();
+    let networks: Arc<[Ipv4Net]> = ipv4_networks(if responder.all_ipv4 {
+        all
+    } else {
+        &responder.listen
+    })
+    .into();
+    let ipv4 = listen.is_empty() || listen.iter().any(|ip| ip.is_ipv4());
+    let ipv6 = listen.is_empty() || listen.iter().any(|ip| ip.is_ipv6());
 
-    let mut any = false;
-    for interface in interfaces() {
-        if socket.join_multicast_v6(&ip, interface).is_ok() {
-            any = true;
+    let ipv4_any = |port| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+    let ipv6_any = |port| SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+
+    let join_ipv6 = |socket: &Socket, ip: &Ipv6Addr| {
+        let mut any = false;
+        for &interface in interfaces.iter() {
+            any |= socket.join_multicast_v6(ip, interface).is_ok();
+        }
+        if !any {
+            bail!("Failed to join any multicast groups");
+        }
+        Ok(())
+    };
+
+    let mut sockets = Vec::new();
+    let mut errors = Vec::new();
+
+    if ipv6 {
+        let ip = Ipv6Addr::from_str("ff02::1").unwrap();
+        match udp_socket(ipv6_any(discovery_port), true)
+            .and_then(|socket| join_ipv6(&socket, &ip).map(|_| socket))
+        {
+            Ok(socket) => sockets.push((socket, false)),
+            Err(error) => errors.push(format!("IPv6 multicast: {:?}", error)),
+        }
+
+        match udp_socket(ipv6_any(mdns::PORT), true)
+            .and_then(|socket| join_ipv6(&socket, &mdns::IPV6).map(|_| socket))
+        {
+            Ok(socket) => sockets.push((socket, true)),
+            Err(error) => errors.push(format!("mDNS over IPv6: {:?}", error)),
         }
     }
-    if !any {
-        bail!("Failed to join any multicast groups");
-    }
-
-    tokio::spawn(async move {
-        let mut buf = [0; 1500];
-        loop {
-            if let Ok((len, src)) = socket.recv_from(&mut buf).await {
-                handle_packet(
-                    port,
-                    state.peer_server,
-                    &hostname,
-                    &buf[..len],
-                    &socket,
-                    src,
-                )
-                .await
-                .map_err(|error| {
-                    (state.msg)(&format!("Unable to handle discovery packet: {:?}", error));
-                })
-                .ok();
+
+    if ipv4 {
+        match udp_socket(ipv4_any(discovery_port), true) {
+            Ok(socket) => sockets.push((socket, false)),
+            Err(error) => errors.push(format!("IPv4 broadcast: {:?}", error)),
+        }
+
+        let mdns = udp_socket(ipv4_any(mdns::PORT), true).and_then(|socket| {
+            let mut joined = false;
+            for net in networks.iter() {
+                joined |= socket.join_multicast_v4(&mdns::IPV4, &net.addr()).is_ok();
             }
+            if !joined {
+                socket.join_multicast_v4(&mdns::IPV4, &Ipv4Addr::UNSPECIFIED)?;
+            }
+            Ok(socket)
+        });
+        match mdns {
+            Ok(socket) => sockets.push((socket, true)),
+            Err(error) => errors.push(format!("mDNS over IPv4: {:?}", error)),
         }
-    });
+    }
+
+    if sockets.is_empty() {
+        bail!("{}", errors.join(", "));
+    }
+    for error in errors {
+        (state.msg)(&format!("Discovery unavailable using {}", error));
+    }
+
+    for (socket, is_mdns) in sockets {
+        let socket = into_tokio(socket)?;
+        let responder = responder.clone();
+        let interfaces = interfaces.clone();
+        let networks = networks.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            loop {
+                if let Ok((len, src)) = socket.recv_from(&mut buf).await {
+                    if !responder.is_local(src, &interfaces, &networks) {
+                        continue;
+                    }
+                    let result = if is_mdns {
+                        responder
+                            .handle_mdns_packet(&buf[..len], &socket, src)
+                            .await
+                    } else {
+                        responder.handle_packet(&buf[..len], &socket, src).await
+                    };
+                    result
+                        .map_err(|error| {
+                            responder.state.rejected(&format!(
+                                "Unable to handle discovery packet: {:?}",
+                                error
+                            ));
+                        })
+                        .ok();
+                }
+            }
+        });
+    }
 
     Ok(())
 }
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+
+    fn reply(protocol_version: u64, max_protocol_version: Option<u64>) -> Vec<u8> {
+        let data = Data {
+            hello: Hello::new(),
+            message: Message::Server {
+                peer: false,
+                port: protocol::PORT,
+                protocol_version,
+                software_version: "0.0.0".to_owned(),
+                hostname: None,
+                label: None,
+                ips: Vec::new(),
+            },
+        };
+        let mut reply = bincode::serialize(&data).unwrap();
+        if let Some(version) = max_protocol_version {
+            reply.extend(bincode::serialize(&version).unwrap());
+        }
+        reply
+    }
+
+    fn server(packet: &[u8]) -> Server {
+        let src = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), DISCOVER_PORT);
+        handle_reply(false, packet, src, Duration::ZERO)
+            .unwrap()
+            .remove(0)
+    }
+
+    #[test]
+    fn version_range() {
+        let server = server(&reply(protocol::MIN_VERSION, Some(protocol::VERSION)));
+        assert_eq!(server.min_protocol_version, protocol::MIN_VERSION);
+        assert_eq!(server.protocol_version, protocol::VERSION);
+        assert!(server.compatible());
+    }
+
+    #[test]
+    fn old_servers() {
+        let server = server(&reply(protocol::MIN_VERSION, None));
+        assert_eq!(server.min_protocol_version, protocol::MIN_VERSION);
+        assert_eq!(server.protocol_version, protocol::MIN_VERSION);
+        assert!(server.compatible());
+
+        assert!(!self::server(&reply(protocol::MIN_VERSION - 1, None)).compatible());
+        assert!(
+            !self::server(&reply(protocol::VERSION + 1, Some(protocol::VERSION + 2))).compatible()
+        );
+    }
+
+    #[test]
+    fn old_clients() {
+        // Clients before version negotiation ignore trailing bytes and require an exact version
+        let packet = reply(protocol::MIN_VERSION, Some(protocol::VERSION));
+        let data: Data = bincode::deserialize(&packet).unwrap();
+        match data.message {
+            Message::Server {
+                protocol_version, ..
+            } => assert_eq!(protocol_version, 3),
+            _ => panic!("Wrong message"),
+        }
+    }
+}
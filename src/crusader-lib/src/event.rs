@@ -0,0 +1,84 @@
+use serde::Serialize;
+use std::net::SocketAddr;
+
+/// A server event written as a JSON record when using [`crate::serve::LogFormat::Json`].
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event {
+    /// A TCP connection was accepted, either a control or load connection.
+    Connected {
+        addr: SocketAddr,
+    },
+    /// A connection was rejected by the access list.
+    Rejected {
+        addr: SocketAddr,
+    },
+    HelloMismatch {
+        addr: SocketAddr,
+        version: u64,
+        expected_version: u64,
+    },
+    AuthenticationFailed {
+        addr: SocketAddr,
+    },
+    TestStarted {
+        addr: SocketAddr,
+        slot: u64,
+        version: u64,
+    },
+    TestCompleted {
+        addr: SocketAddr,
+        slot: u64,
+    },
+    LoadStream {
+        addr: SocketAddr,
+        slot: u64,
+        direction: Direction,
+        group: u32,
+        id: u32,
+        duration_ms: u64,
+        bytes: u64,
+    },
+    PeerSession {
+        addr: SocketAddr,
+        server: SocketAddr,
+        version: u64,
+    },
+    /// The server was unable to forward latency measurements quickly enough.
+    Overload {
+        addr: SocketAddr,
+        slot: u64,
+    },
+    Error {
+        addr: SocketAddr,
+        slot: Option<u64>,
+        error: String,
+    },
+    /// A regular text message from the server.
+    Message {
+        message: String,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Direction {
+    /// The server sent data to the client.
+    Download,
+    /// The client sent data to the server.
+    Upload,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+impl Event {
+    pub(crate) fn to_json(&self) -> String {
+        let time = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false);
+        serde_json::to_string(&Record { time, event: self }).unwrap()
+    }
+}
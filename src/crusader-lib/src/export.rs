@@ -0,0 +1,453 @@
+//! Exports of results for other tools. The CSV export has the latency and throughput time series
+//! of a result. The summary export has those and the summary of each test, in a stable JSON
+//! format described by `export.schema.json`.
+
+use anyhow::Context;
+use serde::Serialize;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::file_format::{RawPing, TestKind};
+use crate::info::{summaries, LatencyInfo, LossInfo};
+use crate::plot::TestResult;
+
+fn seconds(time: Duration, start: Duration) -> f64 {
+    time.as_secs_f64() - start.as_secs_f64()
+}
+
+fn milliseconds(duration: Option<Duration>) -> String {
+    duration
+        .map(|duration| format!("{:.3}", duration.as_secs_f64() * 1000.0))
+        .unwrap_or_default()
+}
+
+/// The test a ping was sent during, or `Idle` outside of the load.
+fn phase(result: &TestResult, ping: &RawPing) -> &'static str {
+    result
+        .raw_result
+        .test_data
+        .iter()
+        .find(|test| test.start <= ping.sent && ping.sent <= test.end)
+        .map(|test| test.kind.name())
+        .unwrap_or("Idle")
+}
+
+/// Writes one row per latency sample of the client, followed by the samples of the latency peer.
+/// Times are in seconds from the start of the test and latencies in milliseconds.
+pub fn write_latency_csv(result: &TestResult, out: impl Write) -> Result<(), anyhow::Error> {
+    let mut out = BufWriter::new(out);
+
+    writeln!(
+        out,
+        "source,index,sent,total,up,down,lost_up,lost_down,phase"
+    )?;
+
+    let sources = [
+        ("client", Some(&result.pings)),
+        ("peer", result.raw_result.peer_pings.as_ref()),
+    ];
+
+    for (source, pings) in sources {
+        for ping in pings.into_iter().flatten() {
+            writeln!(
+                out,
+                "{},{},{:.6},{},{},{},{},{},{}",
+                source,
+                ping.index,
+                seconds(ping.sent, result.start),
+                milliseconds(ping.latency.and_then(|latency| latency.total)),
+                milliseconds(ping.latency.map(|latency| latency.up)),
+                milliseconds(ping.latency.and_then(|latency| latency.down())),
+                ping.latency.is_none() as u8,
+                ping.latency
+                    .map(|latency| latency.total.is_none())
+                    .unwrap_or(false) as u8,
+                phase(result, ping),
+            )?;
+        }
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+/// The transferred bytes of a series at `time`.
+fn bytes_at(series: &[(u64, f64)], time: u64) -> f64 {
+    match series.binary_search_by_key(&time, |point| point.0) {
+        Ok(i) => series[i].1,
+        Err(0) => 0.0,
+        Err(i) => series[i - 1].1,
+    }
+}
+
+/// The throughput in Mbps of each stream and the total of each load direction, with one row
+/// per throughput sample interval. Returns the column names and the rows, which have the time
+/// in seconds from the start of the test and the throughput of each column.
+fn throughput_table(result: &TestResult) -> (Vec<String>, Vec<(f64, Vec<f64>)>) {
+    // The streams of a group are stacked, each one includes the bytes of the previous streams
+    let mut columns: Vec<(String, Vec<(u64, f64)>)> = Vec::new();
+    for group in &result.stream_groups {
+        let name = match (group.both, group.download) {
+            (false, true) => TestKind::Download.name().to_owned(),
+            (false, false) => TestKind::Upload.name().to_owned(),
+            (true, true) => format!("{} download", TestKind::Bidirectional.name()),
+            (true, false) => format!("{} upload", TestKind::Bidirectional.name()),
+        }
+        .to_lowercase()
+        .replace(' ', "_");
+
+        let mut previous: &[(u64, f64)] = &[];
+        for (i, stream) in group.streams.iter().enumerate() {
+            let bytes = stream
+                .data
+                .iter()
+                .map(|&(time, bytes)| (time, bytes - bytes_at(previous, time)))
+                .collect();
+            columns.push((format!("{}_stream_{}", name, i + 1), bytes));
+            previous = &stream.data;
+        }
+        columns.push((format!("{}_total", name), previous.to_vec()));
+    }
+
+    let mut times: Vec<u64> = columns
+        .iter()
+        .flat_map(|column| column.1.iter().map(|point| point.0))
+        .collect();
+    times.sort_unstable();
+    times.dedup();
+
+    let rows = times
+        .iter()
+        .enumerate()
+        .map(|(i, &time)| {
+            let rates = columns
+                .iter()
+                .map(|(_, bytes)| match i.checked_sub(1).map(|i| times[i]) {
+                    Some(last) => {
+                        let bytes = bytes_at(bytes, time) - bytes_at(bytes, last);
+                        let duration = Duration::from_micros(time - last).as_secs_f64();
+                        (bytes * 8.0) / (1000.0 * 1000.0) / duration
+                    }
+                    None => 0.0,
+                })
+                .collect();
+            (seconds(Duration::from_micros(time), result.start), rates)
+        })
+        .collect();
+
+    (columns.into_iter().map(|(name, _)| name).collect(), rows)
+}
+
+/// Writes the throughput in Mbps of each stream and the total of each load direction,
+/// with one row per throughput sample interval. Times are in seconds from the start of the test.
+pub fn write_throughput_csv(result: &TestResult, out: impl Write) -> Result<(), anyhow::Error> {
+    let mut out = BufWriter::new(out);
+
+    let (columns, rows) = throughput_table(result);
+
+    write!(out, "time")?;
+    for name in &columns {
+        write!(out, ",{}", name)?;
+    }
+    writeln!(out)?;
+
+    for (time, rates) in rows {
+        write!(out, "{:.6}", time)?;
+        for rate in rates {
+            write!(out, ",{:.3}", rate)?;
+        }
+        writeln!(out)?;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+/// Saves the latency and throughput of a result as `<stem>.latency.csv` and
+/// `<stem>.throughput.csv`. Existing files are only replaced if `overwrite` is set.
+pub fn save_csv(
+    result: &TestResult,
+    stem: &Path,
+    overwrite: bool,
+) -> Result<[PathBuf; 2], anyhow::Error> {
+    let path = |kind: &str| {
+        let mut name = stem.as_os_str().to_owned();
+        name.push(format!(".{}.csv", kind));
+        PathBuf::from(name)
+    };
+    let create = |path: &Path| {
+        OpenOptions::new()
+            .create_new(!overwrite)
+            .create(overwrite)
+            .truncate(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to create {}", path.display()))
+    };
+
+    let latency = path("latency");
+    let throughput = path("throughput");
+    write_latency_csv(result, create(&latency)?)?;
+    write_throughput_csv(result, create(&throughput)?)?;
+    Ok([latency, throughput])
+}
+
+/// The version of the summary export format. It's increased when fields are removed or change
+/// meaning, but not when fields are added.
+pub const SCHEMA_VERSION: u64 = 1;
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// A result in the summary export format. Times are in milliseconds from the start of the test,
+/// other durations are in milliseconds and throughputs in Mbps.
+#[derive(Serialize)]
+pub struct Export {
+    pub schema_version: u64,
+    pub generated_by: String,
+    /// The version of the result file format the result was loaded from.
+    pub result_version: u64,
+    pub config: ExportConfig,
+    pub ipv6: bool,
+    pub tls: bool,
+    pub server_overload: bool,
+    pub load_termination_timeout: bool,
+    pub concurrent_load: bool,
+    pub server_latency_ms: f64,
+    pub duration_ms: f64,
+    pub metadata: Option<ExportMetadata>,
+    pub trim: Option<ExportTrim>,
+    pub phases: Vec<ExportPhase>,
+    pub pings: Vec<ExportPing>,
+    pub peer_pings: Option<Vec<ExportPing>>,
+    /// The series of the CSV throughput export.
+    pub throughput: Vec<ExportSeries>,
+}
+
+#[derive(Serialize)]
+pub struct ExportConfig {
+    pub streams: u64,
+    pub stream_stagger_ms: f64,
+    pub load_duration_ms: f64,
+    pub grace_duration_ms: f64,
+    pub latency_sample_interval_ms: f64,
+    pub throughput_sample_interval_ms: f64,
+}
+
+#[derive(Serialize)]
+pub struct ExportMetadata {
+    pub server_address: String,
+    pub server_port: u16,
+    pub server_hostname: Option<String>,
+    pub server_version: String,
+    pub client_os: String,
+    pub interface: Option<String>,
+    pub local_address: String,
+    /// RFC 3339 with the offset of the client.
+    pub start_time: String,
+    pub notes: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// The window of the original test kept by a trimmed result.
+#[derive(Serialize)]
+pub struct ExportTrim {
+    pub from_ms: f64,
+    pub to_ms: f64,
+    pub original_duration_ms: f64,
+}
+
+/// The summary of a test, or of the whole result for the idle latency measurement.
+#[derive(Serialize)]
+pub struct ExportPhase {
+    /// `download`, `upload`, `bidirectional` or `idle`.
+    pub test: &'static str,
+    pub start_ms: Option<f64>,
+    pub end_ms: Option<f64>,
+    pub throughput_mbps: Option<f64>,
+    pub throughput_down_mbps: Option<f64>,
+    pub throughput_up_mbps: Option<f64>,
+    pub peak_latency: Option<ExportLatency>,
+    pub median_latency_ms: Option<f64>,
+    pub packet_loss: Option<ExportLoss>,
+    pub peer_peak_latency: Option<ExportLatency>,
+    pub peer_median_latency_ms: Option<f64>,
+    pub peer_packet_loss: Option<ExportLoss>,
+}
+
+#[derive(Serialize)]
+pub struct ExportLatency {
+    pub total_ms: f64,
+    pub down_ms: f64,
+    pub up_ms: f64,
+}
+
+impl From<LatencyInfo> for ExportLatency {
+    fn from(latency: LatencyInfo) -> Self {
+        ExportLatency {
+            total_ms: latency.total,
+            down_ms: latency.down,
+            up_ms: latency.up,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ExportLoss {
+    pub down_percent: f64,
+    pub up_percent: f64,
+}
+
+impl From<LossInfo> for ExportLoss {
+    fn from(loss: LossInfo) -> Self {
+        ExportLoss {
+            down_percent: loss.down * 100.0,
+            up_percent: loss.up * 100.0,
+        }
+    }
+}
+
+/// A latency sample. The latencies of lost pings are `None`.
+#[derive(Serialize)]
+pub struct ExportPing {
+    pub index: u64,
+    pub sent_ms: f64,
+    pub total_ms: Option<f64>,
+    pub up_ms: Option<f64>,
+    pub down_ms: Option<f64>,
+    pub lost_up: bool,
+    pub lost_down: bool,
+}
+
+impl ExportPing {
+    fn new(ping: &RawPing, start: Duration) -> Self {
+        ExportPing {
+            index: ping.index,
+            sent_ms: ms(ping.sent) - ms(start),
+            total_ms: ping.latency.and_then(|latency| latency.total).map(ms),
+            up_ms: ping.latency.map(|latency| ms(latency.up)),
+            down_ms: ping.latency.and_then(|latency| latency.down()).map(ms),
+            lost_up: ping.latency.is_none(),
+            lost_down: ping
+                .latency
+                .map(|latency| latency.total.is_none())
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// The throughput of a stream or of the total of a load direction, named like the CSV columns.
+#[derive(Serialize)]
+pub struct ExportSeries {
+    pub name: String,
+    pub samples: Vec<ExportRate>,
+}
+
+#[derive(Serialize)]
+pub struct ExportRate {
+    pub time_ms: f64,
+    pub mbps: f64,
+}
+
+impl Export {
+    pub fn new(result: &TestResult) -> Self {
+        let raw = &result.raw_result;
+        let config = &raw.config;
+
+        let (columns, rows) = throughput_table(result);
+        let throughput = columns
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| ExportSeries {
+                name,
+                samples: rows
+                    .iter()
+                    .map(|(time, rates)| ExportRate {
+                        time_ms: time * 1000.0,
+                        mbps: rates[i],
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Export {
+            schema_version: SCHEMA_VERSION,
+            generated_by: raw.generated_by.clone(),
+            result_version: raw.version,
+            config: ExportConfig {
+                streams: raw.streams(),
+                stream_stagger_ms: ms(config.stagger),
+                load_duration_ms: ms(config.load_duration),
+                grace_duration_ms: ms(config.grace_duration),
+                latency_sample_interval_ms: ms(config.ping_interval),
+                throughput_sample_interval_ms: ms(config.bandwidth_interval),
+            },
+            ipv6: raw.ipv6,
+            tls: raw.tls,
+            server_overload: raw.server_overload,
+            load_termination_timeout: raw.load_termination_timeout,
+            concurrent_load: raw.concurrent_load,
+            server_latency_ms: ms(raw.server_latency),
+            duration_ms: ms(raw.duration),
+            metadata: raw.metadata.as_ref().map(|metadata| ExportMetadata {
+                server_address: metadata.server_address.clone(),
+                server_port: metadata.server_port,
+                server_hostname: metadata.server_hostname.clone(),
+                server_version: metadata.server_version.clone(),
+                client_os: metadata.client_os.clone(),
+                interface: metadata.interface.clone(),
+                local_address: metadata.local_address.clone(),
+                start_time: metadata.start_time.clone(),
+                notes: metadata.notes.clone(),
+                tags: metadata.tags.clone(),
+            }),
+            trim: raw.trim.as_ref().map(|trim| ExportTrim {
+                from_ms: ms(trim.from),
+                to_ms: ms(trim.to),
+                original_duration_ms: ms(trim.original_duration),
+            }),
+            phases: summaries(result)
+                .into_iter()
+                .map(|summary| {
+                    let test = summary
+                        .kind
+                        .and_then(|kind| raw.test_data.iter().find(|test| test.kind == kind));
+                    ExportPhase {
+                        test: match summary.kind {
+                            Some(TestKind::Download) => "download",
+                            Some(TestKind::Upload) => "upload",
+                            Some(TestKind::Bidirectional) => "bidirectional",
+                            None => "idle",
+                        },
+                        start_ms: test.map(|test| ms(test.start) - ms(raw.start)),
+                        end_ms: test.map(|test| ms(test.end) - ms(raw.start)),
+                        throughput_mbps: summary.throughput,
+                        throughput_down_mbps: summary.throughput_down,
+                        throughput_up_mbps: summary.throughput_up,
+                        peak_latency: summary.latency.map(Into::into),
+                        median_latency_ms: summary.median_latency,
+                        packet_loss: summary.loss.map(Into::into),
+                        peer_peak_latency: summary.peer_latency.map(Into::into),
+                        peer_median_latency_ms: summary.peer_median_latency,
+                        peer_packet_loss: summary.peer_loss.map(Into::into),
+                    }
+                })
+                .collect(),
+            pings: result
+                .pings
+                .iter()
+                .map(|ping| ExportPing::new(ping, raw.start))
+                .collect(),
+            peer_pings: raw.peer_pings.as_ref().map(|pings| {
+                pings
+                    .iter()
+                    .map(|ping| ExportPing::new(ping, raw.start))
+                    .collect()
+            }),
+            throughput,
+        }
+    }
+}
@@ -1,5 +1,12 @@
+use anyhow::bail;
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::error::Error;
+use std::fmt;
 use std::fs::File;
+use std::io;
+use std::io::BufRead;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Read;
@@ -86,9 +93,13 @@ impl RawResultV0 {
             stream_groups: self.stream_groups.clone(),
             pings: self.pings.iter().map(|ping| ping.to_v1()).collect(),
             server_overload: false,
+            concurrent_load: false,
             load_termination_timeout: false,
             peer_pings: None,
             test_data: Vec::new(),
+            tls: false,
+            metadata: None,
+            trim: None,
         }
     }
 }
@@ -161,6 +172,51 @@ pub struct RawConfig {
     pub bandwidth_interval: Duration,
 }
 
+/// The reason a result could not be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file could not be read.
+    Io(io::Error),
+    /// The file is not a Crusader result.
+    NotCrusaderFile,
+    /// The result was saved by a newer version of Crusader using this file format version.
+    UnsupportedVersion(u64),
+    /// The result data could not be decoded, with the message of the decoder.
+    Corrupt(String),
+    /// The file is a JSON export in the format with summaries, which doesn't have all the data.
+    SummaryExport,
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(error) => write!(f, "Unable to read the file: {}", error),
+            LoadError::NotCrusaderFile => write!(f, "Not a Crusader result file"),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "The result uses file format version {}, which is newer than the supported \
+                version {}. Upgrade Crusader to load it",
+                version,
+                RawHeader::default().version
+            ),
+            LoadError::Corrupt(message) => write!(f, "The result data is corrupt: {}", message),
+            LoadError::SummaryExport => write!(
+                f,
+                "The file is a summary export which can't be loaded. \
+                Export the result with the `json` format instead"
+            ),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(error: io::Error) -> Self {
+        LoadError::Io(error)
+    }
+}
+
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
 pub struct RawHeader {
     pub magic: u64,
@@ -171,11 +227,69 @@ impl Default for RawHeader {
     fn default() -> Self {
         Self {
             magic: protocol::MAGIC,
-            version: 2,
+            version: 3,
         }
     }
 }
 
+/// Describes where, when and how a test was run.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct RawMetadata {
+    /// The address of the server, without the port.
+    pub server_address: String,
+    pub server_port: u16,
+    /// The host name given for the server or reported by it during discovery.
+    pub server_hostname: Option<String>,
+    pub server_version: String,
+    /// The operating system and architecture of the client.
+    pub client_os: String,
+    /// The local interface used to reach the server.
+    pub interface: Option<String>,
+    pub local_address: String,
+    /// The wall-clock time the test started at, in RFC 3339 format with the local offset.
+    pub start_time: String,
+    /// The number of streams requested for each load.
+    pub streams: u64,
+    pub notes: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl RawMetadata {
+    /// The host name of the server if known, otherwise its address.
+    pub fn server_name(&self) -> &str {
+        self.server_hostname
+            .as_deref()
+            .unwrap_or(&self.server_address)
+    }
+
+    /// The notes and tags of the test on a single line.
+    pub fn annotation(&self) -> Option<String> {
+        let tags = (!self.tags.is_empty()).then(|| format!("[{}]", self.tags.join(", ")));
+        let annotation = [self.notes.clone(), tags]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(" ");
+        (!annotation.is_empty()).then_some(annotation)
+    }
+
+    /// The start time of the test in the local time of the client.
+    pub fn start_time(&self) -> Option<chrono::DateTime<chrono::FixedOffset>> {
+        chrono::DateTime::parse_from_rfc3339(&self.start_time).ok()
+    }
+}
+
+/// The window of the original result which a trimmed result keeps.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RawTrim {
+    /// The start of the window, from the start of the original test.
+    pub from: Duration,
+    /// The end of the window, from the start of the original test.
+    pub to: Duration,
+    /// The duration of the original test.
+    pub original_duration: Duration,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RawResult {
     pub version: u64,
@@ -195,6 +309,16 @@ pub struct RawResult {
     pub peer_pings: Option<Vec<RawPing>>, // Added in V2
     #[serde(default)] // Added in V2
     pub test_data: Vec<TestData>,
+    #[serde(default)]
+    pub tls: bool, // Added in V3
+    /// Another client was loading the server at the same time.
+    #[serde(default)]
+    pub concurrent_load: bool, // Added in V3
+    #[serde(default)]
+    pub metadata: Option<RawMetadata>, // Added in V3
+    /// Set if this result was trimmed from a longer result.
+    #[serde(default)]
+    pub trim: Option<RawTrim>, // Added in V3
 }
 
 impl RawResult {
@@ -227,27 +351,224 @@ impl RawResult {
         self.stream_groups.iter().any(|group| group.both)
     }
 
-    pub fn load_from_reader(reader: impl Read) -> Option<Self> {
+    /// Adds notes and tags given by the user to the metadata of the result.
+    pub fn annotate(&mut self, notes: Option<String>, tags: Vec<String>) {
+        if let Some(metadata) = self.metadata.as_mut() {
+            metadata.notes = notes.filter(|notes| !notes.trim().is_empty());
+            metadata.tags = tags;
+        }
+    }
+
+    /// Returns a result with only the data in a window of the test. `from` and `to` are relative
+    /// to the start of the test. Tests are cut to the window and left out if they are outside it.
+    pub fn trim(&self, from: Duration, to: Duration) -> Result<Self, anyhow::Error> {
+        let to = to.min(self.duration);
+        if from >= to {
+            bail!(
+                "The window must end after it starts and start before the end of the test at {:.2} s",
+                self.duration.as_secs_f64()
+            );
+        }
+        let (start, end) = (self.start + from, self.start + to);
+        let inside = |time: Duration| start <= time && time <= end;
+
+        let pings = |pings: &[RawPing]| -> Vec<RawPing> {
+            pings
+                .iter()
+                .filter(|ping| inside(ping.sent))
+                .cloned()
+                .collect()
+        };
+
+        let mut stream_groups: Vec<_> = self
+            .stream_groups
+            .iter()
+            .map(|group| RawStreamGroup {
+                download: group.download,
+                both: group.both,
+                streams: group
+                    .streams
+                    .iter()
+                    .map(|stream| {
+                        // Byte counts are cumulative, so count from the last point before the window
+                        let base = stream
+                            .data
+                            .iter()
+                            .take_while(|point| point.time < start)
+                            .last()
+                            .map(|point| point.bytes)
+                            .unwrap_or(0);
+                        RawStream {
+                            data: stream
+                                .data
+                                .iter()
+                                .filter(|point| inside(point.time))
+                                .map(|point| RawPoint {
+                                    time: point.time,
+                                    bytes: point.bytes.saturating_sub(base),
+                                })
+                                .collect(),
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+        let loaded = |group: &RawStreamGroup| group.streams.iter().any(|s| !s.data.is_empty());
+        // Both groups of the bidirectional test are kept together
+        let both = stream_groups
+            .iter()
+            .any(|group| group.both && loaded(group));
+        stream_groups.retain(|group| if group.both { both } else { loaded(group) });
+
+        let test_data = self
+            .test_data
+            .iter()
+            .filter(|test| test.start < end && start < test.end)
+            .map(|test| TestData {
+                start: test.start.max(start),
+                end: test.end.min(end),
+                kind: test.kind,
+            })
+            .collect();
+
+        // Windows of a trimmed result are relative to the original result
+        let original = self.trim.clone().unwrap_or(RawTrim {
+            from: Duration::ZERO,
+            to: self.duration,
+            original_duration: self.duration,
+        });
+
+        Ok(RawResult {
+            start,
+            duration: to - from,
+            stream_groups,
+            pings: pings(&self.pings),
+            peer_pings: self.peer_pings.as_deref().map(pings),
+            test_data,
+            trim: Some(RawTrim {
+                from: original.from + from,
+                to: original.from + to,
+                original_duration: original.original_duration,
+            }),
+            ..self.clone()
+        })
+    }
+
+    /// Returns a copy of the result which doesn't identify the hosts involved, with measurements
+    /// intact. Addresses and host names are replaced by names derived from a keyed hash of them,
+    /// so results anonymized with the same `key` keep matching names. A random key is used
+    /// if none is given. Build details are removed from versions and the interface name is
+    /// removed. Notes and tags are kept.
+    pub fn anonymize(&self, key: Option<&str>) -> Self {
+        let key = match key {
+            Some(key) => key.as_bytes().to_vec(),
+            None => rand::random::<[u8; 32]>().to_vec(),
+        };
+        let pseudonym = |prefix: &str, value: &str| {
+            let mut mac =
+                Hmac::<Sha256>::new_from_slice(&key).expect("HMAC accepts keys of any length");
+            mac.update(value.as_bytes());
+            let hash = mac.finalize().into_bytes();
+            let hash: String = hash[..4].iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{}-{}", prefix, hash)
+        };
+        // Versions may include the commit of the build, as in `0.2 (commit 1234567)`
+        let release = |version: &str| version.split(" (").next().unwrap_or_default().to_owned();
+
+        RawResult {
+            generated_by: release(&self.generated_by),
+            metadata: self.metadata.as_ref().map(|metadata| RawMetadata {
+                server_address: pseudonym("server", &metadata.server_address),
+                server_hostname: metadata
+                    .server_hostname
+                    .as_ref()
+                    .map(|hostname| pseudonym("host", hostname)),
+                server_version: release(&metadata.server_version),
+                interface: None,
+                local_address: pseudonym("client", &metadata.local_address),
+                ..metadata.clone()
+            }),
+            ..self.clone()
+        }
+    }
+
+    pub fn load_from_reader(reader: impl Read) -> Result<Self, LoadError> {
         let mut file = BufReader::new(reader);
-        let header: RawHeader = bincode::deserialize_from(&mut file).ok()?;
+        // Results exported as JSON are objects
+        if file.fill_buf()?.trim_ascii_start().starts_with(b"{") {
+            return Self::from_json(file);
+        }
+        let header: RawHeader =
+            bincode::deserialize_from(&mut file).map_err(|error| match *error {
+                bincode::ErrorKind::Io(error) if error.kind() != io::ErrorKind::UnexpectedEof => {
+                    LoadError::Io(error)
+                }
+                _ => LoadError::NotCrusaderFile,
+            })?;
         if header.magic != RawHeader::default().magic {
-            return None;
+            return Err(LoadError::NotCrusaderFile);
         }
         match header.version {
             0 => {
-                let result: RawResultV0 = bincode::deserialize_from(file).ok()?;
-                Some(result.to_v1())
+                let result: RawResultV0 = bincode::deserialize_from(file)
+                    .map_err(|error| LoadError::Corrupt(error.to_string()))?;
+                Ok(result.to_v1())
             }
-            1 | 2 => {
+            1..=3 => {
                 let data = snap::read::FrameDecoder::new(file);
-                Some(rmp_serde::decode::from_read(data).ok()?)
+                rmp_serde::decode::from_read(data)
+                    .map_err(|error| LoadError::Corrupt(error.to_string()))
             }
-            _ => None,
+            version => Err(LoadError::UnsupportedVersion(version)),
+        }
+    }
+
+    /// Reads a result exported as JSON and checks that it can be plotted.
+    pub fn from_json(reader: impl Read) -> Result<Self, LoadError> {
+        let value: serde_json::Value =
+            serde_json::from_reader(BufReader::new(reader)).map_err(|error| {
+                if error.is_io() {
+                    LoadError::Io(error.into())
+                } else {
+                    LoadError::Corrupt(error.to_string())
+                }
+            })?;
+        if value.get("schema_version").is_some() {
+            return Err(LoadError::SummaryExport);
         }
+        let result: Self =
+            serde_json::from_value(value).map_err(|error| LoadError::Corrupt(error.to_string()))?;
+        result.validate()?;
+        Ok(result)
     }
 
-    pub fn load(path: &Path) -> Option<Self> {
-        Self::load_from_reader(File::open(path).ok()?)
+    /// Checks invariants which JSON edits may break.
+    fn validate(&self) -> Result<(), LoadError> {
+        let corrupt = |reason: &str| Err(LoadError::Corrupt(reason.to_owned()));
+        if self.version > RawHeader::default().version {
+            return Err(LoadError::UnsupportedVersion(self.version));
+        }
+        if self.config.ping_interval.is_zero() || self.config.bandwidth_interval.is_zero() {
+            return corrupt("The ping and bandwidth intervals must not be zero");
+        }
+        let pings = [Some(&self.pings), self.peer_pings.as_ref()];
+        for pings in pings.into_iter().flatten() {
+            if !pings.is_sorted_by_key(|ping| ping.sent) {
+                return corrupt("Pings must be sorted by their sent time");
+            }
+        }
+        for group in &self.stream_groups {
+            for stream in &group.streams {
+                if !stream.data.is_sorted_by_key(|point| point.time) {
+                    return corrupt("Stream data points must be sorted by time");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, LoadError> {
+        Self::load_from_reader(File::open(path)?)
     }
 
     pub fn save_to_writer(&self, writer: impl Write) -> Result<(), anyhow::Error> {
@@ -267,3 +588,155 @@ impl RawResult {
         self.save_to_writer(File::create(name)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: f64) -> Duration {
+        Duration::from_secs_f64(secs)
+    }
+
+    /// A 10 second result starting 1 second after the client started. The download stream
+    /// has a point each second through the test, the upload stream only in the first 4 seconds.
+    fn result() -> RawResult {
+        let stream = |points: u64| RawStream {
+            data: (0..points)
+                .map(|i| RawPoint {
+                    time: secs(1.5 + i as f64),
+                    bytes: (i + 1) * 1000,
+                })
+                .collect(),
+        };
+        RawResult {
+            version: RawHeader::default().version,
+            generated_by: "test".to_owned(),
+            config: RawConfig {
+                stagger: Duration::ZERO,
+                load_duration: secs(10.0),
+                grace_duration: Duration::ZERO,
+                ping_interval: secs(1.0),
+                bandwidth_interval: secs(1.0),
+            },
+            ipv6: false,
+            load_termination_timeout: false,
+            server_overload: false,
+            server_latency: Duration::ZERO,
+            start: secs(1.0),
+            duration: secs(10.0),
+            stream_groups: vec![
+                RawStreamGroup {
+                    download: true,
+                    both: false,
+                    streams: vec![stream(10)],
+                },
+                RawStreamGroup {
+                    download: false,
+                    both: false,
+                    streams: vec![stream(4)],
+                },
+            ],
+            pings: (0..=10)
+                .map(|i| RawPing {
+                    index: i,
+                    sent: secs(1.0 + i as f64),
+                    latency: None,
+                })
+                .collect(),
+            peer_pings: None,
+            test_data: vec![
+                TestData {
+                    start: secs(1.0),
+                    end: secs(11.0),
+                    kind: TestKind::Download,
+                },
+                TestData {
+                    start: secs(1.0),
+                    end: secs(5.0),
+                    kind: TestKind::Upload,
+                },
+            ],
+            tls: false,
+            concurrent_load: false,
+            metadata: None,
+            trim: None,
+        }
+    }
+
+    fn sent(result: &RawResult) -> Vec<u64> {
+        result.pings.iter().map(|ping| ping.index).collect()
+    }
+
+    fn bytes(stream: &RawStream) -> Vec<u64> {
+        stream.data.iter().map(|point| point.bytes).collect()
+    }
+
+    #[test]
+    fn trim_includes_window_edges() {
+        let trimmed = result().trim(secs(2.0), secs(4.0)).unwrap();
+        assert_eq!(trimmed.start, secs(3.0));
+        assert_eq!(trimmed.duration, secs(2.0));
+        assert_eq!(sent(&trimmed), [2, 3, 4]);
+
+        let trim = trimmed.trim.unwrap();
+        assert_eq!(trim.from, secs(2.0));
+        assert_eq!(trim.to, secs(4.0));
+        assert_eq!(trim.original_duration, secs(10.0));
+    }
+
+    #[test]
+    fn trim_clamps_to_test() {
+        let trimmed = result().trim(secs(8.0), secs(20.0)).unwrap();
+        assert_eq!(trimmed.duration, secs(2.0));
+        assert_eq!(sent(&trimmed), [8, 9, 10]);
+
+        assert!(result().trim(secs(10.0), secs(12.0)).is_err());
+        assert!(result().trim(secs(5.0), secs(5.0)).is_err());
+        assert!(result().trim(secs(6.0), secs(5.0)).is_err());
+    }
+
+    #[test]
+    fn trim_rebases_byte_counts() {
+        let trimmed = result().trim(secs(2.0), secs(4.0)).unwrap();
+        assert_eq!(bytes(&trimmed.stream_groups[0].streams[0]), [1000, 2000]);
+        assert_eq!(bytes(&trimmed.stream_groups[1].streams[0]), [1000, 2000]);
+
+        // Windows starting before the first point count from zero
+        let trimmed = result().trim(Duration::ZERO, secs(2.0)).unwrap();
+        assert_eq!(bytes(&trimmed.stream_groups[0].streams[0]), [1000, 2000]);
+    }
+
+    #[test]
+    fn trim_drops_tests_outside_window() {
+        let trimmed = result().trim(secs(5.0), secs(8.0)).unwrap();
+        assert_eq!(trimmed.stream_groups.len(), 1);
+        assert!(trimmed.stream_groups[0].download);
+        assert_eq!(trimmed.test_data.len(), 1);
+        assert_eq!(trimmed.test_data[0].kind, TestKind::Download);
+        assert_eq!(trimmed.test_data[0].start, secs(6.0));
+        assert_eq!(trimmed.test_data[0].end, secs(9.0));
+    }
+
+    #[test]
+    fn trim_twice_is_relative_to_original() {
+        let twice = result()
+            .trim(secs(2.0), secs(8.0))
+            .unwrap()
+            .trim(secs(1.0), secs(3.0))
+            .unwrap();
+        let once = result().trim(secs(3.0), secs(5.0)).unwrap();
+
+        assert_eq!(twice.start, once.start);
+        assert_eq!(twice.duration, once.duration);
+        assert_eq!(sent(&twice), sent(&once));
+        assert_eq!(
+            bytes(&twice.stream_groups[0].streams[0]),
+            bytes(&once.stream_groups[0].streams[0])
+        );
+
+        let trim = twice.trim.unwrap();
+        assert_eq!(trim.from, secs(3.0));
+        assert_eq!(trim.to, secs(5.0));
+        assert_eq!(trim.original_duration, secs(10.0));
+    }
+}
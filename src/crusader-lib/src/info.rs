@@ -0,0 +1,610 @@
+//! Descriptions and consistency checks of result files.
+
+use serde::Serialize;
+use std::fmt;
+use std::time::Duration;
+
+use crate::file_format::{RawMetadata, RawPing, RawResult, RawStreamGroup, TestKind};
+use crate::plot::{LatencyLossSummary, TestResult};
+
+/// The durations of the test configuration in seconds.
+#[derive(Serialize)]
+pub struct ConfigInfo {
+    pub stagger: f64,
+    pub load_duration: f64,
+    pub grace_duration: f64,
+    pub ping_interval: f64,
+    pub throughput_interval: f64,
+}
+
+/// A load of the test, in seconds from the start of the test.
+#[derive(Serialize)]
+pub struct PhaseInfo {
+    pub kind: TestKind,
+    pub start: f64,
+    pub end: f64,
+}
+
+/// The window of the original test kept by a trimmed result, in seconds.
+#[derive(Serialize)]
+pub struct TrimInfo {
+    pub from: f64,
+    pub to: f64,
+    pub original_duration: f64,
+}
+
+#[derive(Serialize)]
+pub struct StreamGroupInfo {
+    pub name: String,
+    pub streams: usize,
+    /// The number of throughput samples of all the streams.
+    pub samples: usize,
+}
+
+/// Latencies in milliseconds.
+#[derive(Serialize, Clone, Copy)]
+pub struct LatencyInfo {
+    pub total: f64,
+    pub down: f64,
+    pub up: f64,
+}
+
+/// The fraction of lost pings in each direction.
+#[derive(Serialize, Clone, Copy)]
+pub struct LossInfo {
+    pub down: f64,
+    pub up: f64,
+}
+
+/// The summary of a test or of the idle latency, as shown by [`TestResult::summary`].
+/// Throughputs are in Mbps.
+#[derive(Serialize, Clone)]
+pub struct PhaseSummary {
+    /// `None` for the idle latency measurement.
+    pub kind: Option<TestKind>,
+    pub throughput: Option<f64>,
+    pub throughput_down: Option<f64>,
+    pub throughput_up: Option<f64>,
+    pub latency: Option<LatencyInfo>,
+    /// The median round-trip latency in milliseconds.
+    pub median_latency: Option<f64>,
+    pub loss: Option<LossInfo>,
+    pub peer_latency: Option<LatencyInfo>,
+    pub peer_median_latency: Option<f64>,
+    pub peer_loss: Option<LossInfo>,
+}
+
+impl PhaseSummary {
+    pub fn name(&self) -> &'static str {
+        self.kind.map(|kind| kind.name()).unwrap_or("Idle")
+    }
+}
+
+/// The median round-trip latency in milliseconds of the pings sent while a test loaded the network,
+/// or during the whole result for `None`.
+fn median_latency(result: &RawResult, kind: Option<TestKind>, pings: &[RawPing]) -> Option<f64> {
+    let (start, end) = match kind {
+        Some(kind) => {
+            let test = result.test_data.iter().find(|test| test.kind == kind)?;
+            (
+                test.start,
+                test.end.min(test.start + result.config.load_duration),
+            )
+        }
+        None => (result.start, result.start + result.duration),
+    };
+    let mut latencies: Vec<_> = pings
+        .iter()
+        .filter(|ping| start <= ping.sent && ping.sent <= end)
+        .filter_map(|ping| ping.latency?.total)
+        .collect();
+    latencies.sort_unstable();
+    let middle = latencies.len() / 2;
+    let median = if latencies.len() % 2 == 0 {
+        (*latencies.get(middle.checked_sub(1)?)? + latencies[middle]) / 2
+    } else {
+        latencies[middle]
+    };
+    Some(median.as_secs_f64() * 1000.0)
+}
+
+/// Returns the summaries of each test of a result in the order they were run,
+/// followed by the idle latency.
+pub fn summaries(result: &TestResult) -> Vec<PhaseSummary> {
+    let raw = &result.raw_result;
+    let kinds = [
+        (raw.download(), Some(TestKind::Download)),
+        (raw.upload(), Some(TestKind::Upload)),
+        (raw.both(), Some(TestKind::Bidirectional)),
+        (raw.idle(), None),
+    ];
+
+    let latency = |summary: &LatencyLossSummary, kind| {
+        summary.latencies.get(&kind).map(|latency| LatencyInfo {
+            total: latency.total.as_secs_f64() * 1000.0,
+            down: latency.down.as_secs_f64() * 1000.0,
+            up: latency.up.as_secs_f64() * 1000.0,
+        })
+    };
+    let loss = |summary: &LatencyLossSummary, kind| {
+        summary
+            .loss
+            .get(&kind)
+            .map(|&(down, up)| LossInfo { down, up })
+    };
+
+    kinds
+        .into_iter()
+        .filter(|(present, _)| *present)
+        .map(|(_, kind)| {
+            let throughput =
+                |sub| kind.and_then(|kind| result.throughputs.get(&(kind, sub)).copied());
+            let both = kind == Some(TestKind::Bidirectional);
+            PhaseSummary {
+                kind,
+                throughput: kind.and_then(throughput),
+                throughput_down: both.then(|| throughput(TestKind::Download)).flatten(),
+                throughput_up: both.then(|| throughput(TestKind::Upload)).flatten(),
+                latency: latency(&result.latencies, kind),
+                median_latency: median_latency(raw, kind, &result.pings),
+                loss: loss(&result.latencies, kind),
+                peer_latency: latency(&result.peer_latencies, kind),
+                peer_median_latency: raw
+                    .peer_pings
+                    .as_ref()
+                    .and_then(|pings| median_latency(raw, kind, pings)),
+                peer_loss: loss(&result.peer_latencies, kind),
+            }
+        })
+        .collect()
+}
+
+/// A description of a result file.
+#[derive(Serialize)]
+pub struct ResultInfo {
+    pub version: u64,
+    pub generated_by: String,
+    pub config: ConfigInfo,
+    pub ipv6: bool,
+    pub tls: bool,
+    pub phases: Vec<PhaseInfo>,
+    pub stream_groups: Vec<StreamGroupInfo>,
+    pub pings: usize,
+    /// The number of pings of the latency peer, if one was used.
+    pub peer_pings: Option<usize>,
+    pub server_overload: bool,
+    pub load_termination_timeout: bool,
+    pub concurrent_load: bool,
+    pub metadata: Option<RawMetadata>,
+    pub trim: Option<TrimInfo>,
+    pub summary: Vec<PhaseSummary>,
+    /// Problems found by the consistency checks.
+    pub issues: Vec<String>,
+}
+
+impl ResultInfo {
+    pub fn new(result: &RawResult) -> Self {
+        let seconds = |time: Duration| time.as_secs_f64() - result.start.as_secs_f64();
+        let config = &result.config;
+
+        // The summaries can't be computed from results with structural problems
+        let mut issues = check_structure(result);
+        let summary = if issues.is_empty() {
+            summaries(&result.to_test_result())
+        } else {
+            Vec::new()
+        };
+        issues.extend(check_data(result));
+
+        ResultInfo {
+            version: result.version,
+            generated_by: result.generated_by.clone(),
+            config: ConfigInfo {
+                stagger: config.stagger.as_secs_f64(),
+                load_duration: config.load_duration.as_secs_f64(),
+                grace_duration: config.grace_duration.as_secs_f64(),
+                ping_interval: config.ping_interval.as_secs_f64(),
+                throughput_interval: config.bandwidth_interval.as_secs_f64(),
+            },
+            ipv6: result.ipv6,
+            tls: result.tls,
+            phases: result
+                .test_data
+                .iter()
+                .map(|test| PhaseInfo {
+                    kind: test.kind,
+                    start: seconds(test.start),
+                    end: seconds(test.end),
+                })
+                .collect(),
+            stream_groups: result
+                .stream_groups
+                .iter()
+                .map(|group| StreamGroupInfo {
+                    name: group_name(group),
+                    streams: group.streams.len(),
+                    samples: group.streams.iter().map(|stream| stream.data.len()).sum(),
+                })
+                .collect(),
+            pings: result.pings.len(),
+            peer_pings: result.peer_pings.as_ref().map(|pings| pings.len()),
+            server_overload: result.server_overload,
+            load_termination_timeout: result.load_termination_timeout,
+            concurrent_load: result.concurrent_load,
+            metadata: result.metadata.clone(),
+            trim: result.trim.as_ref().map(|trim| TrimInfo {
+                from: trim.from.as_secs_f64(),
+                to: trim.to.as_secs_f64(),
+                original_duration: trim.original_duration.as_secs_f64(),
+            }),
+            summary,
+            issues,
+        }
+    }
+}
+
+fn group_name(group: &RawStreamGroup) -> String {
+    match (group.both, group.download) {
+        (false, true) => TestKind::Download.name().to_owned(),
+        (false, false) => TestKind::Upload.name().to_owned(),
+        (true, true) => format!("{} download", TestKind::Bidirectional.name()),
+        (true, false) => format!("{} upload", TestKind::Bidirectional.name()),
+    }
+}
+
+fn check_ping_order(name: &str, pings: &[RawPing], issues: &mut Vec<String>) {
+    if !pings.is_sorted_by_key(|ping| ping.sent) {
+        issues.push(format!("{} are not sorted by their sent time", name));
+    }
+    if !pings.windows(2).all(|pair| pair[0].index < pair[1].index) {
+        issues.push(format!("{} have indices which are not increasing", name));
+    }
+}
+
+fn check_pings(
+    name: &str,
+    pings: &[RawPing],
+    end: Duration,
+    tolerance: Duration,
+    issues: &mut Vec<String>,
+) {
+    let late = pings.iter().filter(|ping| ping.sent > end).count();
+    if late > 0 {
+        issues.push(format!(
+            "{} include {} sent after the end of the test",
+            name, late
+        ));
+    }
+    let inverted = pings
+        .iter()
+        .filter_map(|ping| ping.latency)
+        .filter(|latency| {
+            latency
+                .total
+                .is_some_and(|total| latency.up > total + tolerance)
+        })
+        .count();
+    if inverted > 0 {
+        issues.push(format!(
+            "{} include {} with an upload latency above their total latency",
+            name, inverted
+        ));
+    }
+}
+
+/// Checks a result for data which a working client could not have produced.
+pub fn check(result: &RawResult) -> Vec<String> {
+    let mut issues = check_structure(result);
+    issues.extend(check_data(result));
+    issues
+}
+
+/// Checks the ordering and intervals which the summaries rely on.
+fn check_structure(result: &RawResult) -> Vec<String> {
+    let mut issues = Vec::new();
+
+    if result.config.ping_interval.is_zero() {
+        issues.push("The latency sample interval is zero".to_owned());
+    }
+    if result.config.bandwidth_interval.is_zero() {
+        issues.push("The throughput sample interval is zero".to_owned());
+    }
+
+    check_ping_order("Pings", &result.pings, &mut issues);
+    if let Some(pings) = &result.peer_pings {
+        check_ping_order("Peer pings", pings, &mut issues);
+    }
+
+    for (i, test) in result.test_data.iter().enumerate() {
+        let name = test.kind.name();
+        if test.start > test.end {
+            issues.push(format!("The {} test ends before it starts", name));
+        }
+        if let Some(previous) = i.checked_sub(1).map(|i| &result.test_data[i]) {
+            if test.start < previous.end {
+                issues.push(format!(
+                    "The {} test overlaps the {} test",
+                    name,
+                    previous.kind.name()
+                ));
+            }
+        }
+    }
+
+    for group in &result.stream_groups {
+        let name = group_name(group);
+        if group.streams.len() as u64 != result.streams() {
+            issues.push(format!(
+                "The {} group has {} streams instead of {}",
+                name,
+                group.streams.len(),
+                result.streams()
+            ));
+        }
+        for (i, stream) in group.streams.iter().enumerate() {
+            let stream_name = format!("Stream {} of the {} group", i + 1, name);
+            if !stream.data.is_sorted_by_key(|point| point.time) {
+                issues.push(format!("{} has times which are not monotonic", stream_name));
+            }
+            if !stream.data.is_sorted_by_key(|point| point.bytes) {
+                issues.push(format!("{} has decreasing byte counts", stream_name));
+            }
+        }
+    }
+
+    issues
+}
+
+/// Checks for samples and test phases which don't fit the rest of the result.
+fn check_data(result: &RawResult) -> Vec<String> {
+    let mut issues = Vec::new();
+    let end = result.start + result.duration;
+
+    // The upload latency depends on the clock synchronization with the server,
+    // which is only as accurate as the round trip time measured for it
+    check_pings(
+        "Pings",
+        &result.pings,
+        end,
+        result.server_latency,
+        &mut issues,
+    );
+    if let Some(pings) = &result.peer_pings {
+        check_pings("Peer pings", pings, end, result.server_latency, &mut issues);
+    }
+
+    for test in &result.test_data {
+        if test.start < result.start || test.end > end {
+            issues.push(format!(
+                "The {} test is outside the test window",
+                test.kind.name()
+            ));
+        }
+    }
+
+    for group in &result.stream_groups {
+        for (i, stream) in group.streams.iter().enumerate() {
+            if stream.data.iter().any(|point| point.time > end) {
+                issues.push(format!(
+                    "Stream {} of the {} group has samples after the end of the test",
+                    i + 1,
+                    group_name(group)
+                ));
+            }
+        }
+    }
+
+    // Test phases were added during version 2, so only later versions always have them
+    if result.test_data.is_empty() && !result.stream_groups.is_empty() && result.version >= 3 {
+        issues.push("The result has loads but no test phases".to_owned());
+    }
+
+    issues
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+impl fmt::Display for LatencyInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.01} ms ({:.01} ms down, {:.01} ms up)",
+            self.total, self.down, self.up
+        )
+    }
+}
+
+impl fmt::Display for LossInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.02}% down, {:.02}% up",
+            self.down * 100.0,
+            self.up * 100.0
+        )
+    }
+}
+
+impl fmt::Display for ResultInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = 30;
+        let field = |f: &mut fmt::Formatter<'_>, name: &str, value: &dyn fmt::Display| {
+            writeln!(f, "{:>width$}: {}", name, value, width = width)
+        };
+
+        field(
+            f,
+            "File version",
+            &format!("{} ({})", self.version, self.generated_by),
+        )?;
+        if let Some(metadata) = &self.metadata {
+            field(
+                f,
+                "Server",
+                &format!(
+                    "{} port {} ({})",
+                    metadata.server_name(),
+                    metadata.server_port,
+                    metadata.server_version
+                ),
+            )?;
+            field(f, "Started", &metadata.start_time)?;
+            field(f, "Client", &metadata.client_os)?;
+            if let Some(annotation) = metadata.annotation() {
+                field(f, "Notes", &annotation)?;
+            }
+        }
+        field(f, "IPv6", &yes_no(self.ipv6))?;
+        field(f, "TLS", &yes_no(self.tls))?;
+        if let Some(trim) = &self.trim {
+            field(
+                f,
+                "Trimmed to",
+                &format!(
+                    "{:.02} s to {:.02} s of {:.02} s",
+                    trim.from, trim.to, trim.original_duration
+                ),
+            )?;
+        }
+
+        writeln!(f, "-- Config --")?;
+        let config = &self.config;
+        field(
+            f,
+            "Load duration",
+            &format!("{:.02} s", config.load_duration),
+        )?;
+        field(
+            f,
+            "Grace duration",
+            &format!("{:.02} s", config.grace_duration),
+        )?;
+        field(f, "Stream stagger", &format!("{:.02} s", config.stagger))?;
+        field(
+            f,
+            "Latency sample interval",
+            &format!("{:.02} ms", config.ping_interval * 1000.0),
+        )?;
+        field(
+            f,
+            "Throughput sample interval",
+            &format!("{:.02} ms", config.throughput_interval * 1000.0),
+        )?;
+
+        writeln!(f, "-- Data --")?;
+        for phase in &self.phases {
+            field(
+                f,
+                &format!("{} test", phase.kind.name()),
+                &format!("{:.02} s to {:.02} s", phase.start, phase.end),
+            )?;
+        }
+        for group in &self.stream_groups {
+            field(
+                f,
+                &format!("{} streams", group.name),
+                &format!("{} ({} samples)", group.streams, group.samples),
+            )?;
+        }
+        field(f, "Latency samples", &self.pings)?;
+        field(
+            f,
+            "Peer latency samples",
+            &self
+                .peer_pings
+                .map(|pings| pings.to_string())
+                .unwrap_or("No latency peer".to_owned()),
+        )?;
+        field(f, "Server overload", &yes_no(self.server_overload))?;
+        field(
+            f,
+            "Load termination timeout",
+            &yes_no(self.load_termination_timeout),
+        )?;
+        field(f, "Concurrent load", &yes_no(self.concurrent_load))?;
+
+        for summary in &self.summary {
+            writeln!(f, "-- {} test --", summary.name())?;
+            if let Some(throughput) = summary.throughput {
+                match (summary.throughput_down, summary.throughput_up) {
+                    (Some(down), Some(up)) => field(
+                        f,
+                        "Throughput",
+                        &format!(
+                            "{:.02} Mbps ({:.02} Mbps down, {:.02} Mbps up)",
+                            throughput, down, up
+                        ),
+                    )?,
+                    _ => field(f, "Throughput", &format!("{:.02} Mbps", throughput))?,
+                }
+            }
+            if let Some(latency) = &summary.latency {
+                field(f, "Latency", latency)?;
+            }
+            if let Some(median) = summary.median_latency {
+                field(f, "Median latency", &format!("{:.01} ms", median))?;
+            }
+            if let Some(loss) = &summary.loss {
+                field(f, "Packet loss", loss)?;
+            }
+            if let Some(latency) = &summary.peer_latency {
+                field(f, "Peer latency", latency)?;
+            }
+            if let Some(median) = summary.peer_median_latency {
+                field(f, "Peer median latency", &format!("{:.01} ms", median))?;
+            }
+            if let Some(loss) = &summary.peer_loss {
+                field(f, "Peer packet loss", loss)?;
+            }
+        }
+
+        writeln!(f, "-- Checks --")?;
+        if self.issues.is_empty() {
+            writeln!(f, "No issues found")?;
+        }
+        for issue in &self.issues {
+            writeln!(f, "Warning: {}", issue)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn load(name: &str) -> RawResult {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../data")
+            .join(name);
+        RawResult::load(&path).unwrap()
+    }
+
+    #[test]
+    fn genuine_results() {
+        for name in ["v0.crr", "v1.crr", "v2.crr"] {
+            let info = ResultInfo::new(&load(name));
+            assert_eq!(info.issues, Vec::<String>::new(), "{}", name);
+            assert!(!info.summary.is_empty(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn structural_issues_skip_summaries() {
+        let mut result = load("v2.crr");
+        result.pings.reverse();
+        let info = ResultInfo::new(&result);
+        assert!(info.summary.is_empty());
+        assert!(info
+            .issues
+            .contains(&"Pings are not sorted by their sent time".to_owned()));
+    }
+}
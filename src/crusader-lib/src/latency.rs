@@ -21,9 +21,11 @@ use tokio::{
 };
 use tokio_util::codec::{FramedRead, FramedWrite};
 
-use crate::common::{connect, hello, measure_latency, udp_handle};
+use crate::auth::Secret;
+use crate::common::{connect, fresh_socket_addr, hello, measure_latency, udp_handle};
 use crate::discovery;
-use crate::protocol::{codec, receive, send, ClientMessage, Ping, ServerMessage};
+use crate::protocol::{codec, receive, send, ClientMessage, Ping, ServerMessage, Session};
+use crate::tls::{self, Verification};
 
 type UpdateFn = Arc<dyn Fn() + Send + Sync>;
 
@@ -31,6 +33,12 @@ type UpdateFn = Arc<dyn Fn() + Send + Sync>;
 pub struct Config {
     pub port: u16,
     pub ping_interval: Duration,
+    pub secret: Option<Secret>,
+    pub tls: Option<Verification>,
+    /// Use the local server with the lowest latency instead of the first one to reply.
+    pub lowest_latency: bool,
+    /// The UDP port local servers receive discovery requests on.
+    pub discovery_port: u16,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -97,33 +105,47 @@ async fn test_async(
             server.to_owned(),
         )
     } else {
-        let server = discovery::locate(false).await?;
+        let server = discovery::locate(false, config.lowest_latency, config.discovery_port).await?;
         (connect(server.socket, "server").await?, server.at)
     };
 
     control.set_nodelay(true)?;
 
+    let tls = config
+        .tls
+        .map(|verification| tls::Connector::new(verification, server, control.peer_addr()?.ip()))
+        .transpose()?;
+
     let server = control.peer_addr()?;
 
+    let control = tls::connect(control, tls.as_ref()).await?;
+
     *data.state.lock() = State::Syncing;
     (data.update_fn)();
 
-    let (rx, tx) = control.into_split();
+    let (rx, tx) = tokio::io::split(control);
     let mut control_rx = FramedRead::new(rx, codec());
     let mut control_tx = FramedWrite::new(tx, codec());
 
-    hello(&mut control_tx, &mut control_rx).await?;
+    hello(&mut control_tx, &mut control_rx, config.secret).await?;
 
     send(&mut control_tx, &ClientMessage::NewClient).await?;
 
     let setup_start = data.start;
 
     let reply: ServerMessage = receive(&mut control_rx).await?;
-    let id = match reply {
-        ServerMessage::NewClient(Some(id)) => id,
-        ServerMessage::NewClient(None) => bail!("Server was unable to create client"),
+    let (session, udp_port) = match reply {
+        ServerMessage::NewClient {
+            session: Some(session),
+            udp_port,
+        } => (session, udp_port),
+        ServerMessage::NewClient { session: None, .. } => {
+            bail!("Server was unable to create client")
+        }
+        ServerMessage::ShuttingDown => bail!("Server is shutting down"),
         _ => bail!("Unexpected message {:?}", reply),
     };
+    let ping_server = fresh_socket_addr(server, udp_port);
 
     let local_udp = if server.is_ipv6() {
         SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
@@ -134,11 +156,11 @@ async fn test_async(
     let mut ping_index = 0;
 
     let (latency, mut server_time_offset, mut control_rx) = measure_latency(
-        id,
+        session,
         &mut ping_index,
         &mut control_tx,
         control_rx,
-        server,
+        ping_server,
         local_udp,
         setup_start,
     )
@@ -158,7 +180,7 @@ async fn test_async(
         .collect();
 
     let udp_socket = Arc::new(net::UdpSocket::bind(local_udp).await?);
-    udp_socket.connect(server).await?;
+    udp_socket.connect(ping_server).await?;
     let udp_socket2 = udp_socket.clone();
 
     let ping_interval = config.ping_interval;
@@ -186,10 +208,12 @@ async fn test_async(
                             .await?;
                     }
                 }
-                ServerMessage::MeasurementsDone { overload } => {
+                ServerMessage::MeasurementsDone { overload, .. } => {
                     overload_ = overload;
                     break;
                 }
+                // The server closes the connection when it stops
+                ServerMessage::ShuttingDown => (),
                 _ => bail!("Unexpected message {:?}", reply),
             };
         }
@@ -211,7 +235,7 @@ async fn test_async(
     let ping_send = tokio::spawn(ping_send(
         event_tx.clone(),
         ping_index,
-        id,
+        session,
         setup_start,
         udp_socket2.clone(),
         ping_interval,
@@ -324,7 +348,7 @@ async fn test_async(
 async fn ping_send(
     event_tx: Sender<Event>,
     mut ping_index: u64,
-    id: u64,
+    session: Session,
     setup_start: Instant,
     socket: Arc<UdpSocket>,
     interval: Duration,
@@ -339,7 +363,8 @@ async fn ping_send(
         let current = setup_start.elapsed();
 
         let ping = Ping {
-            id,
+            id: session.id,
+            token: session.token,
             index: ping_index,
         };
 
@@ -25,14 +25,26 @@ pub fn with_time(msg: &str) -> String {
     format!("[{}] {}", time, msg)
 }
 
+pub mod access;
+pub mod admin;
+pub mod auth;
 mod common;
-mod discovery;
+#[cfg(feature = "client")]
+pub mod compare;
+pub mod discovery;
+mod event;
+#[cfg(feature = "client")]
+pub mod export;
 #[cfg(feature = "client")]
 pub use common::Config;
 #[cfg(feature = "client")]
 pub mod file_format;
 #[cfg(feature = "client")]
+pub mod info;
+#[cfg(feature = "client")]
 pub mod latency;
+mod mdns;
+mod metrics;
 mod peer;
 #[cfg(feature = "client")]
 pub mod plot;
@@ -41,4 +53,8 @@ pub mod protocol;
 pub mod remote;
 pub mod serve;
 #[cfg(feature = "client")]
+pub mod stats;
+pub mod systemd;
+#[cfg(feature = "client")]
 pub mod test;
+pub mod tls;
@@ -0,0 +1,581 @@
+//! A minimal DNS-SD service advertised and browsed over multicast DNS (RFC 6762 and RFC 6763).
+
+use anyhow::{bail, Context};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub(crate) const PORT: u16 = 5353;
+pub(crate) const IPV4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub(crate) const IPV6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+/// The DNS-SD service type, `_crusader._tcp.local`.
+const SERVICE: [&str; 3] = ["_crusader", "_tcp", "local"];
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// The top bit of the class is the cache flush bit in records
+/// and the unicast response bit in questions.
+const CLASS_FLAG: u16 = 0x8000;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+const SERVICE_TTL: u32 = 4500;
+const HOST_TTL: u32 = 120;
+/// The maximum TTL of responses to legacy unicast queries.
+const LEGACY_TTL: u32 = 10;
+
+/// A service instance advertised over DNS-SD.
+#[derive(Debug, Clone)]
+pub(crate) struct Service {
+    pub instance: String,
+    /// The host label of the SRV target, without `.local`.
+    pub host: String,
+    pub port: u16,
+    pub txt: Vec<(String, String)>,
+    pub addresses: Vec<IpAddr>,
+}
+
+impl Service {
+    #[cfg(feature = "client")]
+    pub fn txt(&self, key: &str) -> Option<&str> {
+        self.txt
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| &**v)
+    }
+}
+
+/// Returns a valid DNS label for `name`, truncated to 63 bytes.
+pub(crate) fn label(name: &str) -> String {
+    let mut label = name.to_owned();
+    while label.len() > 63 {
+        label.pop();
+    }
+    label
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn name(&mut self, labels: &[&str]) {
+        for label in labels {
+            self.0.push(label.len() as u8);
+            self.0.extend_from_slice(label.as_bytes());
+        }
+        self.0.push(0);
+    }
+
+    fn record(
+        &mut self,
+        name: &[&str],
+        kind: u16,
+        flush: bool,
+        ttl: u32,
+        rdata: impl FnOnce(&mut Writer),
+    ) {
+        self.name(name);
+        self.u16(kind);
+        self.u16(if flush {
+            CLASS_IN | CLASS_FLAG
+        } else {
+            CLASS_IN
+        });
+        self.u32(ttl);
+        let start = self.0.len();
+        self.u16(0);
+        rdata(self);
+        let len = (self.0.len() - start - 2) as u16;
+        self.0[start..(start + 2)].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(packet: &'a [u8]) -> Self {
+        Reader { packet, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], anyhow::Error> {
+        let bytes = self
+            .packet
+            .get(self.pos..(self.pos + len))
+            .context("Truncated packet")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    #[cfg(feature = "client")]
+    fn u8(&mut self) -> Result<u8, anyhow::Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, anyhow::Error> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    #[cfg(feature = "client")]
+    fn u32(&mut self) -> Result<u32, anyhow::Error> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    /// Reads a possibly compressed name.
+    fn name(&mut self) -> Result<Vec<String>, anyhow::Error> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut end = None;
+        for _ in 0..64 {
+            let len = *self.packet.get(pos).context("Truncated name")? as usize;
+            match len {
+                0 => {
+                    self.pos = end.unwrap_or(pos + 1);
+                    return Ok(labels);
+                }
+                0xc0.. => {
+                    let low = *self.packet.get(pos + 1).context("Truncated name")? as usize;
+                    end.get_or_insert(pos + 2);
+                    pos = ((len & 0x3f) << 8) | low;
+                }
+                0x40.. => bail!("Invalid label"),
+                _ => {
+                    let label = self
+                        .packet
+                        .get((pos + 1)..(pos + 1 + len))
+                        .context("Truncated name")?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + len;
+                }
+            }
+        }
+        bail!("Name too long")
+    }
+}
+
+fn name_eq(name: &[String], other: &[&str]) -> bool {
+    name.len() == other.len()
+        && name
+            .iter()
+            .zip(other)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+struct Header {
+    id: u16,
+    flags: u16,
+    questions: u16,
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    records: u32,
+}
+
+fn header(reader: &mut Reader) -> Result<Header, anyhow::Error> {
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    let questions = reader.u16()?;
+    let records = reader.u16()? as u32 + reader.u16()? as u32 + reader.u16()? as u32;
+    Ok(Header {
+        id,
+        flags,
+        questions,
+        records,
+    })
+}
+
+/// Returns a query for all Crusader services.
+#[cfg(feature = "client")]
+pub(crate) fn query() -> Vec<u8> {
+    let mut writer = Writer(Vec::new());
+    writer.u16(0);
+    writer.u16(0);
+    writer.u16(1);
+    writer.u16(0);
+    writer.u16(0);
+    writer.u16(0);
+    writer.name(&SERVICE);
+    writer.u16(TYPE_PTR);
+    writer.u16(CLASS_IN);
+    writer.0
+}
+
+/// A query asking for Crusader services.
+pub(crate) struct Query {
+    id: u16,
+    /// A response was requested to be sent directly to the querier.
+    pub unicast: bool,
+}
+
+/// Parses a query. Returns `None` if it's a response or doesn't ask for Crusader services.
+pub(crate) fn parse_query(packet: &[u8]) -> Result<Option<Query>, anyhow::Error> {
+    let mut reader = Reader::new(packet);
+    let header = header(&mut reader)?;
+    if header.flags & FLAG_RESPONSE != 0 {
+        return Ok(None);
+    }
+
+    let mut found = false;
+    let mut unicast = false;
+    for _ in 0..header.questions {
+        let name = reader.name()?;
+        let kind = reader.u16()?;
+        let class = reader.u16()?;
+        if name_eq(&name, &SERVICE)
+            && (kind == TYPE_PTR || kind == TYPE_ANY)
+            && (class & !CLASS_FLAG) == CLASS_IN
+        {
+            found = true;
+            unicast |= class & CLASS_FLAG != 0;
+        }
+    }
+
+    Ok(found.then_some(Query {
+        id: header.id,
+        unicast,
+    }))
+}
+
+/// Returns a response advertising `service`. A `legacy` query is one sent from a port
+/// other than 5353, which must be answered directly with its question repeated.
+pub(crate) fn response(service: &Service, legacy: Option<&Query>) -> Vec<u8> {
+    let instance: Vec<&str> = [&*service.instance]
+        .into_iter()
+        .chain(SERVICE.iter().copied())
+        .collect();
+    let host = [&*service.host, "local"];
+    let ttl = |ttl: u32| match legacy {
+        Some(_) => ttl.min(LEGACY_TTL),
+        None => ttl,
+    };
+    // The cache flush bit must not be set in legacy unicast responses
+    let flush = legacy.is_none();
+
+    let mut writer = Writer(Vec::new());
+    writer.u16(legacy.map(|query| query.id).unwrap_or(0));
+    writer.u16(FLAG_RESPONSE | FLAG_AUTHORITATIVE);
+    writer.u16(legacy.is_some() as u16);
+    writer.u16(1);
+    writer.u16(0);
+    writer.u16(2 + service.addresses.len() as u16);
+
+    if legacy.is_some() {
+        writer.name(&SERVICE);
+        writer.u16(TYPE_PTR);
+        writer.u16(CLASS_IN);
+    }
+
+    writer.record(&SERVICE, TYPE_PTR, false, ttl(SERVICE_TTL), |writer| {
+        writer.name(&instance)
+    });
+    writer.record(&instance, TYPE_SRV, flush, ttl(HOST_TTL), |writer| {
+        writer.u16(0);
+        writer.u16(0);
+        writer.u16(service.port);
+        writer.name(&host);
+    });
+    writer.record(&instance, TYPE_TXT, flush, ttl(SERVICE_TTL), |writer| {
+        for (key, value) in &service.txt {
+            let entry = format!("{key}={value}");
+            let entry = &entry.as_bytes()[..entry.len().min(255)];
+            writer.0.push(entry.len() as u8);
+            writer.0.extend_from_slice(entry);
+        }
+    });
+    for address in &service.addresses {
+        match address {
+            IpAddr::V4(ip) => writer.record(&host, TYPE_A, flush, ttl(HOST_TTL), |writer| {
+                writer.0.extend_from_slice(&ip.octets())
+            }),
+            IpAddr::V6(ip) => writer.record(&host, TYPE_AAAA, flush, ttl(HOST_TTL), |writer| {
+                writer.0.extend_from_slice(&ip.octets())
+            }),
+        }
+    }
+
+    writer.0
+}
+
+/// Returns the Crusader services found in a response.
+#[cfg(feature = "client")]
+pub(crate) fn parse_response(packet: &[u8]) -> Result<Vec<Service>, anyhow::Error> {
+    enum Data {
+        Srv { port: u16, target: Vec<String> },
+        Txt(Vec<(String, String)>),
+        Ip(IpAddr),
+        Other,
+    }
+
+    let mut reader = Reader::new(packet);
+    let header = header(&mut reader)?;
+    if header.flags & FLAG_RESPONSE == 0 {
+        bail!("Not a response");
+    }
+
+    for _ in 0..header.questions {
+        reader.name()?;
+        reader.bytes(4)?;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..header.records {
+        let name = reader.name()?;
+        let kind = reader.u16()?;
+        reader.u16()?;
+        reader.u32()?;
+        let len = reader.u16()? as usize;
+        let end = reader.pos + len;
+        let data = match kind {
+            TYPE_SRV => {
+                reader.bytes(4)?;
+                let port = reader.u16()?;
+                let target = reader.name()?;
+                Data::Srv { port, target }
+            }
+            TYPE_TXT => {
+                let mut entries = Vec::new();
+                while reader.pos < end {
+                    let len = reader.u8()? as usize;
+                    let entry = String::from_utf8_lossy(reader.bytes(len)?);
+                    let (key, value) = entry.split_once('=').unwrap_or((&entry, ""));
+                    entries.push((key.to_owned(), value.to_owned()));
+                }
+                Data::Txt(entries)
+            }
+            TYPE_A if len == 4 => {
+                let ip: [u8; 4] = reader.bytes(4)?.try_into()?;
+                Data::Ip(IpAddr::V4(ip.into()))
+            }
+            TYPE_AAAA if len == 16 => {
+                let ip: [u8; 16] = reader.bytes(16)?.try_into()?;
+                Data::Ip(IpAddr::V6(ip.into()))
+            }
+            _ => Data::Other,
+        };
+        reader.pos = end;
+        records.push((name, data));
+    }
+
+    let mut services = Vec::new();
+    for (name, data) in &records {
+        let Data::Srv { port, target } = data else {
+            continue;
+        };
+        if name.len() != 4 || !name_eq(&name[1..], &SERVICE) {
+            continue;
+        }
+        let txt = records
+            .iter()
+            .find_map(|(other, data)| match data {
+                Data::Txt(txt) if other == name => Some(txt.clone()),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let addresses = records
+            .iter()
+            .filter_map(|(other, data)| match data {
+                Data::Ip(ip) if other == target => Some(*ip),
+                _ => None,
+            })
+            .collect();
+        services.push(Service {
+            instance: name[0].clone(),
+            host: target.first().cloned().unwrap_or_default(),
+            port: *port,
+            txt,
+            addresses,
+        });
+    }
+
+    Ok(services)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pointer(offset: usize) -> [u8; 2] {
+        [0xc0 | (offset >> 8) as u8, offset as u8]
+    }
+
+    fn write_header(writer: &mut Writer, flags: u16, questions: u16, records: u16) {
+        writer.u16(0);
+        writer.u16(flags);
+        writer.u16(questions);
+        writer.u16(records);
+        writer.u16(0);
+        writer.u16(0);
+    }
+
+    fn service() -> Service {
+        Service {
+            instance: "Crusader on host".to_owned(),
+            host: "host".to_owned(),
+            port: 35481,
+            txt: vec![
+                ("protocol".to_owned(), "3".to_owned()),
+                ("peer".to_owned(), "0".to_owned()),
+            ],
+            addresses: vec![
+                IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)),
+            ],
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn parse_own_query() {
+        let query = parse_query(&query()).unwrap().unwrap();
+        assert!(!query.unicast);
+    }
+
+    #[test]
+    fn parse_compressed_query() {
+        let mut writer = Writer(Vec::new());
+        write_header(&mut writer, 0, 2, 0);
+        writer.name(&["_other", "_tcp", "local"]);
+        writer.u16(TYPE_PTR);
+        writer.u16(CLASS_IN);
+        // `_crusader` followed by a pointer to `_tcp.local` in the first question
+        let tcp = 12 + 1 + "_other".len();
+        writer.0.extend_from_slice(b"\x09_crusader");
+        writer.0.extend_from_slice(&pointer(tcp));
+        writer.u16(TYPE_PTR);
+        writer.u16(CLASS_IN | CLASS_FLAG);
+
+        let query = parse_query(&writer.0).unwrap().unwrap();
+        assert!(query.unicast);
+    }
+
+    #[test]
+    fn parse_query_ignores_other_services() {
+        let mut writer = Writer(Vec::new());
+        write_header(&mut writer, 0, 1, 0);
+        writer.name(&["_other", "_tcp", "local"]);
+        writer.u16(TYPE_PTR);
+        writer.u16(CLASS_IN);
+        assert!(parse_query(&writer.0).unwrap().is_none());
+
+        let response = response(&service(), None);
+        assert!(parse_query(&response).unwrap().is_none());
+    }
+
+    #[test]
+    fn parse_truncated_query() {
+        let mut writer = Writer(Vec::new());
+        write_header(&mut writer, 0, 1, 0);
+        writer.name(&SERVICE);
+        writer.u16(TYPE_PTR);
+        writer.u16(CLASS_IN);
+        for len in 0..writer.0.len() {
+            assert!(parse_query(&writer.0[..len]).is_err(), "length {len}");
+        }
+
+        // A pointer past the end of the packet
+        let mut writer = Writer(Vec::new());
+        write_header(&mut writer, 0, 1, 0);
+        writer.0.extend_from_slice(&pointer(100));
+        writer.u16(TYPE_PTR);
+        writer.u16(CLASS_IN);
+        assert!(parse_query(&writer.0).is_err());
+    }
+
+    #[test]
+    fn parse_name_pointer_loop() {
+        let mut writer = Writer(Vec::new());
+        write_header(&mut writer, 0, 1, 0);
+        writer.0.extend_from_slice(&pointer(12));
+        writer.u16(TYPE_PTR);
+        writer.u16(CLASS_IN);
+        assert!(parse_query(&writer.0).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn parse_own_response() {
+        let expected = service();
+        for legacy in [false, true] {
+            let query = Query {
+                id: 7,
+                unicast: false,
+            };
+            let packet = response(&expected, legacy.then_some(&query));
+            let services = parse_response(&packet).unwrap();
+            assert_eq!(services.len(), 1);
+            let service = &services[0];
+            assert_eq!(service.instance, expected.instance);
+            assert_eq!(service.host, expected.host);
+            assert_eq!(service.port, expected.port);
+            assert_eq!(service.txt, expected.txt);
+            assert_eq!(service.addresses, expected.addresses);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn parse_compressed_response() {
+        let mut writer = Writer(Vec::new());
+        write_header(&mut writer, FLAG_RESPONSE, 0, 3);
+
+        let instance = writer.0.len();
+        writer.name(&["inst", "_crusader", "_tcp", "local"]);
+        let local = instance + 1 + "inst".len() + 1 + "_crusader".len() + 1 + "_tcp".len();
+        writer.u16(TYPE_SRV);
+        writer.u16(CLASS_IN | CLASS_FLAG);
+        writer.u32(HOST_TTL);
+        writer.u16(6 + 5 + 2);
+        writer.u16(0);
+        writer.u16(0);
+        writer.u16(35481);
+        let host = writer.0.len();
+        writer.0.extend_from_slice(b"\x04host");
+        writer.0.extend_from_slice(&pointer(local));
+
+        writer.0.extend_from_slice(&pointer(instance));
+        writer.u16(TYPE_TXT);
+        writer.u16(CLASS_IN | CLASS_FLAG);
+        writer.u32(SERVICE_TTL);
+        writer.u16(11);
+        writer.0.extend_from_slice(b"\x0aprotocol=3");
+
+        writer.0.extend_from_slice(&pointer(host));
+        writer.u16(TYPE_A);
+        writer.u16(CLASS_IN | CLASS_FLAG);
+        writer.u32(HOST_TTL);
+        writer.u16(4);
+        writer.0.extend_from_slice(&[192, 0, 2, 1]);
+
+        let services = parse_response(&writer.0).unwrap();
+        assert_eq!(services.len(), 1);
+        let service = &services[0];
+        assert_eq!(service.instance, "inst");
+        assert_eq!(service.host, "host");
+        assert_eq!(service.port, 35481);
+        assert_eq!(service.txt("protocol"), Some("3"));
+        assert_eq!(service.addresses, [IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]);
+    }
+
+    #[test]
+    #[cfg(feature = "client")]
+    fn parse_truncated_response() {
+        let packet = response(&service(), None);
+        for len in 0..packet.len() {
+            assert!(parse_response(&packet[..len]).is_err(), "length {len}");
+        }
+        assert!(parse_response(&query()).is_err());
+    }
+}
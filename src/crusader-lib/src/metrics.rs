@@ -0,0 +1,186 @@
+use anyhow::bail;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+use crate::serve::State;
+
+/// Counters exposed by the metrics endpoint of the server.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    pub(crate) tests: AtomicU64,
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) pongs: AtomicU64,
+    pub(crate) overloads: AtomicU64,
+    pub(crate) rejected_hellos: AtomicU64,
+    pub(crate) failed_authentications: AtomicU64,
+    pub(crate) rejected_connections: AtomicU64,
+    pub(crate) rejected_discovery_requests: AtomicU64,
+    pub(crate) peer_sessions: AtomicU64,
+    pub(crate) active_peer_sessions: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc(counter: &AtomicU64) {
+        Self::add(counter, 1);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    fn render(&self, active_clients: usize) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(&str, u64)]| {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} {kind}").unwrap();
+            for (labels, value) in values {
+                writeln!(out, "{name}{labels} {value}").unwrap();
+            }
+        };
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        metric(
+            "crusader_active_clients",
+            "gauge",
+            "Number of clients currently running a test.",
+            &[("", active_clients as u64)],
+        );
+        metric(
+            "crusader_tests_total",
+            "counter",
+            "Number of tests served.",
+            &[("", get(&self.tests))],
+        );
+        metric(
+            "crusader_load_bytes_total",
+            "counter",
+            "Bytes transferred by completed load streams.",
+            &[
+                ("{direction=\"download\"}", get(&self.bytes_sent)),
+                ("{direction=\"upload\"}", get(&self.bytes_received)),
+            ],
+        );
+        metric(
+            "crusader_pongs_total",
+            "counter",
+            "Number of UDP latency pings answered.",
+            &[("", get(&self.pongs))],
+        );
+        metric(
+            "crusader_overloads_total",
+            "counter",
+            "Number of tests where the server was unable to keep up with latency measurements.",
+            &[("", get(&self.overloads))],
+        );
+        metric(
+            "crusader_rejected_hellos_total",
+            "counter",
+            "Number of connections rejected due to an invalid hello.",
+            &[("", get(&self.rejected_hellos))],
+        );
+        metric(
+            "crusader_failed_authentications_total",
+            "counter",
+            "Number of connections which failed to authenticate.",
+            &[("", get(&self.failed_authentications))],
+        );
+        metric(
+            "crusader_rejected_connections_total",
+            "counter",
+            "Number of connections rejected by the access list.",
+            &[("", get(&self.rejected_connections))],
+        );
+        metric(
+            "crusader_rejected_discovery_requests_total",
+            "counter",
+            "Number of discovery requests rejected by the access list.",
+            &[("", get(&self.rejected_discovery_requests))],
+        );
+        metric(
+            "crusader_peer_sessions_total",
+            "counter",
+            "Number of sessions served as a latency peer.",
+            &[("", get(&self.peer_sessions))],
+        );
+        metric(
+            "crusader_active_peer_sessions",
+            "gauge",
+            "Number of sessions currently served as a latency peer.",
+            &[("", get(&self.active_peer_sessions))],
+        );
+
+        out
+    }
+}
+
+async fn respond(state: &State, mut stream: TcpStream) -> Result<(), anyhow::Error> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    let read = async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            if request.len() > 16 * 1024 {
+                bail!("Request too large");
+            }
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                bail!("Connection closed");
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok(())
+    };
+    time::timeout(Duration::from_secs(5), read).await??;
+
+    let line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = line.split(|&b| b == b' ');
+    let (method, path) = (parts.next(), parts.next());
+
+    let (status, body) = match (method, path) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            ("200 OK", state.metrics.render(state.active_clients()))
+        }
+        (Some(b"GET"), _) => ("404 Not Found", "Not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_owned()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\n\
+        Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+pub(crate) async fn serve(state: Arc<State>, addr: SocketAddr) -> Result<(), anyhow::Error> {
+    let listener = TcpListener::bind(addr).await?;
+    (state.msg)(&format!(
+        "Metrics available at http://{}/metrics",
+        listener.local_addr()?
+    ));
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let state = state.clone();
+                    tokio::spawn(async move { respond(&state, stream).await.ok() });
+                }
+                Err(error) => {
+                    (state.msg)(&format!("Error accepting metrics client: {}", error));
+                }
+            }
+        }
+    });
+    Ok(())
+}
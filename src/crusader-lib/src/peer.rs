@@ -1,13 +1,17 @@
+use crate::auth::Secret;
 use crate::common::connect;
 #[cfg(feature = "client")]
 use crate::common::{Config, Msg};
 #[cfg(feature = "client")]
 use crate::discovery;
+#[cfg(feature = "client")]
+use crate::protocol::capability;
 use crate::protocol::PeerLatency;
 use crate::serve::State;
+use crate::tls::{self, Connection, Verification};
 use crate::{
-    common::{hello, measure_latency, ping_recv, ping_send, TestState},
-    protocol::{codec, receive, send, ClientMessage, RawLatency, ServerMessage},
+    common::{fresh_socket_addr, hello, measure_latency, ping_recv, ping_send, TestState},
+    protocol::{codec, receive, send, ClientMessage, RawLatency, ServerMessage, Wire},
 };
 use anyhow::{bail, Context};
 use std::{
@@ -15,7 +19,7 @@ use std::{
     sync::Arc,
     time::Duration,
 };
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::io::{self, ReadHalf, WriteHalf};
 use tokio::sync::watch;
 use tokio::time::Instant;
 use tokio::{
@@ -27,8 +31,8 @@ use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 #[cfg(feature = "client")]
 pub struct Peer {
     msg: Msg,
-    tx: FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>,
-    rx: FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
+    tx: FramedWrite<WriteHalf<Connection>, LengthDelimitedCodec>,
+    rx: FramedRead<ReadHalf<Connection>, LengthDelimitedCodec>,
 }
 
 #[cfg(feature = "client")]
@@ -76,6 +80,7 @@ impl Peer {
 pub async fn connect_to_peer(
     config: Config,
     server: SocketAddr,
+    server_hostname: Option<&str>,
     latency_peer_server: Option<&str>,
     estimated_duration: Duration,
     msg: Msg,
@@ -83,7 +88,7 @@ pub async fn connect_to_peer(
     let control = if let Some(server) = latency_peer_server {
         connect((server, config.port), "latency peer").await?
     } else {
-        let server = discovery::locate(true).await?;
+        let server = discovery::locate(true, false, config.discovery_port).await?;
         msg(&format!(
             "Found peer at {} running version {}",
             server.at, server.software_version
@@ -96,11 +101,27 @@ pub async fn connect_to_peer(
 
     msg(&format!("Connected to peer {}", peer_server));
 
-    let (rx, tx) = control.into_split();
+    let tls = config
+        .peer_tls
+        .map(|verification| {
+            tls::Connector::new(verification, latency_peer_server, peer_server.ip())
+        })
+        .transpose()?;
+    let control = tls::connect(control, tls.as_ref())
+        .await
+        .context("Failed to connect to peer")?;
+
+    let (rx, tx) = io::split(control);
     let mut control_rx = FramedRead::new(rx, codec());
     let mut control_tx = FramedWrite::new(tx, codec());
 
-    hello(&mut control_tx, &mut control_rx).await?;
+    let negotiated = hello(&mut control_tx, &mut control_rx, config.secret).await?;
+    if !negotiated.has(capability::PEER) {
+        bail!(
+            "The latency peer {} does not accept peers, it must be started with `--peer`",
+            peer_server
+        );
+    }
 
     send(
         &mut control_tx,
@@ -113,6 +134,10 @@ pub async fn connect_to_peer(
             port: config.port,
             ping_interval: config.ping_interval.as_millis() as u64,
             estimated_duration: estimated_duration.as_millis(),
+            // The peer verifies the server the same way as this client
+            tls: config.tls,
+            server_name: server_hostname.map(str::to_owned),
+            secret: config.secret,
         },
     )
     .await?;
@@ -137,8 +162,12 @@ pub async fn run_peer(
     server: SocketAddr,
     ping_interval: Duration,
     estimated_duration: Duration,
-    stream_rx: &mut FramedRead<OwnedReadHalf, LengthDelimitedCodec>,
-    stream_tx: &mut FramedWrite<OwnedWriteHalf, LengthDelimitedCodec>,
+    tls: Option<Verification>,
+    server_name: Option<String>,
+    secret: Option<Secret>,
+    wire: Wire,
+    stream_rx: &mut FramedRead<ReadHalf<Connection>, LengthDelimitedCodec>,
+    stream_tx: &mut FramedWrite<WriteHalf<Connection>, LengthDelimitedCodec>,
 ) -> Result<(), anyhow::Error> {
     let control = connect(server, "server").await?;
     control.set_nodelay(true)?;
@@ -147,24 +176,41 @@ pub async fn run_peer(
 
     (state.msg)(&format!("Peer connected to server {}", server));
 
-    let (rx, tx) = control.into_split();
+    let tls = tls
+        .map(|verification| tls::Connector::new(verification, server_name.as_deref(), server.ip()))
+        .transpose()?;
+    let control = tls::connect(control, tls.as_ref())
+        .await
+        .context("Peer failed to connect to the server")?;
+
+    let (rx, tx) = io::split(control);
     let mut control_rx = FramedRead::new(rx, codec());
     let mut control_tx = FramedWrite::new(tx, codec());
 
-    hello(&mut control_tx, &mut control_rx).await?;
+    // The peer authenticates to the server with the token of the client
+    hello(&mut control_tx, &mut control_rx, secret)
+        .await
+        .context("Peer failed to connect to the server")?;
 
     send(&mut control_tx, &ClientMessage::NewClient).await?;
 
     let setup_start = Instant::now();
 
     let reply: ServerMessage = receive(&mut control_rx).await?;
-    let id = match reply {
-        ServerMessage::NewClient(Some(id)) => id,
-        ServerMessage::NewClient(None) => bail!("Server was unable to create client"),
+    let (session, udp_port) = match reply {
+        ServerMessage::NewClient {
+            session: Some(session),
+            udp_port,
+        } => (session, udp_port),
+        ServerMessage::NewClient { session: None, .. } => {
+            bail!("Server was unable to create client")
+        }
+        ServerMessage::ShuttingDown => bail!("Server is shutting down"),
         _ => bail!("Unexpected message {:?}", reply),
     };
+    let ping_server = fresh_socket_addr(server, udp_port);
 
-    send(stream_tx, &ServerMessage::NewPeer).await?;
+    wire.send(stream_tx, ServerMessage::NewPeer).await?;
 
     let local_udp = if server.is_ipv6() {
         SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
@@ -175,11 +221,11 @@ pub async fn run_peer(
     let mut ping_index = 0;
 
     let (latency, server_time_offset, mut control_rx) = measure_latency(
-        id,
+        session,
         &mut ping_index,
         &mut control_tx,
         control_rx,
-        server,
+        ping_server,
         local_udp,
         setup_start,
     )
@@ -191,7 +237,7 @@ pub async fn run_peer(
     ));
 
     let udp_socket = Arc::new(net::UdpSocket::bind(local_udp).await?);
-    udp_socket.connect(server).await?;
+    udp_socket.connect(ping_server).await?;
     let udp_socket2 = udp_socket.clone();
 
     let (state_tx, state_rx) = watch::channel((TestState::Setup, setup_start));
@@ -208,10 +254,11 @@ pub async fn run_peer(
                 ServerMessage::LatencyMeasures(measures) => {
                     latencies.extend(measures.into_iter());
                 }
-                ServerMessage::MeasurementsDone { overload } => {
+                ServerMessage::MeasurementsDone { overload, .. } => {
                     overload_ = overload;
                     break;
                 }
+                ServerMessage::ShuttingDown => (),
                 _ => bail!("Unexpected message {:?}", reply),
             };
         }
@@ -219,15 +266,15 @@ pub async fn run_peer(
         Ok((latencies, overload_))
     });
 
-    send(
+    wire.send(
         stream_tx,
-        &ServerMessage::PeerReady {
+        ServerMessage::PeerReady {
             server_latency: latency.as_nanos() as u64,
         },
     )
     .await?;
 
-    let reply: ClientMessage = receive(stream_rx).await?;
+    let reply = wire.receive(stream_rx).await?;
     match reply {
         ClientMessage::PeerStart => (),
         _ => bail!("Unexpected message {:?}", reply),
@@ -236,7 +283,7 @@ pub async fn run_peer(
     let ping_start_index = ping_index;
     let ping_send = tokio::spawn(ping_send(
         ping_index,
-        id,
+        session,
         state_rx.clone(),
         setup_start,
         udp_socket2.clone(),
@@ -252,10 +299,10 @@ pub async fn run_peer(
         estimated_duration,
     ));
 
-    send(stream_tx, &ServerMessage::PeerStarted).await?;
+    wire.send(stream_tx, ServerMessage::PeerStarted).await?;
 
     // Wait for client to complete test
-    let reply: ClientMessage = receive(stream_rx).await?;
+    let reply = wire.receive(stream_rx).await?;
     match reply {
         ClientMessage::PeerStop => (),
         _ => bail!("Unexpected message {:?}", reply),
@@ -310,9 +357,9 @@ pub async fn run_peer(
         })
         .collect();
 
-    send(
+    wire.send(
         stream_tx,
-        &ServerMessage::PeerDone {
+        ServerMessage::PeerDone {
             overload: server_overload,
             latencies: pings,
         },
@@ -321,3 +368,122 @@ pub async fn run_peer(
 
     Ok(())
 }
+
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use super::*;
+    use crate::file_format::RawResult;
+    use crate::serve;
+    use crate::test::test_async;
+    use crate::tls::ServerTls;
+    use std::path::Path;
+    use tokio::sync::oneshot;
+
+    fn certificate(name: &str) -> ServerTls {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        ServerTls::load(
+            &dir.join(format!("{name}-cert.pem")),
+            &dir.join(format!("{name}-key.pem")),
+        )
+        .unwrap()
+    }
+
+    /// Starts a server using TLS with the `name` certificate on a loopback address.
+    fn start(
+        ip: &str,
+        port: u16,
+        name: &str,
+        peer_server: bool,
+        secret: Option<Secret>,
+    ) -> oneshot::Sender<()> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let stop = serve::serve_until(
+            serve::Config {
+                listen: vec![ip.parse().unwrap()],
+                port,
+                udp_port: port,
+                discovery: false,
+                peer_server,
+                secret,
+                tls: Some(certificate(name)),
+                ..Default::default()
+            },
+            Box::new(|_| ()),
+            Box::new(move |result| tx.send(result).unwrap()),
+            Box::new(|| ()),
+        )
+        .unwrap();
+        rx.recv().unwrap().unwrap();
+        stop
+    }
+
+    fn config(port: u16, peer_tls: Verification) -> Config {
+        Config {
+            download: true,
+            upload: false,
+            bidirectional: false,
+            port,
+            load_duration: Duration::from_millis(500),
+            grace_duration: Duration::from_millis(200),
+            streams: 1,
+            stream_stagger: Duration::ZERO,
+            ping_interval: Duration::from_millis(10),
+            throughput_interval: Duration::from_millis(60),
+            secret: None,
+            tls: Some(Verification::Fingerprint(
+                certificate("server").fingerprint(),
+            )),
+            peer_tls: Some(peer_tls),
+            lowest_latency: false,
+            discovery_port: discovery::DISCOVER_PORT,
+        }
+    }
+
+    fn run(config: Config) -> Result<RawResult, anyhow::Error> {
+        tokio::runtime::Runtime::new().unwrap().block_on(test_async(
+            config,
+            Some("127.0.0.1"),
+            Some(Some("127.0.0.2")),
+            Arc::new(|_| ()),
+        ))
+    }
+
+    #[test]
+    fn pinned_server_and_peer() {
+        let port = 35591;
+        let _server = start("127.0.0.1", port, "server", false, None);
+        let _peer = start("127.0.0.2", port, "peer", true, None);
+
+        let peer_tls = Verification::Fingerprint(certificate("peer").fingerprint());
+        let result = run(config(port, peer_tls)).unwrap();
+        assert!(result.tls);
+        assert!(result.peer_pings.is_some_and(|pings| !pings.is_empty()));
+    }
+
+    #[test]
+    fn peer_is_verified_separately() {
+        let port = 35592;
+        let _server = start("127.0.0.1", port, "server", false, None);
+        let _peer = start("127.0.0.2", port, "peer", true, None);
+
+        // The server certificate is not accepted for the peer
+        let peer_tls = Verification::Fingerprint(certificate("server").fingerprint());
+        assert!(run(config(port, peer_tls)).is_err());
+    }
+
+    #[test]
+    fn peer_uses_client_token() {
+        let port = 35593;
+        let secret = Secret::new("token");
+        let _server = start("127.0.0.1", port, "server", false, Some(secret));
+        let _peer = start("127.0.0.2", port, "peer", true, None);
+
+        let peer_tls = Verification::Fingerprint(certificate("peer").fingerprint());
+        let result = run(Config {
+            secret: Some(secret),
+            ..config(port, peer_tls)
+        })
+        .unwrap();
+        assert!(result.peer_pings.is_some_and(|pings| !pings.is_empty()));
+    }
+}
@@ -11,8 +11,11 @@ use std::path::Path;
 use std::time::Duration;
 use std::{cmp, fmt::Write, mem};
 
+use crate::compare::Run;
 use crate::file_format::{RawPing, RawResult, TestData, TestKind};
+use crate::info::PhaseSummary;
 use crate::protocol::RawLatency;
+use crate::stats::{Sample, KINDS};
 use crate::test::{unique, PlotConfig};
 
 const UP_COLOR: RGBColor = RGBColor(37, 83, 169);
@@ -402,6 +405,14 @@ impl TestResult {
             kind(None)?;
         }
 
+        if self.raw_result.concurrent_load {
+            writeln!(
+                &mut o,
+                "Warning: The server was loaded by other clients during the test. \
+                Result is affected by concurrent usage.\n"
+            )?;
+        }
+
         Ok(o)
     }
 }
@@ -1585,12 +1596,32 @@ pub(crate) fn graph(
             )
             .unwrap();
 
-            root.draw_text(
-                &result.raw_result.generated_by,
-                &small_style.pos(Pos::new(HPos::Right, VPos::Center)),
-                (width as i32 - 100, center),
-            )
-            .unwrap();
+            if let Some(metadata) = &result.raw_result.metadata {
+                root.draw_text(
+                    &result.raw_result.generated_by,
+                    &small_style.pos(Pos::new(HPos::Right, VPos::Top)),
+                    (width as i32 - 100, top_margin),
+                )
+                .unwrap();
+
+                let start = metadata
+                    .start_time()
+                    .map(|time| time.format("%Y-%m-%d %H:%M %:z").to_string())
+                    .unwrap_or_default();
+                root.draw_text(
+                    &format!("Server: {} at {}", metadata.server_name(), start),
+                    &small_style.pos(Pos::new(HPos::Right, VPos::Top)),
+                    (width as i32 - 100, top_margin + text_height / lines),
+                )
+                .unwrap();
+            } else {
+                root.draw_text(
+                    &result.raw_result.generated_by,
+                    &small_style.pos(Pos::new(HPos::Right, VPos::Center)),
+                    (width as i32 - 100, center),
+                )
+                .unwrap();
+            }
         }
 
         let (root, textarea) = root.split_vertically(root.dim_in_pixel().1 - 24);
@@ -1603,6 +1634,41 @@ pub(crate) fn graph(
             )
             .unwrap();
 
+        if let Some(annotation) = result
+            .raw_result
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.annotation())
+        {
+            let annotation = if annotation.chars().count() > 70 {
+                format!("{}...", annotation.chars().take(67).collect::<String>())
+            } else {
+                annotation
+            };
+            textarea
+                .draw_text(
+                    &annotation,
+                    &small_style.pos(Pos::new(HPos::Left, VPos::Center)),
+                    (10, 12),
+                )
+                .unwrap();
+        }
+
+        if let Some(trim) = &result.raw_result.trim {
+            textarea
+                .draw_text(
+                    &format!(
+                        "Trimmed to {:.2} s - {:.2} s of {:.2} s",
+                        trim.from.as_secs_f64(),
+                        trim.to.as_secs_f64(),
+                        trim.original_duration.as_secs_f64()
+                    ),
+                    &small_style.pos(Pos::new(HPos::Right, VPos::Center)),
+                    (width as i32 - 10, 12),
+                )
+                .unwrap();
+        }
+
         let mut root = root.split_vertically(text_height + 10).1;
 
         let loss = if !peer_latency {
@@ -1716,3 +1782,453 @@ pub(crate) fn graph(
 
     ImageBuffer::from_raw(width, height, data).ok_or(anyhow!("Failed to create image"))
 }
+
+/// The colors of the runs in comparison plots.
+const RUN_COLORS: [RGBColor; 6] = [
+    RGBColor(37, 83, 169),
+    RGBColor(214, 110, 38),
+    RGBColor(95, 145, 62),
+    RGBColor(149, 96, 153),
+    RGBColor(190, 60, 60),
+    RGBColor(54, 150, 160),
+];
+
+/// The color of the run at `index` in comparison plots.
+pub fn run_color(index: usize) -> (u8, u8, u8) {
+    let color = RUN_COLORS[index % RUN_COLORS.len()];
+    (color.0, color.1, color.2)
+}
+
+pub fn save_comparison_graph(
+    config: &PlotConfig,
+    runs: &[Run],
+    name: &str,
+    root_path: &Path,
+) -> Result<String, anyhow::Error> {
+    std::fs::create_dir_all(root_path)?;
+    let file = unique(name, "png");
+    comparison_graph(config, runs)
+        .context("Unable to plot")?
+        .save_with_format(root_path.join(&file), ImageFormat::Png)
+        .context("Unable to write plot to file")?;
+    Ok(file)
+}
+
+fn relative(data: &[(u64, f64)], start: f64) -> Vec<(f64, f64)> {
+    data.iter()
+        .map(|(time, value)| (Duration::from_micros(*time).as_secs_f64() - start, *value))
+        .collect()
+}
+
+fn round_trips(pings: &[RawPing], start: f64) -> Vec<(f64, f64)> {
+    pings
+        .iter()
+        .filter_map(|ping| {
+            ping.latency?.total.map(|total| {
+                (
+                    ping.sent.as_secs_f64() - start,
+                    total.as_secs_f64() * 1000.0,
+                )
+            })
+        })
+        .collect()
+}
+
+/// Draws the samples of each run faded with their smoothed values on top.
+fn comparison_chart(
+    runs: &[Run],
+    series: &[(Vec<Vec<(f64, f64)>>, Vec<Vec<(f64, f64)>>)],
+    duration: f64,
+    min_max: Option<f64>,
+    label: &str,
+    area: &DrawingArea<BitMapBackend, Shift>,
+) {
+    let max = float_max(
+        series
+            .iter()
+            .flat_map(|(samples, _)| samples.iter().flatten())
+            .map(|e| e.1),
+    );
+
+    let mut max = max * 1.05;
+
+    if let Some(min_max) = min_max {
+        if min_max > max {
+            max = min_max;
+        }
+    }
+
+    let mut chart = new_chart(duration, None, max, label, true, area);
+
+    for (i, (samples, _)) in series.iter().enumerate() {
+        let color = RUN_COLORS[i % RUN_COLORS.len()];
+        for samples in samples {
+            chart
+                .draw_series(LineSeries::new(samples.iter().copied(), color.mix(0.3)))
+                .unwrap();
+        }
+    }
+
+    for (i, (run, (_, smooth))) in runs.iter().zip(series).enumerate() {
+        let color = RUN_COLORS[i % RUN_COLORS.len()];
+        let style = ShapeStyle {
+            color: color.to_rgba(),
+            filled: true,
+            stroke_width: 2,
+        };
+        for (j, smooth) in smooth.iter().enumerate() {
+            let series = chart
+                .draw_series(LineSeries::new(smooth.iter().copied(), style))
+                .unwrap();
+            if j == 0 {
+                series.label(&run.name).legend(move |(x, y)| {
+                    Rectangle::new([(x, y - 5), (x + 18, y + 3)], color.filled())
+                });
+            }
+        }
+    }
+
+    legends(&mut chart);
+}
+
+/// Plots the throughput and latency of several results on shared axes, with each result
+/// in its own color. The time axis of each result starts at the start of its test.
+pub(crate) fn comparison_graph(
+    config: &PlotConfig,
+    runs: &[Run],
+) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, anyhow::Error> {
+    let width = config.width.unwrap_or(1280) as u32;
+    let height = config.height.unwrap_or(720) as u32;
+
+    let mut data = vec![0; 3 * (width as usize * height as usize)];
+
+    let title = config.title.as_deref().unwrap_or("Comparison");
+
+    let throughput: Vec<_> = runs
+        .iter()
+        .map(|run| {
+            let result = run.result;
+            let start = result.start.as_secs_f64();
+            let interval = result.raw_result.config.bandwidth_interval;
+            let smooth_interval = cmp::min(
+                Duration::from_secs_f64(1.0),
+                result.raw_result.config.grace_duration,
+            );
+            let bytes: Vec<_> = [
+                &result.download_bytes,
+                &result.upload_bytes,
+                &result.both_bytes,
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+            (
+                bytes
+                    .iter()
+                    .map(|bytes| relative(&to_rates(bytes), start))
+                    .collect(),
+                bytes
+                    .iter()
+                    .map(|bytes| relative(&smooth(bytes, interval, smooth_interval), start))
+                    .collect(),
+            )
+        })
+        .collect();
+
+    let latency: Vec<_> = runs
+        .iter()
+        .map(|run| {
+            let result = run.result;
+            let start = result.start.as_secs_f64();
+            let interval =
+                (result.raw_result.config.ping_interval * 3).max(Duration::from_millis(200));
+            (
+                vec![round_trips(&result.pings, start)],
+                vec![round_trips(&smooth_ping(&result.pings, interval), start)],
+            )
+        })
+        .collect();
+
+    let loads = runs.iter().any(|run| run.result.raw_result.streams() > 0);
+
+    {
+        let root = BitMapBackend::with_buffer(&mut data, (width, height)).into_drawing_area();
+
+        root.fill(&WHITE).unwrap();
+
+        let style: TextStyle = (FontFamily::SansSerif, 26).into();
+
+        let medium_style: TextStyle = (FontFamily::SansSerif, 16).into();
+
+        let small_style: TextStyle = (FontFamily::SansSerif, 14).into();
+
+        let text_height = root.estimate_text_size("Wg", &small_style).unwrap().1 as i32 + 5;
+
+        root.draw_text(
+            title,
+            &style.pos(Pos::new(HPos::Center, VPos::Center)),
+            (width as i32 / 2, text_height + 10),
+        )
+        .unwrap();
+
+        let (root, textarea) = root.split_vertically(root.dim_in_pixel().1 - 24);
+
+        textarea
+            .draw_text(
+                "Elapsed time (seconds)",
+                &medium_style.pos(Pos::new(HPos::Center, VPos::Center)),
+                ((width as i32) / 2, 12),
+            )
+            .unwrap();
+
+        let root = root.split_vertically(text_height * 2 + 10).1;
+
+        let areas = root.split_evenly((1 + loads as usize, 1));
+
+        // Scale to fit the legend
+        let duration = float_max(runs.iter().map(|run| run.result.duration.as_secs_f64())) * 1.12;
+
+        if loads {
+            comparison_chart(
+                runs,
+                &throughput,
+                duration,
+                config.max_throughput.map(|l| l as f64 / (1000.0 * 1000.0)),
+                "Throughput (Mbps)",
+                &areas[0],
+            );
+        }
+
+        comparison_chart(
+            runs,
+            &latency,
+            duration,
+            config.max_latency.map(|l| l as f64),
+            "Latency (ms)",
+            areas.last().unwrap(),
+        );
+
+        root.present().map_err(|_| anyhow!("Unable to plot"))?;
+    }
+
+    ImageBuffer::from_raw(width, height, data).ok_or(anyhow!("Failed to create image"))
+}
+
+pub fn save_trend_graph(
+    config: &PlotConfig,
+    samples: &[Sample],
+    name: &str,
+    root_path: &Path,
+) -> Result<String, anyhow::Error> {
+    std::fs::create_dir_all(root_path)?;
+    let file = unique(name, "png");
+    trend_graph(config, samples)
+        .context("Unable to plot")?
+        .save_with_format(root_path.join(&file), ImageFormat::Png)
+        .context("Unable to write plot to file")?;
+    Ok(file)
+}
+
+fn phase_color(kind: Option<TestKind>) -> RGBColor {
+    match kind {
+        Some(TestKind::Download) => DOWN_COLOR,
+        Some(TestKind::Upload) => UP_COLOR,
+        Some(TestKind::Bidirectional) => RGBColor(149, 96, 153),
+        None => RGBColor(50, 50, 50),
+    }
+}
+
+fn trend_chart(
+    samples: &[(f64, &Sample)],
+    range: (f64, f64),
+    min_max: Option<f64>,
+    label: &str,
+    value: fn(&PhaseSummary) -> Option<f64>,
+    format_time: &dyn Fn(&f64) -> String,
+    area: &DrawingArea<BitMapBackend, Shift>,
+) {
+    let series: Vec<(Option<TestKind>, Vec<(f64, f64)>)> = KINDS
+        .into_iter()
+        .map(|kind| {
+            let points = samples
+                .iter()
+                .filter_map(|(time, sample)| {
+                    sample
+                        .phase(kind)
+                        .and_then(value)
+                        .map(|value| (*time, value))
+                })
+                .collect();
+            (kind, points)
+        })
+        .filter(|(_, points): &(_, Vec<_>)| !points.is_empty())
+        .collect();
+
+    let max = float_max(series.iter().flat_map(|(_, points)| points).map(|e| e.1));
+
+    let mut max = max * 1.05;
+
+    if let Some(min_max) = min_max {
+        if min_max > max {
+            max = min_max;
+        }
+    }
+
+    let font = (FontFamily::SansSerif, 16);
+
+    let mut chart = ChartBuilder::on(area)
+        .margin(6)
+        .set_label_area_size(LabelAreaPosition::Left, 100)
+        .set_label_area_size(LabelAreaPosition::Right, 100)
+        .set_label_area_size(LabelAreaPosition::Bottom, 30)
+        .build_cartesian_2d(range.0..range.1, 0.0..max)
+        .unwrap();
+
+    chart
+        .plotting_area()
+        .fill(&RGBColor(248, 248, 248))
+        .unwrap();
+
+    chart
+        .configure_mesh()
+        .disable_x_mesh()
+        .disable_y_mesh()
+        .x_labels(8)
+        .y_labels(10)
+        .x_label_formatter(format_time)
+        .x_label_style(font)
+        .y_label_style(font)
+        .y_desc(label)
+        .draw()
+        .unwrap();
+
+    for (kind, points) in series {
+        let color = phase_color(kind);
+        chart
+            .draw_series(
+                points
+                    .iter()
+                    .map(|&point| Circle::new(point, 3, color.filled())),
+            )
+            .unwrap();
+        chart
+            .draw_series(LineSeries::new(points, color))
+            .unwrap()
+            .label(kind.map(|kind| kind.name()).unwrap_or("Idle"))
+            .legend(move |(x, y)| Rectangle::new([(x, y - 5), (x + 18, y + 3)], color.filled()));
+    }
+
+    legends(&mut chart);
+}
+
+/// Plots the throughput and latency of each test of the samples against their start time.
+/// Samples without a start time are left out.
+pub(crate) fn trend_graph(
+    config: &PlotConfig,
+    samples: &[Sample],
+) -> Result<ImageBuffer<Rgb<u8>, Vec<u8>>, anyhow::Error> {
+    let width = config.width.unwrap_or(1280) as u32;
+    let height = config.height.unwrap_or(720) as u32;
+
+    let mut data = vec![0; 3 * (width as usize * height as usize)];
+
+    let title = config.title.as_deref().unwrap_or("Trend");
+
+    let dated: Vec<_> = samples
+        .iter()
+        .filter_map(|sample| {
+            sample
+                .start_time
+                .map(|time| (time.timestamp() as f64, sample))
+        })
+        .collect();
+    let offset = samples
+        .iter()
+        .find_map(|sample| sample.start_time)
+        .ok_or(anyhow!("No result has a start time"))?
+        .timezone();
+
+    let first = dated.iter().map(|e| e.0).fold(f64::INFINITY, f64::min);
+    let last = dated.iter().map(|e| e.0).fold(f64::NEG_INFINITY, f64::max);
+    // Pad so the first and last results are not on the edges
+    let pad = ((last - first) * 0.05).max(60.0);
+    let (first, last) = (first - pad, last + pad);
+    // Scale to fit the legend
+    let range = (first, last + (last - first) * 0.12);
+
+    let format = if last - first > 2.0 * 24.0 * 3600.0 {
+        "%Y-%m-%d"
+    } else {
+        "%m-%d %H:%M"
+    };
+    let format_time = |time: &f64| {
+        chrono::DateTime::from_timestamp(*time as i64, 0)
+            .map(|time| time.with_timezone(&offset).format(format).to_string())
+            .unwrap_or_default()
+    };
+
+    let loads = dated
+        .iter()
+        .any(|(_, sample)| sample.phases.iter().any(|phase| phase.throughput.is_some()));
+
+    {
+        let root = BitMapBackend::with_buffer(&mut data, (width, height)).into_drawing_area();
+
+        root.fill(&WHITE).unwrap();
+
+        let style: TextStyle = (FontFamily::SansSerif, 26).into();
+
+        let medium_style: TextStyle = (FontFamily::SansSerif, 16).into();
+
+        let small_style: TextStyle = (FontFamily::SansSerif, 14).into();
+
+        let text_height = root.estimate_text_size("Wg", &small_style).unwrap().1 as i32 + 5;
+
+        root.draw_text(
+            title,
+            &style.pos(Pos::new(HPos::Center, VPos::Center)),
+            (width as i32 / 2, text_height + 10),
+        )
+        .unwrap();
+
+        let (root, textarea) = root.split_vertically(root.dim_in_pixel().1 - 24);
+
+        textarea
+            .draw_text(
+                "Start time",
+                &medium_style.pos(Pos::new(HPos::Center, VPos::Center)),
+                ((width as i32) / 2, 12),
+            )
+            .unwrap();
+
+        let root = root.split_vertically(text_height * 2 + 10).1;
+
+        let areas = root.split_evenly((1 + loads as usize, 1));
+
+        if loads {
+            trend_chart(
+                &dated,
+                range,
+                config.max_throughput.map(|l| l as f64 / (1000.0 * 1000.0)),
+                "Throughput (Mbps)",
+                |phase| phase.throughput,
+                &format_time,
+                &areas[0],
+            );
+        }
+
+        trend_chart(
+            &dated,
+            range,
+            config.max_latency.map(|l| l as f64),
+            "Latency (ms)",
+            |phase| phase.latency.map(|latency| latency.total),
+            &format_time,
+            areas.last().unwrap(),
+        );
+
+        root.present().map_err(|_| anyhow!("Unable to plot"))?;
+    }
+
+    ImageBuffer::from_raw(width, height, data).ok_or(anyhow!("Failed to create image"))
+}
@@ -1,3 +1,6 @@
+use crate::auth::Secret;
+use crate::tls::Verification;
+use crate::version;
 use anyhow::Context;
 use bytes::{Bytes, BytesMut};
 use futures::{Sink, SinkExt, Stream, StreamExt};
@@ -20,9 +23,29 @@ impl RawLatency {
 
 pub const PORT: u16 = 35481;
 
+pub mod v3;
+
 pub const MAGIC: u64 = 0x5372ab82ae7c59cb;
-pub const VERSION: u64 = 3;
+/// The newest protocol version.
+pub const VERSION: u64 = 4;
+/// The oldest protocol version served. Versions older than [`NEGOTIATION_VERSION`]
+/// are served by translating messages to their wire format.
+pub const MIN_VERSION: u64 = 3;
+/// The first protocol version which negotiates the version after the hello.
+pub const NEGOTIATION_VERSION: u64 = 4;
+
+/// Capability flags exchanged during version negotiation.
+pub mod capability {
+    /// The server accepts latency peer sessions.
+    pub const PEER: u64 = 1 << 0;
+    /// The server uses TLS.
+    pub const TLS: u64 = 1 << 1;
+    /// The server requires authentication, or the client has a token.
+    pub const AUTHENTICATION: u64 = 1 << 2;
+}
 
+/// The first message on a connection. The version is the newest protocol version
+/// supported by the sender.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct Hello {
     magic: u64,
@@ -31,11 +54,66 @@ pub struct Hello {
 
 impl Hello {
     pub fn new() -> Self {
+        Self::with_version(VERSION)
+    }
+
+    pub fn with_version(version: u64) -> Self {
         Hello {
             magic: MAGIC,
-            version: VERSION,
+            version,
         }
     }
+
+    pub fn valid_magic(&self) -> bool {
+        self.magic == MAGIC
+    }
+}
+
+/// Sent by both sides after the hello if both support [`NEGOTIATION_VERSION`].
+/// The newest protocol version supported by both sides is used.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Negotiate {
+    pub min_version: u64,
+    pub max_version: u64,
+    pub software_version: String,
+    pub capabilities: u64,
+}
+
+impl Negotiate {
+    pub fn new(min_version: u64, capabilities: u64) -> Self {
+        Negotiate {
+            min_version,
+            max_version: VERSION,
+            software_version: version(),
+            capabilities,
+        }
+    }
+
+    /// Returns the newest protocol version supported by both sides.
+    pub fn agree(&self, other: &Negotiate) -> Option<u64> {
+        let version = self.max_version.min(other.max_version);
+        (version >= self.min_version.max(other.min_version)).then_some(version)
+    }
+
+    pub fn has(&self, capability: u64) -> bool {
+        self.capabilities & capability != 0
+    }
+
+    /// Describes the software and protocol versions for error messages.
+    pub fn describe(&self) -> String {
+        format!(
+            "version {} (protocol versions {}-{})",
+            self.software_version, self.min_version, self.max_version
+        )
+    }
+}
+
+/// Identifies a client on the server. The token is a random secret which must accompany
+/// the slot id on associated connections and in pings.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub id: u64,
+    pub token: u128,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, Hash, PartialEq, Eq)]
@@ -58,7 +136,14 @@ pub struct PeerLatency {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServerMessage {
-    NewClient(Option<u64>),
+    Challenge(Option<[u8; 32]>),
+    Authenticated,
+    AuthenticationFailed,
+    NewClient {
+        session: Option<Session>,
+        /// The UDP port used for latency measurements.
+        udp_port: u16,
+    },
     LatencyMeasures(Vec<LatencyMeasure>),
     Measure {
         stream: TestStream,
@@ -71,6 +156,8 @@ pub enum ServerMessage {
     },
     MeasurementsDone {
         overload: bool,
+        /// Load from another client overlapped with the load of this client.
+        concurrent_load: bool,
     },
     LoadComplete {
         stream: TestStream,
@@ -90,12 +177,16 @@ pub enum ServerMessage {
         overload: bool,
         latencies: Vec<PeerLatency>,
     },
+    /// The server is shutting down. Running tests are allowed to complete,
+    /// but new clients are rejected.
+    ShuttingDown,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ClientMessage {
+    ChallengeResponse(Option<[u8; 32]>),
     NewClient,
-    Associate(u64),
+    Associate(Session),
     Done,
     ScheduleLoads {
         groups: Vec<u32>,
@@ -123,6 +214,12 @@ pub enum ClientMessage {
         port: u16,
         ping_interval: u64,
         estimated_duration: u128,
+        /// How the peer verifies the certificate of the server.
+        tls: Option<Verification>,
+        /// The host name used to verify the certificate of the server.
+        server_name: Option<String>,
+        /// The token the peer authenticates to the server with.
+        secret: Option<Secret>,
     },
     PeerStart,
     PeerStop,
@@ -131,9 +228,53 @@ pub enum ClientMessage {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Ping {
     pub id: u64,
+    pub token: u128,
     pub index: u64,
 }
 
+/// The protocol version used on a server connection. Messages are translated
+/// for clients older than [`NEGOTIATION_VERSION`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Wire(pub u64);
+
+impl Wire {
+    pub(crate) fn legacy(self) -> bool {
+        self.0 < NEGOTIATION_VERSION
+    }
+
+    pub(crate) async fn send<S: Sink<Bytes> + Unpin>(
+        self,
+        sink: &mut S,
+        message: ServerMessage,
+    ) -> Result<(), anyhow::Error>
+    where
+        S::Error: Error + Send + Sync + 'static,
+    {
+        if self.legacy() {
+            match v3::ServerMessage::from_current(message) {
+                Some(message) => send(sink, &message).await,
+                None => Ok(()),
+            }
+        } else {
+            send(sink, &message).await
+        }
+    }
+
+    pub(crate) async fn receive<S: Stream<Item = Result<BytesMut, E>> + Unpin, E>(
+        self,
+        stream: &mut S,
+    ) -> Result<ClientMessage, anyhow::Error>
+    where
+        E: Error + Send + Sync + 'static,
+    {
+        if self.legacy() {
+            Ok(receive::<_, v3::ClientMessage, _>(stream).await?.into())
+        } else {
+            receive(stream).await
+        }
+    }
+}
+
 pub fn codec() -> LengthDelimitedCodec {
     length_delimited::Builder::new()
         .little_endian()
@@ -164,3 +305,50 @@ where
         .context("Failed to receive protocol message")?;
     Ok(bincode::deserialize(&bytes)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(min_version: u64, max_version: u64) -> Negotiate {
+        Negotiate {
+            min_version,
+            max_version,
+            software_version: String::new(),
+            capabilities: 0,
+        }
+    }
+
+    fn agree(a: &Negotiate, b: &Negotiate) -> Option<u64> {
+        let version = a.agree(b);
+        assert_eq!(version, b.agree(a), "agreement must be symmetric");
+        version
+    }
+
+    #[test]
+    fn agree_on_newest_common_version() {
+        assert_eq!(agree(&range(4, 4), &range(4, 4)), Some(4));
+        assert_eq!(agree(&range(3, 6), &range(4, 5)), Some(5));
+        assert_eq!(agree(&range(4, 8), &range(5, 6)), Some(6));
+    }
+
+    #[test]
+    fn agree_at_range_edges() {
+        // Ranges touching at a single version
+        assert_eq!(agree(&range(3, 5), &range(5, 7)), Some(5));
+        assert_eq!(agree(&range(5, 7), &range(1, 5)), Some(5));
+        // Ranges one version apart
+        assert_eq!(agree(&range(3, 4), &range(5, 7)), None);
+        assert_eq!(agree(&range(6, 7), &range(3, 5)), None);
+    }
+
+    #[test]
+    fn agree_with_own_versions() {
+        let ours = Negotiate::new(MIN_VERSION, 0);
+        assert_eq!(agree(&ours, &ours), Some(VERSION));
+        assert_eq!(agree(&ours, &range(VERSION, VERSION + 1)), Some(VERSION));
+        assert_eq!(agree(&ours, &range(VERSION + 1, VERSION + 2)), None);
+        assert_eq!(agree(&ours, &range(0, MIN_VERSION)), Some(MIN_VERSION));
+        assert_eq!(agree(&ours, &range(0, MIN_VERSION - 1)), None);
+    }
+}
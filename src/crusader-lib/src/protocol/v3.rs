@@ -0,0 +1,190 @@
+//! The wire format of protocol version 3, which predates version negotiation.
+//! Servers translate messages to and from this format for older clients.
+
+use serde::{Deserialize, Serialize};
+
+use super::{LatencyMeasure, PeerLatency, Session, TestStream};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ServerMessage {
+    NewClient(Option<u64>),
+    LatencyMeasures(Vec<LatencyMeasure>),
+    Measure {
+        stream: TestStream,
+        time: u64,
+        bytes: u64,
+    },
+    MeasureStreamDone {
+        stream: TestStream,
+        timeout: bool,
+    },
+    MeasurementsDone {
+        overload: bool,
+    },
+    LoadComplete {
+        stream: TestStream,
+    },
+    ScheduledLoads {
+        groups: Vec<u32>,
+        time: u64,
+    },
+    WaitingForLoad,
+    WaitingForByte,
+    NewPeer,
+    PeerReady {
+        server_latency: u64,
+    },
+    PeerStarted,
+    PeerDone {
+        overload: bool,
+        latencies: Vec<PeerLatency>,
+    },
+}
+
+impl ServerMessage {
+    /// Converts a message to the version 3 format. Returns `None` for messages
+    /// version 3 clients don't know about.
+    pub fn from_current(message: super::ServerMessage) -> Option<Self> {
+        use super::ServerMessage as Current;
+
+        Some(match message {
+            Current::NewClient { session, .. } => {
+                ServerMessage::NewClient(session.map(|session| session.id))
+            }
+            Current::LatencyMeasures(measures) => ServerMessage::LatencyMeasures(measures),
+            Current::Measure {
+                stream,
+                time,
+                bytes,
+            } => ServerMessage::Measure {
+                stream,
+                time,
+                bytes,
+            },
+            Current::MeasureStreamDone { stream, timeout } => {
+                ServerMessage::MeasureStreamDone { stream, timeout }
+            }
+            Current::MeasurementsDone { overload, .. } => {
+                ServerMessage::MeasurementsDone { overload }
+            }
+            Current::LoadComplete { stream } => ServerMessage::LoadComplete { stream },
+            Current::ScheduledLoads { groups, time } => {
+                ServerMessage::ScheduledLoads { groups, time }
+            }
+            Current::WaitingForLoad => ServerMessage::WaitingForLoad,
+            Current::WaitingForByte => ServerMessage::WaitingForByte,
+            Current::NewPeer => ServerMessage::NewPeer,
+            Current::PeerReady { server_latency } => ServerMessage::PeerReady { server_latency },
+            Current::PeerStarted => ServerMessage::PeerStarted,
+            Current::PeerDone {
+                overload,
+                latencies,
+            } => ServerMessage::PeerDone {
+                overload,
+                latencies,
+            },
+            Current::Challenge(..)
+            | Current::Authenticated
+            | Current::AuthenticationFailed
+            | Current::ShuttingDown => return None,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ClientMessage {
+    NewClient,
+    Associate(u64),
+    Done,
+    ScheduleLoads {
+        groups: Vec<u32>,
+        delay: u64,
+    },
+    LoadFromClient {
+        stream: TestStream,
+        duration: u64,
+        delay: u64,
+        throughput_interval: u64,
+    },
+    LoadFromServer {
+        stream: TestStream,
+        duration: u64,
+        delay: u64,
+    },
+    LoadComplete {
+        stream: TestStream,
+    },
+    SendByte,
+    GetMeasurements,
+    StopMeasurements,
+    NewPeer {
+        server: [u8; 16],
+        port: u16,
+        ping_interval: u64,
+        estimated_duration: u128,
+    },
+    PeerStart,
+    PeerStop,
+}
+
+impl From<ClientMessage> for super::ClientMessage {
+    fn from(message: ClientMessage) -> Self {
+        use super::ClientMessage as Current;
+
+        match message {
+            ClientMessage::NewClient => Current::NewClient,
+            // Version 3 clients have no session token
+            ClientMessage::Associate(id) => Current::Associate(Session { id, token: 0 }),
+            ClientMessage::Done => Current::Done,
+            ClientMessage::ScheduleLoads { groups, delay } => {
+                Current::ScheduleLoads { groups, delay }
+            }
+            ClientMessage::LoadFromClient {
+                stream,
+                duration,
+                delay,
+                throughput_interval,
+            } => Current::LoadFromClient {
+                stream,
+                duration,
+                delay,
+                throughput_interval,
+            },
+            ClientMessage::LoadFromServer {
+                stream,
+                duration,
+                delay,
+            } => Current::LoadFromServer {
+                stream,
+                duration,
+                delay,
+            },
+            ClientMessage::LoadComplete { stream } => Current::LoadComplete { stream },
+            ClientMessage::SendByte => Current::SendByte,
+            ClientMessage::GetMeasurements => Current::GetMeasurements,
+            ClientMessage::StopMeasurements => Current::StopMeasurements,
+            ClientMessage::NewPeer {
+                server,
+                port,
+                ping_interval,
+                estimated_duration,
+            } => Current::NewPeer {
+                server,
+                port,
+                ping_interval,
+                estimated_duration,
+                tls: None,
+                server_name: None,
+                secret: None,
+            },
+            ClientMessage::PeerStart => Current::PeerStart,
+            ClientMessage::PeerStop => Current::PeerStop,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Ping {
+    pub id: u64,
+    pub index: u64,
+}
@@ -1,7 +1,9 @@
-use crate::common::{interface_ips, Config};
+use crate::auth::Secret;
+use crate::common::{bind_tcp, interface_ips, listen_ips, Config};
 use crate::plot::save_graph_to_mem;
 use crate::test::{test_async, timed, PlotConfig};
-use crate::{version, with_time};
+use crate::tls::{parse_fingerprint, Verification};
+use crate::{discovery, version, with_time};
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Error;
@@ -17,16 +19,11 @@ use axum::{
 use image::ImageFormat;
 use serde::Deserialize;
 use serde_json::json;
-use socket2::{Domain, Protocol, Socket};
-use std::io::{Cursor, ErrorKind};
-use std::net::{IpAddr, Ipv6Addr};
+use std::io::Cursor;
+use std::net::IpAddr;
 use std::thread;
 use std::time::Duration;
-use std::{
-    net::{Ipv4Addr, SocketAddr},
-    sync::Arc,
-};
-use tokio::net::TcpSocket;
+use std::{net::SocketAddr, sync::Arc};
 use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::oneshot;
 use tokio::{net::TcpListener, signal, task};
@@ -65,6 +62,26 @@ struct TestArgs {
     throughput_sample_interval: u64,
     latency_peer: bool,
     latency_peer_server: Option<String>,
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    tls: bool,
+    #[serde(default)]
+    tls_fingerprint: Option<String>,
+    #[serde(default)]
+    peer_tls: bool,
+    #[serde(default)]
+    peer_tls_fingerprint: Option<String>,
+}
+
+fn verification(
+    tls: bool,
+    fingerprint: Option<&str>,
+) -> Result<Option<Verification>, anyhow::Error> {
+    Ok(match fingerprint {
+        Some(fingerprint) => Some(Verification::Fingerprint(parse_fingerprint(fingerprint)?)),
+        None => tls.then_some(Verification::WebPki),
+    })
 }
 
 async fn handle_client(
@@ -76,6 +93,25 @@ async fn handle_client(
         Message::Text(request) => serde_json::from_str(&request)?,
         _ => bail!("unexpected message"),
     };
+    let verifications = verification(args.tls, args.tls_fingerprint.as_deref()).and_then(|tls| {
+        let peer_tls = verification(args.peer_tls, args.peer_tls_fingerprint.as_deref())?;
+        Ok((tls, peer_tls))
+    });
+    let (tls, peer_tls) = match verifications {
+        Ok(verifications) => verifications,
+        Err(error) => {
+            socket
+                .send(Message::Text(
+                    json!({
+                        "type": "log",
+                        "message": with_time(&format!("Client failed: {}", error)),
+                    })
+                    .to_string(),
+                ))
+                .await?;
+            return Err(error);
+        }
+    };
     let config = Config {
         port: args.port,
         streams: args.streams,
@@ -87,6 +123,11 @@ async fn handle_client(
         bidirectional: args.bidirectional,
         ping_interval: Duration::from_millis(args.latency_sample_interval),
         throughput_interval: Duration::from_millis(args.throughput_sample_interval),
+        secret: args.token.as_deref().and_then(Secret::from_token),
+        tls,
+        peer_tls,
+        lowest_latency: false,
+        discovery_port: discovery::DISCOVER_PORT,
     };
 
     (state.msg)(&format!("Remote client ({}) test started", who.ip()));
@@ -203,7 +244,11 @@ async fn listen(state: Arc<Env>, listener: TcpListener) {
     .unwrap();
 }
 
-async fn serve_async(port: u16, msg: Box<dyn Fn(&str) + Send + Sync>) -> Result<(), Error> {
+async fn serve_async(
+    port: u16,
+    listen: Vec<IpAddr>,
+    msg: Box<dyn Fn(&str) + Send + Sync>,
+) -> Result<(), Error> {
     let live_reload = cfg!(debug_assertions)
         && std::fs::read_to_string("crusader-lib/src/remote.html")
             .map(|file| *file == *include_str!("remote.html"))
@@ -217,27 +262,10 @@ async fn serve_async(port: u16, msg: Box<dyn Fn(&str) + Send + Sync>) -> Result<
 
     let state = Arc::new(Env { live_reload, msg });
 
-    let v6 = Socket::new(Domain::IPV6, socket2::Type::STREAM, Some(Protocol::TCP))?;
-    v6.set_only_v6(true)?;
-    let v6: std::net::TcpStream = v6.into();
-    v6.set_nonblocking(true)?;
-    let v6 = TcpSocket::from_std_stream(v6);
-    v6.bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port))
-        .map_err(|error| {
-            if let ErrorKind::AddrInUse = error.kind() {
-                anyhow!(
-                    "Failed to bind TCP port, maybe another Crusader instance is already running"
-                )
-            } else {
-                error.into()
-            }
-        })?;
-    let v6 = v6.listen(1024)?;
-
-    let v4 = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
-
-    task::spawn(listen(state.clone(), v6));
-    task::spawn(listen(state.clone(), v4));
+    for ip in listen_ips(&listen) {
+        let listener = bind_tcp(SocketAddr::new(ip, port))?;
+        task::spawn(self::listen(state.clone(), listener));
+    }
 
     (state.msg)(&format!(
         "Remote{} version {} running...",
@@ -250,6 +278,9 @@ async fn serve_async(port: u16, msg: Box<dyn Fn(&str) + Send + Sync>) -> Result<
     ));
 
     for (name, ip) in interface_ips() {
+        if !listen.is_empty() && !listen.contains(&ip) {
+            continue;
+        }
         let addr = match ip {
             IpAddr::V6(ip) => format!("[{ip}]"),
             IpAddr::V4(ip) => ip.to_string(),
@@ -262,6 +293,7 @@ async fn serve_async(port: u16, msg: Box<dyn Fn(&str) + Send + Sync>) -> Result<
 
 pub fn serve_until(
     port: u16,
+    listen: Vec<IpAddr>,
     msg: Box<dyn Fn(&str) + Send + Sync>,
     started: Box<dyn FnOnce(Result<(), String>) + Send>,
     done: Box<dyn FnOnce() + Send>,
@@ -272,7 +304,7 @@ pub fn serve_until(
 
     thread::spawn(move || {
         rt.block_on(async move {
-            match serve_async(port, msg).await {
+            match serve_async(port, listen, msg).await {
                 Ok(()) => {
                     started(Ok(()));
                     rx.await.ok();
@@ -287,11 +319,12 @@ pub fn serve_until(
     Ok(tx)
 }
 
-pub fn run(port: u16) -> Result<(), anyhow::Error> {
+pub fn run(port: u16, listen: Vec<IpAddr>) -> Result<(), anyhow::Error> {
     let rt = tokio::runtime::Runtime::new()?;
     rt.block_on(async move {
         serve_async(
             port,
+            listen,
             Box::new(|msg: &str| {
                 let msg = msg.to_owned();
                 task::spawn_blocking(move || println!("{}", with_time(&msg)));
@@ -1,15 +1,17 @@
 use anyhow::{anyhow, bail, Context};
-use futures::{pin_mut, select, FutureExt};
+use bytes::{Bytes, BytesMut};
+use futures::future::{AbortHandle, Abortable};
+use futures::{pin_mut, select, FutureExt, Sink, Stream};
 use parking_lot::Mutex;
-use socket2::{Domain, Protocol, Socket};
 use std::collections::HashMap;
-use std::io::ErrorKind;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::error::Error;
+use std::mem;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::sync::mpsc::{
     channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
 };
@@ -18,14 +20,23 @@ use tokio::task::{self};
 use tokio::{signal, time, time::Instant};
 use tokio_util::codec::{FramedRead, FramedWrite};
 
-use crate::common::{fresh_socket_addr, inherit_local, interface_ips, read_data, write_data};
+use crate::access::{Access, AccessControl};
+use crate::auth::{self, Secret};
+use crate::common::{
+    bind_tcp, fresh_socket_addr, inherit_local, interface_ips, listen_ips, read_data, write_data,
+};
+use crate::event::{Direction, Event};
+use crate::metrics::{self, Metrics};
 use crate::peer::run_peer;
 use crate::protocol::{
-    self, codec, receive, send, ClientMessage, LatencyMeasure, ServerMessage, TestStream,
+    self, capability, codec, receive, send, v3, ClientMessage, Hello, LatencyMeasure, Negotiate,
+    ServerMessage, Session, TestStream, Wire,
 };
-use crate::{discovery, version, with_time};
+use crate::tls::{self, ServerTls};
+use crate::{admin, discovery, systemd, version, with_time};
 
 use std::thread;
+use subtle::ConstantTimeEq;
 
 #[derive(Debug)]
 struct SlotUpdate {
@@ -35,17 +46,33 @@ struct SlotUpdate {
 }
 
 #[derive(Debug)]
-struct Client {
-    ip: Ipv6Addr,
+pub(crate) struct Client {
+    token: u128,
+    /// The client uses a protocol version without session tokens.
+    legacy: bool,
+    pub(crate) addr: SocketAddr,
+    pub(crate) started: SystemTime,
     tx_message: UnboundedSender<ServerMessage>,
     tx_latency: Sender<LatencyMeasure>,
     rx_latency: Mutex<Receiver<LatencyMeasure>>,
-    overload: AtomicBool,
+    pub(crate) overload: AtomicBool,
+    rejected: AtomicBool,
     loads: Mutex<HashMap<u32, watch::Sender<Option<Instant>>>>,
     uploads: Mutex<HashMap<TestStream, oneshot::Sender<()>>>,
+    /// The number of load streams currently transferring data.
+    pub(crate) active_loads: AtomicU64,
+    /// Load from another client overlapped with the load of this client.
+    concurrent_load: AtomicBool,
+    /// Aborts the control and load connections of the client.
+    connections: Mutex<Vec<AbortHandle>>,
 }
 
 impl Client {
+    /// Checks the session token of a connection or ping in constant time.
+    fn has_token(&self, token: u128) -> bool {
+        !self.legacy && self.token.ct_eq(&token).into()
+    }
+
     fn forward_latency_msgs(&self) {
         let mut rx = self.rx_latency.lock();
 
@@ -60,6 +87,40 @@ impl Client {
             .ok();
     }
 
+    /// Tracks a load stream transferring data until the returned value is dropped.
+    /// Both clients are flagged if another client is also transferring data.
+    fn load(self: &Arc<Self>, state: &State) -> OnDrop<impl Fn()> {
+        self.active_loads.fetch_add(1, Ordering::SeqCst);
+        for other in state.clients.lock().iter().flatten() {
+            if !Arc::ptr_eq(other, self) && other.active_loads.load(Ordering::SeqCst) > 0 {
+                if !self.concurrent_load.swap(true, Ordering::SeqCst) {
+                    (state.msg)(&format!(
+                        "Load from {} overlaps with load from {}",
+                        self.addr, other.addr
+                    ));
+                }
+                other.concurrent_load.store(true, Ordering::SeqCst);
+            }
+        }
+        let client = self.clone();
+        OnDrop(move || {
+            client.active_loads.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    /// The client has opened load connections, so it's running a test and not only
+    /// measuring latency.
+    fn running_test(&self) -> bool {
+        !self.loads.lock().is_empty()
+    }
+
+    /// Closes all connections of the client.
+    pub(crate) fn kill(&self) {
+        for connection in self.connections.lock().iter() {
+            connection.abort();
+        }
+    }
+
     fn load_waiter(&self, group: u32) -> watch::Receiver<Option<Instant>> {
         self.loads
             .lock()
@@ -96,14 +157,154 @@ struct Pong {
     updates: UnboundedSender<SlotUpdate>,
 }
 
+/// A session served as a latency peer.
+pub(crate) struct Peer {
+    pub(crate) addr: SocketAddr,
+    pub(crate) server: SocketAddr,
+    pub(crate) started: SystemTime,
+    pub(crate) connection: AbortHandle,
+}
+
+/// Counts rejections which are left out of the log.
+#[derive(Default)]
+struct Rejections {
+    logged: Option<Instant>,
+    suppressed: u64,
+}
+
+/// The minimum time between messages about rejected connections and packets. Remote hosts can
+/// cause these at will, and the source of discovery packets can be spoofed.
+const REJECTION_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
 pub(crate) struct State {
-    port: u16,
+    udp_port: u16,
     started: Instant,
     dummy_data: Vec<u8>,
-    clients: Mutex<Vec<Option<Arc<Client>>>>,
+    pub(crate) clients: Mutex<Vec<Option<Arc<Client>>>>,
+    pub(crate) peers: Mutex<HashMap<u64, Peer>>,
+    next_peer: AtomicU64,
     pong_servers: Mutex<HashMap<SocketAddr, Arc<Pong>>>,
     pub(crate) msg: Box<dyn Fn(&str) + Send + Sync>,
     pub(crate) peer_server: bool,
+    pub(crate) label: Option<String>,
+    max_clients: usize,
+    pub(crate) secret: Option<Secret>,
+    pub(crate) admin_secret: Option<Secret>,
+    tls: Option<ServerTls>,
+    pub(crate) access: AccessControl,
+    pub(crate) metrics: Metrics,
+    events: Option<Arc<dyn Fn(&str) + Send + Sync>>,
+    shutting_down: AtomicBool,
+    /// Stops the TCP listeners.
+    listeners: Mutex<Vec<AbortHandle>>,
+    rejections: Mutex<Rejections>,
+}
+
+impl State {
+    /// Records a structured event when using [`LogFormat::Json`].
+    pub(crate) fn event(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events(&event.to_json());
+        }
+    }
+
+    /// Logs a message about a rejected connection or packet, unless one was logged
+    /// in the last [`REJECTION_LOG_INTERVAL`].
+    pub(crate) fn rejected(&self, message: &str) {
+        let suppressed = {
+            let mut rejections = self.rejections.lock();
+            let now = Instant::now();
+            if let Some(logged) = rejections.logged {
+                if now < logged + REJECTION_LOG_INTERVAL {
+                    rejections.suppressed += 1;
+                    return;
+                }
+            }
+            rejections.logged = Some(now);
+            mem::take(&mut rejections.suppressed)
+        };
+        if suppressed > 0 {
+            (self.msg)(&format!(
+                "{} ({} earlier rejections were not logged)",
+                message, suppressed
+            ));
+        } else {
+            (self.msg)(message);
+        }
+    }
+
+    pub(crate) fn active_clients(&self) -> usize {
+        self.clients
+            .lock()
+            .iter()
+            .filter(|slot| slot.is_some())
+            .count()
+    }
+
+    /// The status line shown by the service manager.
+    fn status(&self) -> String {
+        format!(
+            "{} active clients, {} peer sessions",
+            self.active_clients(),
+            self.metrics.active_peer_sessions.load(Ordering::Relaxed)
+        )
+    }
+
+    /// Stops accepting connections and tells the connected clients that the server is going away.
+    /// Clients which are only measuring latency, such as latency monitors, are disconnected
+    /// as they would otherwise keep the server running.
+    fn shutdown(&self) {
+        systemd::notify("STOPPING=1").ok();
+        for listener in self.listeners.lock().drain(..) {
+            listener.abort();
+        }
+        let clients = self.clients.lock();
+        self.shutting_down.store(true, Ordering::SeqCst);
+        for client in clients.iter().flatten() {
+            client.tx_message.send(ServerMessage::ShuttingDown).ok();
+            if !client.running_test() {
+                client.kill();
+            }
+        }
+    }
+
+    /// Waits for running tests and peer sessions to complete, up to `timeout`.
+    async fn drain(&self, timeout: Duration) {
+        self.shutdown();
+
+        let running = || {
+            let tests = self
+                .clients
+                .lock()
+                .iter()
+                .flatten()
+                .filter(|client| client.running_test())
+                .count() as u64;
+            tests + self.metrics.active_peer_sessions.load(Ordering::Relaxed)
+        };
+        if running() == 0 {
+            return;
+        }
+
+        (self.msg)(&format!(
+            "Waiting up to {} seconds for {} running tests to complete. \
+                Press Ctrl-C again to exit immediately",
+            timeout.as_secs(),
+            running()
+        ));
+
+        let deadline = Instant::now() + timeout;
+        while running() > 0 {
+            if Instant::now() >= deadline {
+                (self.msg)(&format!(
+                    "Timed out waiting for {} running tests",
+                    running()
+                ));
+                return;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    }
 }
 
 fn ip_to_ipv6_mapped(ip: IpAddr) -> Ipv6Addr {
@@ -121,27 +322,165 @@ impl<F: Fn()> Drop for OnDrop<F> {
     }
 }
 
-async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Error> {
+/// Sends an authentication challenge if the server has a secret and verifies the response.
+/// Returns `false` if the client failed to authenticate.
+pub(crate) async fn challenge<
+    T: Sink<Bytes> + Unpin,
+    R: Stream<Item = Result<BytesMut, RE>> + Unpin,
+    RE,
+>(
+    secret: Option<Secret>,
+    tx: &mut T,
+    rx: &mut R,
+) -> Result<bool, anyhow::Error>
+where
+    T::Error: Error + Send + Sync + 'static,
+    RE: Error + Send + Sync + 'static,
+{
+    let nonce = secret.map(|_| auth::nonce());
+    send(tx, &ServerMessage::Challenge(nonce)).await?;
+
+    if let (Some(secret), Some(nonce)) = (secret, nonce) {
+        let response: ClientMessage = receive(rx).await?;
+        let accepted = match response {
+            ClientMessage::ChallengeResponse(Some(response)) => secret.verify(&nonce, &response),
+            ClientMessage::ChallengeResponse(None) => false,
+            _ => bail!("Unexpected message {:?}", response),
+        };
+        if !accepted {
+            send(tx, &ServerMessage::AuthenticationFailed).await?;
+            return Ok(false);
+        }
+        send(tx, &ServerMessage::Authenticated).await?;
+    }
+
+    Ok(true)
+}
+
+/// The outcome of the hello and version negotiation with a client.
+pub(crate) enum Handshake {
+    Accepted(Wire),
+    Rejected {
+        /// The newest protocol version of the client.
+        version: u64,
+        reason: String,
+    },
+}
+
+fn capabilities(state: &State) -> u64 {
+    let mut capabilities = 0;
+    if state.peer_server {
+        capabilities |= capability::PEER;
+    }
+    if state.tls.is_some() {
+        capabilities |= capability::TLS;
+    }
+    if state.secret.is_some() {
+        capabilities |= capability::AUTHENTICATION;
+    }
+    capabilities
+}
+
+/// Receives the hello of a client and negotiates the protocol version. Clients older than
+/// [`protocol::NEGOTIATION_VERSION`] are only accepted if `legacy` is set.
+pub(crate) async fn handshake<
+    T: Sink<Bytes> + Unpin,
+    R: Stream<Item = Result<BytesMut, RE>> + Unpin,
+    RE,
+>(
+    state: &State,
+    legacy: bool,
+    tx: &mut T,
+    rx: &mut R,
+) -> Result<Handshake, anyhow::Error>
+where
+    T::Error: Error + Send + Sync + 'static,
+    RE: Error + Send + Sync + 'static,
+{
+    let client_hello: Hello = receive(rx).await?;
+    let version = client_hello.version;
+
+    let reason = if !client_hello.valid_magic() {
+        Some(format!("Invalid hello {:?}", client_hello))
+    } else if version < protocol::NEGOTIATION_VERSION {
+        if !legacy || version < protocol::MIN_VERSION {
+            Some(format!("Unsupported protocol version {}", version))
+        } else if state.secret.is_some() {
+            Some(format!(
+                "Protocol version {} does not support authentication",
+                version
+            ))
+        } else {
+            // Older clients expect the server to reply with the same version
+            send(tx, &Hello::with_version(version)).await?;
+            return Ok(Handshake::Accepted(Wire(version)));
+        }
+    } else {
+        None
+    };
+
+    send(tx, &Hello::new()).await?;
+
+    if let Some(reason) = reason {
+        return Ok(Handshake::Rejected { version, reason });
+    }
+
+    let ours = Negotiate::new(protocol::MIN_VERSION, capabilities(state));
+    send(tx, &ours).await?;
+    let theirs: Negotiate = receive(rx).await?;
+
+    Ok(match ours.agree(&theirs) {
+        Some(version) => Handshake::Accepted(Wire(version)),
+        None => Handshake::Rejected {
+            version,
+            reason: format!("Incompatible client {}", theirs.describe()),
+        },
+    })
+}
+
+async fn client(
+    state: Arc<State>,
+    stream: TcpStream,
+    slot: &mut Option<u64>,
+    connection: AbortHandle,
+) -> Result<(), anyhow::Error> {
     stream.set_nodelay(true)?;
 
     let addr = stream.peer_addr()?;
-    let local_addr = fresh_socket_addr(stream.local_addr()?, state.port);
-
-    let (rx, tx) = stream.into_split();
+    let local = stream.local_addr()?;
+    let local_addr = fresh_socket_addr(local, state.udp_port);
+    // Clients using protocol version 3 send pings to the TCP port
+    let legacy_local_addr = fresh_socket_addr(local, local.port());
+
+    let stream = time::timeout(
+        Duration::from_secs(5),
+        tls::accept(stream, state.tls.as_ref()),
+    )
+    .await
+    .context("TLS handshake timed out")??;
+
+    let (rx, tx) = tokio::io::split(stream);
     let mut stream_rx = FramedRead::new(rx, codec());
     let mut stream_tx = FramedWrite::new(tx, codec());
 
-    let hello = protocol::Hello::new();
-
-    let client_hello: protocol::Hello = receive(&mut stream_rx).await?;
-
-    send(&mut stream_tx, &hello).await?;
+    let wire = match handshake(&state, true, &mut stream_tx, &mut stream_rx).await? {
+        Handshake::Accepted(wire) => wire,
+        Handshake::Rejected { version, reason } => {
+            state.rejected(&format!("Rejected client {}: {}", addr, reason));
+            Metrics::inc(&state.metrics.rejected_hellos);
+            state.event(Event::HelloMismatch {
+                addr,
+                version,
+                expected_version: protocol::VERSION,
+            });
+            return Ok(());
+        }
+    };
 
-    if hello != client_hello {
-        (state.msg)(&format!(
-            "Client {} had invalid hello {:?}, expected {:?}",
-            addr, client_hello, hello
-        ));
+    if !wire.legacy() && !challenge(state.secret, &mut stream_tx, &mut stream_rx).await? {
+        state.rejected(&format!("Client {} failed authentication", addr));
+        Metrics::inc(&state.metrics.failed_authentications);
+        state.event(Event::AuthenticationFailed { addr });
         return Ok(());
     }
 
@@ -153,28 +492,60 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
     let mut _client_dropper = None;
 
     loop {
-        let request: ClientMessage = receive(&mut stream_rx).await?;
+        let request = wire.receive(&mut stream_rx).await?;
         match request {
             ClientMessage::NewPeer {
                 server,
                 port,
                 ping_interval,
                 estimated_duration,
+                tls,
+                server_name,
+                secret,
             } => {
                 if !state.peer_server {
                     bail!("Server not accepting peers")
                 }
-                (state.msg)(&format!(
-                    "Serving as peer for {}, version {}",
-                    addr, hello.version
-                ));
+                if state.shutting_down.load(Ordering::SeqCst) {
+                    bail!("Server is shutting down")
+                }
+                (state.msg)(&format!("Serving as peer for {}, version {}", addr, wire.0));
                 let ip = Ipv6Addr::from(server).to_canonical();
                 (state.msg)(&format!("Server for peer is {ip}:{port}",));
+                Metrics::inc(&state.metrics.peer_sessions);
+                state.event(Event::PeerSession {
+                    addr,
+                    server: SocketAddr::new(ip, port),
+                    version: wire.0,
+                });
+                Metrics::inc(&state.metrics.active_peer_sessions);
+                let id = state.next_peer.fetch_add(1, Ordering::Relaxed);
+                state.peers.lock().insert(
+                    id,
+                    Peer {
+                        addr,
+                        server: SocketAddr::new(ip, port),
+                        started: SystemTime::now(),
+                        connection,
+                    },
+                );
+                let state_ = state.clone();
+                let _active = OnDrop(move || {
+                    state_.peers.lock().remove(&id);
+                    state_
+                        .metrics
+                        .active_peer_sessions
+                        .fetch_sub(1, Ordering::Relaxed);
+                });
                 run_peer(
                     state,
                     inherit_local(local_addr, ip, port),
                     Duration::from_millis(ping_interval),
                     Duration::from_millis(estimated_duration as u64),
+                    tls,
+                    server_name,
+                    secret,
+                    wire,
                     &mut stream_rx,
                     &mut stream_tx,
                 )
@@ -183,9 +554,22 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
                 return Ok(());
             }
             ClientMessage::NewClient => {
-                (state.msg)(&format!("Serving {}, version {}", addr, hello.version));
+                if state.shutting_down.load(Ordering::SeqCst) {
+                    (state.msg)(&format!("Rejected {}, server is shutting down", addr));
+                    wire.send(&mut stream_tx, ServerMessage::ShuttingDown)
+                        .await?;
+                    return Ok(());
+                }
+
+                (state.msg)(&format!("Serving {}, version {}", addr, wire.0));
+                Metrics::inc(&state.metrics.tests);
 
-                let pong = start_pong_server(&state, local_addr)
+                let pong_addr = if wire.legacy() {
+                    legacy_local_addr
+                } else {
+                    local_addr
+                };
+                let pong = start_pong_server(&state, pong_addr)
                     .await
                     .context("Failed to start pong server")?;
 
@@ -201,13 +585,20 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
                             let (tx_latency, rx_latency) = channel(200);
                             let slot = slot as u64;
                             let new_client = Arc::new(Client {
-                                ip: ip_to_ipv6_mapped(addr.ip()),
+                                token: rand::random(),
+                                legacy: wire.legacy(),
+                                addr,
+                                started: SystemTime::now(),
                                 tx_message,
                                 tx_latency,
                                 rx_latency: Mutex::new(rx_latency),
                                 overload: AtomicBool::new(false),
+                                rejected: AtomicBool::new(false),
                                 loads: Mutex::new(HashMap::new()),
                                 uploads: Mutex::new(HashMap::new()),
+                                active_loads: AtomicU64::new(0),
+                                concurrent_load: AtomicBool::new(false),
+                                connections: Mutex::new(vec![connection.clone()]),
                             });
                             *data = Some(new_client.clone());
 
@@ -217,47 +608,73 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
                         })
                     };
 
-                    if let Some((slot, client)) = client {
+                    if let Some((id, client)) = client {
+                        let token = client.token;
+                        *slot = Some(id);
+                        state.event(Event::TestStarted {
+                            addr,
+                            slot: id,
+                            version: wire.0,
+                        });
+
                         // Update pong server slot
                         let (rx, tx) = oneshot::channel();
                         pong.updates.send(SlotUpdate {
-                            slot,
+                            slot: id,
                             client: Some(client.clone()),
                             reply: Some(rx),
                         })?;
                         tx.await.ok();
 
-                        _client_dropper = Some(move || {
+                        let state = state.clone();
+                        _client_dropper = Some(OnDrop(move || {
+                            state.clients.lock()[id as usize] = None;
                             pong.updates
                                 .send(SlotUpdate {
-                                    slot,
+                                    slot: id,
                                     client: None,
                                     reply: None,
                                 })
                                 .ok();
-                        });
+                        }));
 
-                        Some(slot)
+                        Some(Session { id, token })
                     } else {
                         None
                     }
                 };
 
-                send(&mut stream_tx, &ServerMessage::NewClient(client)).await?;
+                wire.send(
+                    &mut stream_tx,
+                    ServerMessage::NewClient {
+                        session: client,
+                        udp_port: state.udp_port,
+                    },
+                )
+                .await?;
             }
-            ClientMessage::Associate(id) => {
-                client = Some(
-                    state
-                        .clients
-                        .lock()
-                        .get(id as usize)
-                        .and_then(|client| client.as_ref())
-                        .cloned()
-                        .and_then(|client| {
-                            (client.ip == ip_to_ipv6_mapped(addr.ip())).then_some(client)
-                        })
-                        .ok_or(anyhow!("Unable to assoicate client"))?,
-                );
+            ClientMessage::Associate(session) => {
+                *slot = Some(session.id);
+                let associated = state
+                    .clients
+                    .lock()
+                    .get(session.id as usize)
+                    .and_then(|client| client.as_ref())
+                    .cloned()
+                    .filter(|client| {
+                        // Clients using protocol version 3 have no session token,
+                        // so their connections must come from the same IP instead
+                        if wire.legacy() {
+                            client.legacy
+                                && ip_to_ipv6_mapped(client.addr.ip())
+                                    == ip_to_ipv6_mapped(addr.ip())
+                        } else {
+                            client.has_token(session.token)
+                        }
+                    })
+                    .ok_or(anyhow!("Unable to assoicate client"))?;
+                associated.connections.lock().push(connection.clone());
+                client = Some(associated);
             }
             ClientMessage::GetMeasurements => {
                 let receiver = receiver.as_mut().ok_or(anyhow!("Not the main client"))?;
@@ -283,7 +700,7 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
 
                 loop {
                     let message = {
-                        let request = receive::<_, ClientMessage, _>(&mut stream_rx).fuse();
+                        let request = wire.receive(&mut stream_rx).fuse();
                         pin_mut!(request);
 
                         let message = receiver.recv().fuse();
@@ -297,7 +714,7 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
 
                     match message {
                         Ok(Some(message)) => {
-                            send(&mut stream_tx, &message).await?;
+                            wire.send(&mut stream_tx, message).await?;
                         }
                         Ok(None) | Err(ClientMessage::StopMeasurements) => {
                             done.store(true, Ordering::Release);
@@ -305,12 +722,15 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
 
                             // Send pending messages
                             while let Ok(message) = receiver.try_recv() {
-                                send(&mut stream_tx, &message).await?;
+                                wire.send(&mut stream_tx, message).await?;
                             }
 
-                            send(
+                            wire.send(
                                 &mut stream_tx,
-                                &ServerMessage::MeasurementsDone { overload },
+                                ServerMessage::MeasurementsDone {
+                                    overload,
+                                    concurrent_load: client_.concurrent_load.load(Ordering::SeqCst),
+                                },
                             )
                             .await?;
                             break;
@@ -328,7 +748,7 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
                         }
                         Err(ClientMessage::ScheduleLoads { groups, delay }) => {
                             let reply = client_.schedule_loads(&state, groups, delay).await?;
-                            send(&mut stream_tx, &reply).await?;
+                            wire.send(&mut stream_tx, reply).await?;
                         }
                         Err(msg) => {
                             bail!("Unexpected message during measurement {:?}", msg)
@@ -344,25 +764,21 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
             } => {
                 let client = client.ok_or(anyhow!("No associated client"))?;
 
-                let mut stream_rx = stream_rx.into_inner();
+                wire.send(&mut stream_tx, ServerMessage::WaitingForByte)
+                    .await?;
 
-                send(&mut stream_tx, &ServerMessage::WaitingForByte).await?;
+                let mut stream = stream_rx.into_inner().unsplit(stream_tx.into_inner());
 
                 // Wait for a pending read byte
-                loop {
-                    let _ = stream_rx.read(&mut []).await?;
-                    match time::timeout(Duration::from_millis(10), stream_rx.peek(&mut [0])).await {
-                        Ok(Ok(1)) => break,
-                        Err(_) | Ok(Ok(_)) => (),
-                        Ok(Err(err)) => return Err(err.into()),
-                    }
-                }
+                stream.wait_for_byte().await?;
 
                 let mut waiter = client.load_waiter(test_stream.group);
 
-                send(&mut stream_tx, &ServerMessage::WaitingForLoad).await?;
-
-                let stream = stream_tx.into_inner().reunite(stream_rx).unwrap();
+                wire.send(
+                    &mut FramedWrite::new(&mut stream, codec()),
+                    ServerMessage::WaitingForLoad,
+                )
+                .await?;
 
                 waiter.changed().await?;
                 let start =
@@ -370,12 +786,23 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
 
                 time::sleep_until(start).await;
 
-                write_data(
+                let _load = client.load(&state);
+                let written = write_data(
                     stream,
                     state.dummy_data.as_ref(),
                     start + Duration::from_micros(duration),
                 )
                 .await?;
+                Metrics::add(&state.metrics.bytes_sent, written);
+                state.event(Event::LoadStream {
+                    addr,
+                    slot: slot.unwrap_or_default(),
+                    direction: Direction::Download,
+                    group: test_stream.group,
+                    id: test_stream.id,
+                    duration_ms: start.elapsed().as_millis() as u64,
+                    bytes: written,
+                });
 
                 client
                     .tx_message
@@ -394,20 +821,19 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
             } => {
                 let client = client.ok_or(anyhow!("No associated client"))?;
 
-                send(&mut stream_tx, &ServerMessage::WaitingForLoad).await?;
+                wire.send(&mut stream_tx, ServerMessage::WaitingForLoad)
+                    .await?;
 
-                let reply: ClientMessage = receive(&mut stream_rx).await.unwrap();
+                let reply = wire.receive(&mut stream_rx).await.unwrap();
                 match reply {
                     ClientMessage::SendByte => (),
                     _ => bail!("Unexpected message {:?}", reply),
                 };
 
-                let mut stream = stream_rx
-                    .into_inner()
-                    .reunite(stream_tx.into_inner())
-                    .unwrap();
+                let mut stream = stream_rx.into_inner().unsplit(stream_tx.into_inner());
 
-                stream.write_u8(1).await.unwrap();
+                stream.write_u8(1).await?;
+                stream.flush().await?;
 
                 let (reading_done_tx, reading_done_rx) = oneshot::channel();
 
@@ -424,6 +850,8 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
 
                 time::sleep_until(start).await;
 
+                let _load = client.load(&state);
+                let started = state.started;
                 tokio::spawn(async move {
                     let mut interval = time::interval(Duration::from_micros(throughput_interval));
                     loop {
@@ -436,9 +864,8 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
                             .tx_message
                             .send(ServerMessage::Measure {
                                 stream: test_stream,
-                                time: current_time
-                                    .saturating_duration_since(state.started)
-                                    .as_micros() as u64,
+                                time: current_time.saturating_duration_since(started).as_micros()
+                                    as u64,
                                 bytes: current_bytes,
                             })
                             .ok();
@@ -459,11 +886,22 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
                 let timeout = read_data(
                     stream,
                     &mut buffer,
-                    bytes,
+                    bytes.clone(),
                     start + Duration::from_micros(duration),
                     reading_done_rx,
                 )
                 .await?;
+                let bytes = bytes.load(Ordering::Acquire);
+                Metrics::add(&state.metrics.bytes_received, bytes);
+                state.event(Event::LoadStream {
+                    addr,
+                    slot: slot.unwrap_or_default(),
+                    direction: Direction::Upload,
+                    group: test_stream.group,
+                    id: test_stream.id,
+                    duration_ms: start.elapsed().as_millis() as u64,
+                    bytes,
+                });
 
                 done_tx
                     .send(timeout)
@@ -473,10 +911,14 @@ async fn client(state: Arc<State>, stream: TcpStream) -> Result<(), anyhow::Erro
             }
             ClientMessage::Done => {
                 (state.msg)(&format!("Serving complete for {}", addr));
+                if let Some(slot) = *slot {
+                    state.event(Event::TestCompleted { addr, slot });
+                }
 
                 return Ok(());
             }
-            msg @ (ClientMessage::StopMeasurements
+            msg @ (ClientMessage::ChallengeResponse(..)
+            | ClientMessage::StopMeasurements
             | ClientMessage::ScheduleLoads { .. }
             | ClientMessage::LoadComplete { .. }
             | ClientMessage::SendByte
@@ -492,11 +934,31 @@ async fn listen(state: Arc<State>, listener: TcpListener) {
     loop {
         match listener.accept().await {
             Ok((socket, addr)) => {
+                if !state.access.allows(addr.ip()) {
+                    state.rejected(&format!("Rejected client {}", addr));
+                    Metrics::inc(&state.metrics.rejected_connections);
+                    state.event(Event::Rejected { addr });
+                    continue;
+                }
+                state.event(Event::Connected { addr });
                 let state = state.clone();
                 tokio::spawn(async move {
-                    client(state.clone(), socket).await.map_err(|error| {
+                    let mut slot = None;
+                    let (abort, registration) = AbortHandle::new_pair();
+                    let result = Abortable::new(
+                        client(state.clone(), socket, &mut slot, abort),
+                        registration,
+                    )
+                    .await;
+                    // Aborted connections were terminated by an admin
+                    if let Ok(Err(error)) = result {
                         (state.msg)(&format!("Error serving client {}: {:?}", addr, error));
-                    })
+                        state.event(Event::Error {
+                            addr,
+                            slot,
+                            error: format!("{:#}", error),
+                        });
+                    }
                 });
             }
             Err(error) => {
@@ -506,6 +968,16 @@ async fn listen(state: Arc<State>, listener: TcpListener) {
     }
 }
 
+/// Accepts connections on `listener` until the server shuts down.
+fn spawn_listener(state: &Arc<State>, listener: TcpListener) {
+    let (abort, registration) = AbortHandle::new_pair();
+    state.listeners.lock().push(abort);
+    task::spawn(Abortable::new(
+        listen(state.clone(), listener),
+        registration,
+    ));
+}
+
 async fn handle_ping(
     state: &State,
     slots: &[Option<Arc<Client>>],
@@ -513,34 +985,59 @@ async fn handle_ping(
     src: SocketAddr,
     socket: &UdpSocket,
 ) {
-    let valid_ping = bincode::deserialize(packet)
-        .ok()
-        .and_then(|ping: protocol::Ping| {
-            slots
-                .get(ping.id as usize)
-                .and_then(|client| client.as_ref())
-                .and_then(|client| {
-                    (ip_to_ipv6_mapped(src.ip()) == client.ip).then_some((client, ping))
-                })
-        });
+    let ping = if packet.len() == 16 {
+        // Pings from clients using protocol version 3 have no session token
+        bincode::deserialize(packet)
+            .ok()
+            .map(|ping: v3::Ping| (ping.id, None, ping.index))
+    } else {
+        bincode::deserialize(packet)
+            .ok()
+            .map(|ping: protocol::Ping| (ping.id, Some(ping.token), ping.index))
+    };
+
+    let valid_ping = ping.and_then(|(id, token, index)| {
+        slots
+            .get(id as usize)
+            .and_then(|client| client.as_ref())
+            .filter(|client| match token {
+                Some(token) => client.has_token(token),
+                None => {
+                    client.legacy
+                        && ip_to_ipv6_mapped(client.addr.ip()) == ip_to_ipv6_mapped(src.ip())
+                }
+            })
+            .map(|client| (client, id, index))
+    });
+
+    if let Some((client, id, index)) = valid_ping {
+        if !state.access.allows(src.ip()) {
+            if !client.rejected.swap(true, Ordering::SeqCst) {
+                state.rejected(&format!("Rejected UDP pings from {}", src));
+            }
+            return;
+        }
 
-    if let Some((client, ping)) = valid_ping {
         let time = Instant::now()
             .saturating_duration_since(state.started)
             .as_micros() as u64;
 
-        let measure = LatencyMeasure {
-            time,
-            index: ping.index,
-        };
+        let measure = LatencyMeasure { time, index };
 
-        if client.tx_latency.try_send(measure).is_err() {
-            client.overload.store(true, Ordering::SeqCst);
+        if client.tx_latency.try_send(measure).is_err()
+            && !client.overload.swap(true, Ordering::SeqCst)
+        {
+            Metrics::inc(&state.metrics.overloads);
+            state.event(Event::Overload {
+                addr: src,
+                slot: id,
+            });
         }
 
         socket
             .send_to(packet, &src)
             .await
+            .map(|_| Metrics::inc(&state.metrics.pongs))
             .map_err(|error| {
                 (state.msg)(&format!("Unable to send UDP pong packet: {:?}", error));
             })
@@ -556,7 +1053,7 @@ async fn pong(
 ) {
     (state.msg)(&format!("Starting UDP server ({})", addr));
 
-    let mut slots: Vec<_> = (0..SLOTS).map(|_| None).collect();
+    let mut slots: Vec<_> = (0..state.max_clients).map(|_| None).collect();
     let mut buf = [0; 128];
 
     loop {
@@ -597,8 +1094,6 @@ async fn pong(
     }
 }
 
-const SLOTS: usize = 1000;
-
 async fn start_pong_server(
     state: &Arc<State>,
     addr: SocketAddr,
@@ -623,63 +1118,244 @@ async fn start_pong_server(
     .clone())
 }
 
+pub struct Config {
+    /// The addresses to listen on. All addresses are used if empty.
+    pub listen: Vec<IpAddr>,
+    /// Listening sockets which replace the ones bound for `listen`, such as those passed by
+    /// systemd socket activation. `port` should be the port of these sockets.
+    pub listeners: Vec<std::net::TcpListener>,
+    /// The TCP port.
+    pub port: u16,
+    /// The UDP port used for latency measurements.
+    pub udp_port: u16,
+    /// Respond to discovery requests from clients on the local network.
+    pub discovery: bool,
+    pub discovery_port: u16,
+    /// A label sent to clients discovering the server.
+    pub label: Option<String>,
+    /// The maximum number of concurrent clients.
+    pub max_clients: usize,
+    /// Allow use and discovery as a peer.
+    pub peer_server: bool,
+    /// Require clients to authenticate with this secret.
+    pub secret: Option<Secret>,
+    pub tls: Option<ServerTls>,
+    pub access: Access,
+    /// Serve Prometheus metrics over HTTP on this address.
+    pub metrics: Option<SocketAddr>,
+    /// Accept admin connections on this address.
+    pub admin: Option<SocketAddr>,
+    /// Require admin connections to authenticate with this secret.
+    /// Admin addresses which are not loopback addresses require one.
+    pub admin_secret: Option<Secret>,
+    pub log_format: LogFormat,
+    /// How long to wait for running tests to complete when shutting down.
+    pub shutdown_timeout: Duration,
+}
+
+/// How server messages are written.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum LogFormat {
+    /// Human readable text lines.
+    #[default]
+    Text,
+    /// One JSON record per line for each event. Text messages are included as `message` events.
+    Json,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: Vec::new(),
+            listeners: Vec::new(),
+            port: protocol::PORT,
+            udp_port: protocol::PORT,
+            discovery: true,
+            discovery_port: discovery::DISCOVER_PORT,
+            label: None,
+            max_clients: 1000,
+            peer_server: false,
+            secret: None,
+            tls: None,
+            access: Access::default(),
+            metrics: None,
+            admin: None,
+            admin_secret: None,
+            log_format: LogFormat::Text,
+            shutdown_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+async fn reload_access(state: Arc<State>) {
+    let mut interval = time::interval(Duration::from_secs(2));
+    loop {
+        interval.tick().await;
+        match state.access.reload() {
+            Ok(true) => (state.msg)("Reloaded access list"),
+            Ok(false) => (),
+            Err(error) => (state.msg)(&format!("Unable to reload access list: {:?}", error)),
+        }
+    }
+}
+
+/// Keeps the status shown by the service manager up to date.
+async fn report_status(state: Arc<State>) {
+    let mut interval = time::interval(Duration::from_secs(1));
+    let mut reported = String::new();
+    loop {
+        interval.tick().await;
+        let status = state.status();
+        if status != reported {
+            systemd::notify(&format!("STATUS={}", status)).ok();
+            reported = status;
+        }
+    }
+}
+
 async fn serve_async(
-    port: u16,
-    peer_server: bool,
+    mut config: Config,
     msg: Box<dyn Fn(&str) + Send + Sync>,
-) -> Result<(), anyhow::Error> {
+) -> Result<Arc<State>, anyhow::Error> {
+    if let Some(addr) = config.admin {
+        if !addr.ip().is_loopback() && config.admin_secret.is_none() {
+            bail!(
+                "An admin token is required to accept admin connections on {}, \
+                    which is not a loopback address",
+                addr
+            );
+        }
+    }
+
+    let activated = mem::take(&mut config.listeners);
+    let port = config.port;
+    let (msg, events) = match config.log_format {
+        LogFormat::Text => (msg, None),
+        LogFormat::Json => {
+            let events: Arc<dyn Fn(&str) + Send + Sync> = Arc::from(msg);
+            let events_ = events.clone();
+            let msg: Box<dyn Fn(&str) + Send + Sync> = Box::new(move |msg: &str| {
+                events_(
+                    &Event::Message {
+                        message: msg.to_owned(),
+                    }
+                    .to_json(),
+                )
+            });
+            (msg, Some(events))
+        }
+    };
     let state = Arc::new(State {
-        port,
+        udp_port: config.udp_port,
+        label: config.label,
+        max_clients: config.max_clients,
         started: Instant::now(),
         dummy_data: crate::common::data(),
-        clients: Mutex::new((0..SLOTS).map(|_| None).collect()),
+        clients: Mutex::new((0..config.max_clients).map(|_| None).collect()),
+        peers: Default::default(),
+        next_peer: AtomicU64::new(0),
         pong_servers: Default::default(),
         msg,
-        peer_server,
+        peer_server: config.peer_server,
+        secret: config.secret,
+        admin_secret: config.admin_secret,
+        tls: config.tls,
+        access: AccessControl::new(config.access)?,
+        metrics: Metrics::default(),
+        events,
+        shutting_down: AtomicBool::new(false),
+        listeners: Default::default(),
+        rejections: Default::default(),
     });
 
-    let v6 = Socket::new(Domain::IPV6, socket2::Type::STREAM, Some(Protocol::TCP))?;
-    v6.set_only_v6(true)?;
-    let v6: std::net::TcpStream = v6.into();
-    v6.set_nonblocking(true)?;
-    let v6 = TcpSocket::from_std_stream(v6);
-    v6.bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port))
-        .map_err(|error| {
-            if let ErrorKind::AddrInUse = error.kind() {
-                anyhow!(
-                    "Failed to bind TCP port, maybe another Crusader instance is already running"
-                )
-            } else {
-                error.into()
-            }
-        })?;
-    let v6 = v6.listen(1024)?;
+    if activated.is_empty() {
+        for ip in listen_ips(&config.listen) {
+            let listener = bind_tcp(SocketAddr::new(ip, port))?;
+            spawn_listener(&state, listener);
+        }
+    } else {
+        for listener in activated {
+            let listener = TcpListener::from_std(listener)?;
+            (state.msg)(&format!(
+                "Using socket {} from systemd",
+                listener.local_addr()?
+            ));
+            spawn_listener(&state, listener);
+        }
+    }
 
-    let v4 = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+    // Pong servers for specific addresses are bound upfront so unavailable addresses fail early.
+    // Otherwise they are bound on demand for the local address clients connect to.
+    for &ip in config.listen.iter().filter(|ip| !ip.is_unspecified()) {
+        let addr = SocketAddr::new(ip, state.udp_port);
+        start_pong_server(&state, addr)
+            .await
+            .with_context(|| format!("Failed to bind UDP socket {}", addr))?;
+    }
 
-    task::spawn(listen(state.clone(), v6));
-    task::spawn(listen(state.clone(), v4));
+    if let Some(addr) = config.metrics {
+        metrics::serve(state.clone(), addr)
+            .await
+            .with_context(|| format!("Failed to start metrics server on {}", addr))?;
+    }
 
-    if let Err(error) = discovery::serve(state.clone(), port) {
-        (state.msg)(&format!("Failed to run discovery: {:?}", error));
+    if let Some(addr) = config.admin {
+        admin::serve(state.clone(), addr)
+            .await
+            .with_context(|| format!("Failed to start admin server on {}", addr))?;
+    }
+
+    if config.discovery {
+        if let Err(error) =
+            discovery::serve(state.clone(), port, config.discovery_port, &config.listen)
+        {
+            (state.msg)(&format!("Failed to run discovery: {:?}", error));
+        }
     }
 
     (state.msg)(&format!("Server version {} running...", version()));
 
     for (name, ip) in interface_ips() {
-        (state.msg)(&format!("Address on `{name}`: {ip}"));
+        if config.listen.is_empty() || config.listen.contains(&ip) {
+            (state.msg)(&format!("Address on `{name}`: {ip}"));
+        }
     }
 
-    if peer_server {
+    if state.peer_server {
         (state.msg)("Server is in peer mode");
     }
 
-    Ok(())
+    if state.secret.is_some() {
+        (state.msg)("Server requires an authentication token");
+    }
+
+    if let Some(tls) = &state.tls {
+        (state.msg)(&format!(
+            "Server is using TLS, certificate fingerprint: {}",
+            tls::format_fingerprint(&tls.fingerprint())
+        ));
+    }
+
+    if state.access.is_enabled() {
+        (state.msg)("Server is restricted by an access list");
+    }
+
+    if state.access.file().is_some() {
+        task::spawn(reload_access(state.clone()));
+    }
+
+    if systemd::enabled() {
+        if let Err(error) = systemd::notify("READY=1") {
+            (state.msg)(&format!("Failed to notify systemd: {:?}", error));
+        }
+        task::spawn(report_status(state.clone()));
+    }
+
+    Ok(state)
 }
 
 pub fn serve_until(
-    port: u16,
-    peer_server: bool,
+    config: Config,
     msg: Box<dyn Fn(&str) + Send + Sync>,
     started: Box<dyn FnOnce(Result<(), String>) + Send>,
     done: Box<dyn FnOnce() + Send>,
@@ -690,8 +1366,8 @@ pub fn serve_until(
 
     thread::spawn(move || {
         rt.block_on(async move {
-            match serve_async(port, peer_server, msg).await {
-                Ok(()) => {
+            match serve_async(config, msg).await {
+                Ok(_) => {
                     started(Ok(()));
                     rx.await.ok();
                 }
@@ -705,20 +1381,67 @@ pub fn serve_until(
     Ok(tx)
 }
 
-pub fn serve(port: u16, peer_server: bool) -> Result<(), anyhow::Error> {
+/// Waits for Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() -> Result<(), anyhow::Error> {
+    #[cfg(target_family = "unix")]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+
+        let ctrl_c = signal::ctrl_c().fuse();
+        pin_mut!(ctrl_c);
+
+        let terminate = terminate.recv().fuse();
+        pin_mut!(terminate);
+
+        select! {
+            result = ctrl_c => result?,
+            _ = terminate => (),
+        }
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    signal::ctrl_c().await?;
+
+    Ok(())
+}
+
+pub fn serve(config: Config) -> Result<(), anyhow::Error> {
+    let json = config.log_format == LogFormat::Json;
+    let format = move |msg: &str| {
+        if json {
+            msg.to_owned()
+        } else {
+            with_time(msg)
+        }
+    };
+    let shutdown_timeout = config.shutdown_timeout;
     let rt = tokio::runtime::Runtime::new()?;
     rt.block_on(async move {
-        serve_async(
-            port,
-            peer_server,
-            Box::new(|msg: &str| {
-                let msg = msg.to_owned();
-                task::spawn_blocking(move || println!("{}", with_time(&msg)));
+        let state = serve_async(
+            config,
+            Box::new(move |msg: &str| {
+                let msg = format(msg);
+                task::spawn_blocking(move || println!("{}", msg));
             }),
         )
         .await?;
-        signal::ctrl_c().await?;
-        println!("{}", with_time("Server aborting..."));
+
+        shutdown_signal().await?;
+        (state.msg)("Server shutting down...");
+
+        let drain = state.drain(shutdown_timeout).fuse();
+        pin_mut!(drain);
+
+        let signal = shutdown_signal().fuse();
+        pin_mut!(signal);
+
+        select! {
+            _ = drain => (state.msg)("Server stopped"),
+            result = signal => {
+                result?;
+                (state.msg)("Server aborting...");
+            }
+        }
         Ok(())
     })
 }
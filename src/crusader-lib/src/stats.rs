@@ -0,0 +1,221 @@
+//! Statistics over many results, such as results collected over weeks of monitoring.
+
+use chrono::{DateTime, FixedOffset};
+use std::fmt::Write;
+
+use crate::file_format::TestKind;
+use crate::info::{summaries, PhaseSummary};
+use crate::plot::TestResult;
+
+/// The summary of a single result.
+pub struct Sample {
+    pub name: String,
+    /// The start time of the test, which is only stored in newer result files.
+    pub start_time: Option<DateTime<FixedOffset>>,
+    pub phases: Vec<PhaseSummary>,
+}
+
+impl Sample {
+    pub fn new(name: String, result: &TestResult) -> Self {
+        Sample {
+            name,
+            start_time: result
+                .raw_result
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.start_time()),
+            phases: summaries(result),
+        }
+    }
+
+    pub fn phase(&self, kind: Option<TestKind>) -> Option<&PhaseSummary> {
+        self.phases.iter().find(|phase| phase.kind == kind)
+    }
+}
+
+/// Sorts samples by their start time, with samples without one last.
+pub fn sort_by_time(samples: &mut [Sample]) {
+    samples.sort_by_key(|sample| (sample.start_time.is_none(), sample.start_time));
+}
+
+/// The spread of a set of values.
+pub struct Distribution {
+    pub count: usize,
+    pub min: f64,
+    pub lower_quartile: f64,
+    pub median: f64,
+    pub upper_quartile: f64,
+    pub max: f64,
+}
+
+impl Distribution {
+    /// Returns `None` if there are no values. Quartiles are interpolated between values.
+    pub fn new(mut values: Vec<f64>) -> Option<Self> {
+        values.sort_unstable_by(f64::total_cmp);
+        let (min, max) = (*values.first()?, *values.last()?);
+        let quantile = |q: f64| {
+            let position = q * (values.len() - 1) as f64;
+            let (lower, upper) = (position.floor() as usize, position.ceil() as usize);
+            values[lower] + (values[upper] - values[lower]) * (position - lower as f64)
+        };
+        Some(Distribution {
+            count: values.len(),
+            min,
+            lower_quartile: quantile(0.25),
+            median: quantile(0.5),
+            upper_quartile: quantile(0.75),
+            max,
+        })
+    }
+}
+
+/// A value of the summary of a test which is tracked over time.
+pub struct Metric {
+    pub label: &'static str,
+    pub value: fn(&PhaseSummary) -> Option<f64>,
+}
+
+pub const METRICS: &[Metric] = &[
+    Metric {
+        label: "Throughput (Mbps)",
+        value: |s| s.throughput,
+    },
+    Metric {
+        label: "Latency (ms)",
+        value: |s| s.latency.map(|latency| latency.total),
+    },
+    Metric {
+        label: "Median latency (ms)",
+        value: |s| s.median_latency,
+    },
+];
+
+pub const KINDS: [Option<TestKind>; 4] = [
+    Some(TestKind::Download),
+    Some(TestKind::Upload),
+    Some(TestKind::Bidirectional),
+    None,
+];
+
+fn short_name(kind: Option<TestKind>) -> &'static str {
+    match kind {
+        Some(TestKind::Download) => "Down",
+        Some(TestKind::Upload) => "Up",
+        Some(TestKind::Bidirectional) => "Bidir",
+        None => "Idle",
+    }
+}
+
+/// Returns the distribution of each metric of each test, followed by a table with the throughput
+/// and latency of each sample in the order given.
+pub fn report(samples: &[Sample]) -> Result<String, anyhow::Error> {
+    let mut o = String::new();
+
+    let label_width = 22;
+    let width = 10;
+
+    let dated: Vec<_> = samples
+        .iter()
+        .filter_map(|sample| sample.start_time)
+        .collect();
+    write!(&mut o, "{} results", samples.len())?;
+    if let (Some(first), Some(last)) = (dated.iter().min(), dated.iter().max()) {
+        write!(
+            &mut o,
+            " from {} to {}",
+            first.format("%Y-%m-%d %H:%M"),
+            last.format("%Y-%m-%d %H:%M")
+        )?;
+    }
+    writeln!(&mut o)?;
+
+    let kinds: Vec<_> = KINDS
+        .into_iter()
+        .filter(|&kind| samples.iter().any(|sample| sample.phase(kind).is_some()))
+        .collect();
+
+    for &kind in &kinds {
+        writeln!(&mut o)?;
+        writeln!(
+            &mut o,
+            "-- {} test --",
+            kind.map(|kind| kind.name()).unwrap_or("Idle")
+        )?;
+        write!(&mut o, "{:>label_width$}{:>width$}", "", "Results")?;
+        for column in ["Min", "Q1", "Median", "Q3", "Max"] {
+            write!(&mut o, "{:>width$}", column)?;
+        }
+        writeln!(&mut o)?;
+
+        for metric in METRICS {
+            let values = samples
+                .iter()
+                .filter_map(|sample| sample.phase(kind).and_then(metric.value))
+                .collect();
+            if let Some(distribution) = Distribution::new(values) {
+                writeln!(
+                    &mut o,
+                    "{:>label_width$}{:>width$}{:>width$.02}{:>width$.02}{:>width$.02}{:>width$.02}{:>width$.02}",
+                    metric.label,
+                    distribution.count,
+                    distribution.min,
+                    distribution.lower_quartile,
+                    distribution.median,
+                    distribution.upper_quartile,
+                    distribution.max,
+                )?;
+            }
+        }
+    }
+
+    let width = 12;
+
+    writeln!(&mut o)?;
+    writeln!(&mut o, "-- Trend --")?;
+    write!(&mut o, "{:<16}", "Start")?;
+    for &kind in &kinds {
+        if kind.is_some() {
+            write!(&mut o, "{:>width$}", format!("{} Mbps", short_name(kind)))?;
+        }
+        write!(&mut o, "{:>width$}", format!("{} ms", short_name(kind)))?;
+    }
+    writeln!(&mut o, "  Name")?;
+
+    for sample in samples {
+        write!(
+            &mut o,
+            "{:<16}",
+            sample
+                .start_time
+                .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or("-".to_owned())
+        )?;
+        for &kind in &kinds {
+            let phase = sample.phase(kind);
+            let cell = |value: Option<f64>| {
+                value
+                    .map(|value| format!("{:.02}", value))
+                    .unwrap_or("-".to_owned())
+            };
+            if kind.is_some() {
+                write!(
+                    &mut o,
+                    "{:>width$}",
+                    cell(phase.and_then(|phase| phase.throughput))
+                )?;
+            }
+            write!(
+                &mut o,
+                "{:>width$}",
+                cell(
+                    phase
+                        .and_then(|phase| phase.latency)
+                        .map(|latency| latency.total)
+                )
+            )?;
+        }
+        writeln!(&mut o, "  {}", sample.name)?;
+    }
+
+    Ok(o)
+}
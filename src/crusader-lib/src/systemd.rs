@@ -0,0 +1,94 @@
+//! Readiness notifications and socket activation for servers managed by systemd.
+//! These are no-ops on other platforms.
+
+use std::net::TcpListener;
+
+#[cfg(target_os = "linux")]
+use anyhow::{anyhow, bail};
+#[cfg(target_os = "linux")]
+use socket2::Socket;
+#[cfg(target_os = "linux")]
+use std::{
+    env, io,
+    os::fd::{FromRawFd, RawFd},
+    os::linux::net::SocketAddrExt,
+    os::unix::net::{SocketAddr, UnixDatagram},
+};
+
+/// The first file descriptor passed with socket activation.
+#[cfg(target_os = "linux")]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Returns `true` if the service manager expects notifications.
+pub(crate) fn enabled() -> bool {
+    cfg!(target_os = "linux") && std::env::var_os("NOTIFY_SOCKET").is_some()
+}
+
+/// Sends newline separated state assignments such as `READY=1` to the service manager
+/// listening on `NOTIFY_SOCKET`.
+#[cfg(target_os = "linux")]
+pub(crate) fn notify(state: &str) -> Result<(), anyhow::Error> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let path = path.into_encoded_bytes();
+    let addr = match path.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(std::str::from_utf8(&path)?)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn notify(_state: &str) -> Result<(), anyhow::Error> {
+    Ok(())
+}
+
+/// Takes the listening TCP sockets passed with socket activation through `LISTEN_FDS`.
+/// The variables are removed so they are not inherited by child processes.
+/// This changes the environment, so it must be called before any other threads are started.
+#[cfg(target_os = "linux")]
+pub fn listeners() -> Result<Vec<TcpListener>, anyhow::Error> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(Vec::new());
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        // The sockets were meant for another process
+        return Ok(Vec::new());
+    }
+    let fds: RawFd = fds
+        .parse()
+        .map_err(|_| anyhow!("Invalid LISTEN_FDS value `{}`", fds))?;
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + fds)
+        .map(|fd| {
+            // SAFETY: The service manager passes ownership of these descriptors to us
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            let is_tcp = socket.r#type()? == socket2::Type::STREAM
+                && socket.local_addr()?.as_socket().is_some();
+            if !is_tcp {
+                bail!("Socket {} passed by systemd is not a TCP socket", fd);
+            }
+            // SAFETY: `fd` is a valid descriptor owned by `socket`
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+                return Err(io::Error::last_os_error().into());
+            }
+            let listener: TcpListener = socket.into();
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn listeners() -> Result<Vec<TcpListener>, anyhow::Error> {
+    Ok(Vec::new())
+}
@@ -1,16 +1,20 @@
+use crate::auth::Secret;
 use crate::common::{
-    connect, data, fresh_socket_addr, hello, measure_latency, ping_recv, ping_send, read_data,
-    wait_for_state, write_data, Config, Msg, TestState,
+    authenticated, challenge_response, check_negotiation, check_server_hello, client_negotiation,
+    connect, data, fresh_socket_addr, hello, interface_ips, measure_latency, ping_recv, ping_send,
+    read_data, wait_for_state, write_data, Config, Msg, TestState,
 };
 use crate::file_format::{
-    RawConfig, RawHeader, RawPing, RawPoint, RawResult, RawStream, RawStreamGroup, TestData,
-    TestKind,
+    RawConfig, RawHeader, RawMetadata, RawPing, RawPoint, RawResult, RawStream, RawStreamGroup,
+    TestData, TestKind,
 };
 use crate::peer::connect_to_peer;
 use crate::plot::save_graph;
 use crate::protocol::{
-    codec, receive, send, ClientMessage, Hello, RawLatency, ServerMessage, TestStream,
+    codec, receive, send, ClientMessage, Hello, Negotiate, RawLatency, ServerMessage, Session,
+    TestStream,
 };
+use crate::tls::{self, Connection};
 use crate::{discovery, version, with_time};
 use anyhow::{anyhow, bail, Context};
 use bytes::{Bytes, BytesMut};
@@ -19,6 +23,7 @@ use futures::{select, Sink, Stream};
 use futures::{stream, StreamExt};
 use parking_lot::Mutex;
 use std::collections::HashMap;
+use std::env;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread;
@@ -28,7 +33,7 @@ use std::{
     sync::Arc,
     time::Duration,
 };
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{self as tokio_io, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{channel, Sender};
 use tokio::sync::{oneshot, watch, Semaphore};
@@ -54,20 +59,24 @@ struct State {
 
 async fn hello_combined<S: Sink<Bytes> + Stream<Item = Result<BytesMut, S::Error>> + Unpin>(
     stream: &mut S,
+    secret: Option<Secret>,
 ) -> Result<(), anyhow::Error>
 where
     S::Error: Error + Send + Sync + 'static,
 {
-    let hello = Hello::new();
-
-    send(stream, &hello).await?;
+    send(stream, &Hello::new()).await?;
     let server_hello: Hello = receive(stream).await?;
+    check_server_hello(&server_hello)?;
 
-    if hello != server_hello {
-        panic!(
-            "Mismatched server hello, got {:?}, expected {:?}",
-            server_hello, hello
-        );
+    let server: Negotiate = receive(stream).await?;
+    let ours = client_negotiation(secret);
+    send(stream, &ours).await?;
+    check_negotiation(&ours, &server)?;
+
+    let challenge: ServerMessage = receive(stream).await?;
+    if let Some(response) = challenge_response(challenge, secret)? {
+        send(stream, &response).await?;
+        authenticated(receive(stream).await?, secret)?;
     }
 
     Ok(())
@@ -92,44 +101,67 @@ pub(crate) async fn test_async(
 ) -> Result<RawResult, anyhow::Error> {
     msg(&format!("Client version {} running", version()));
 
+    let host = server;
+    let mut server_hostname = host
+        .filter(|host| host.parse::<IpAddr>().is_err())
+        .map(|host| host.to_owned());
+
     let control = if let Some(server) = server {
         connect((server, config.port), "server").await?
     } else {
-        let server = discovery::locate(false).await?;
+        let server = discovery::locate(false, config.lowest_latency, config.discovery_port).await?;
         msg(&format!(
             "Found server at {} running version {}",
             server.at, server.software_version
         ));
+        server_hostname = server.hostname;
         connect(server.socket, "server").await?
     };
 
     control.set_nodelay(true)?;
 
+    let local = control.local_addr()?.ip();
     let server = control.peer_addr()?;
     let server = fresh_socket_addr(server, server.port());
 
     msg(&format!("Connected to server {}", server));
 
-    let (rx, tx) = control.into_split();
+    let tls = config
+        .tls
+        .map(|verification| tls::Connector::new(verification, host, server.ip()))
+        .transpose()?;
+    let control = tls::connect(control, tls.as_ref())
+        .await
+        .context("Failed to connect to server")?;
+
+    let (rx, tx) = tokio_io::split(control);
     let mut control_rx = FramedRead::new(rx, codec());
     let mut control_tx = FramedWrite::new(tx, codec());
 
-    hello(&mut control_tx, &mut control_rx)
+    let negotiated = hello(&mut control_tx, &mut control_rx, config.secret)
         .await
         .context("Failed protocol handshake")?;
 
     send(&mut control_tx, &ClientMessage::NewClient).await?;
 
     let setup_start = Instant::now();
+    let setup_time = chrono::Local::now();
 
     let reply: ServerMessage = receive(&mut control_rx)
         .await
         .context("Failed to create a new client id")?;
-    let id = match reply {
-        ServerMessage::NewClient(Some(id)) => id,
-        ServerMessage::NewClient(None) => bail!("Server was unable to create client"),
+    let (session, udp_port) = match reply {
+        ServerMessage::NewClient {
+            session: Some(session),
+            udp_port,
+        } => (session, udp_port),
+        ServerMessage::NewClient { session: None, .. } => {
+            bail!("Server was unable to create client")
+        }
+        ServerMessage::ShuttingDown => bail!("Server is shutting down"),
         _ => bail!("Unexpected message {:?}", reply),
     };
+    let ping_server = fresh_socket_addr(server, udp_port);
 
     let loading_streams: u32 = config.streams.try_into()?;
 
@@ -142,7 +174,17 @@ pub(crate) async fn test_async(
     let estimated_duration = load_duration * loads + grace * 2;
 
     let mut peer = if let Some(peer) = latency_peer_server {
-        Some(connect_to_peer(config, server, peer, estimated_duration, msg.clone()).await?)
+        Some(
+            connect_to_peer(
+                config,
+                server,
+                server_hostname.as_deref(),
+                peer,
+                estimated_duration,
+                msg.clone(),
+            )
+            .await?,
+        )
     } else {
         None
     };
@@ -156,11 +198,11 @@ pub(crate) async fn test_async(
     let mut ping_index = 0;
 
     let (latency, server_time_offset, mut control_rx) = measure_latency(
-        id,
+        session,
         &mut ping_index,
         &mut control_tx,
         control_rx,
-        server,
+        ping_server,
         local_udp,
         setup_start,
     )
@@ -172,7 +214,7 @@ pub(crate) async fn test_async(
     ));
 
     let udp_socket = Arc::new(net::UdpSocket::bind(local_udp).await?);
-    udp_socket.connect(server).await?;
+    udp_socket.connect(ping_server).await?;
     let udp_socket2 = udp_socket.clone();
 
     let data = Arc::new(data());
@@ -193,10 +235,11 @@ pub(crate) async fn test_async(
         loader_count += config.streams;
         upload_loaders(
             all_loaders.clone(),
-            id,
+            session,
             server,
             0,
             config,
+            tls.clone(),
             Duration::ZERO,
             data.clone(),
             state_rx.clone(),
@@ -209,10 +252,11 @@ pub(crate) async fn test_async(
         loader_count += config.streams;
         upload_loaders(
             all_loaders.clone(),
-            id,
+            session,
             server,
             1,
             config,
+            tls.clone(),
             config.stream_stagger / 2,
             data.clone(),
             state_rx.clone(),
@@ -226,10 +270,11 @@ pub(crate) async fn test_async(
         download_loaders(
             state.clone(),
             all_loaders.clone(),
-            id,
+            session,
             server,
             2,
             config,
+            tls.clone(),
             setup_start,
             state_rx.clone(),
             TestState::LoadFromServer,
@@ -241,10 +286,11 @@ pub(crate) async fn test_async(
         download_loaders(
             state.clone(),
             all_loaders.clone(),
-            id,
+            session,
             server,
             3,
             config,
+            tls.clone(),
             setup_start,
             state_rx.clone(),
             TestState::LoadFromBoth,
@@ -264,10 +310,12 @@ pub(crate) async fn test_async(
     let (scheduled_load_tx, mut scheduled_load_rx) = channel(4);
 
     let state_ = state.clone();
+    let msg_ = msg.clone();
     let measures = tokio::spawn(async move {
         let mut throughput = Vec::new();
         let mut latencies = Vec::new();
         let overload_;
+        let concurrent_load_;
 
         loop {
             let reply: ServerMessage = receive(&mut control_rx).await?;
@@ -293,8 +341,12 @@ pub(crate) async fn test_async(
                 ServerMessage::LatencyMeasures(measures) => {
                     latencies.extend(measures.into_iter());
                 }
-                ServerMessage::MeasurementsDone { overload } => {
+                ServerMessage::MeasurementsDone {
+                    overload,
+                    concurrent_load,
+                } => {
                     overload_ = overload;
+                    concurrent_load_ = concurrent_load;
                     break;
                 }
                 ServerMessage::LoadComplete { stream } => {
@@ -314,11 +366,14 @@ pub(crate) async fn test_async(
                         })
                         .await?
                 }
+                ServerMessage::ShuttingDown => {
+                    msg_("Server is shutting down, the test will be completed");
+                }
                 _ => bail!("Unexpected message {:?}", reply),
             };
         }
 
-        Ok((latencies, throughput, overload_))
+        Ok((latencies, throughput, overload_, concurrent_load_))
     });
 
     if let Some(peer) = peer.as_mut() {
@@ -328,7 +383,7 @@ pub(crate) async fn test_async(
     let ping_start_index = ping_index;
     let ping_send = tokio::spawn(ping_send(
         ping_index,
-        id,
+        session,
         state_rx.clone(),
         setup_start,
         udp_socket2.clone(),
@@ -483,7 +538,7 @@ pub(crate) async fn test_async(
 
     let mut pongs = ping_recv.await??;
 
-    let (mut latencies, throughput, server_overload) = measures.await??;
+    let (mut latencies, throughput, server_overload, concurrent_load) = measures.await??;
 
     let server_overload = server_overload || peer.as_ref().map(|p| p.0).unwrap_or_default();
 
@@ -628,6 +683,7 @@ pub(crate) async fn test_async(
         ipv6: server.is_ipv6(),
         load_termination_timeout,
         server_overload,
+        concurrent_load,
         server_latency: latency,
         start,
         duration,
@@ -635,6 +691,24 @@ pub(crate) async fn test_async(
         pings,
         peer_pings: peer_latencies,
         test_data,
+        tls: tls.is_some(),
+        metadata: Some(RawMetadata {
+            server_address: server.ip().to_string(),
+            server_port: server.port(),
+            server_hostname,
+            server_version: negotiated.software_version,
+            client_os: format!("{} {}", env::consts::OS, env::consts::ARCH),
+            interface: interface_ips()
+                .into_iter()
+                .find(|(_, ip)| *ip == local)
+                .map(|(name, _)| name),
+            local_address: local.to_string(),
+            start_time: (setup_time + start).to_rfc3339(),
+            streams: config.streams,
+            notes: None,
+            tags: Vec::new(),
+        }),
+        trim: None,
     };
 
     Ok(raw_result)
@@ -653,20 +727,24 @@ pub fn save_raw(
 }
 
 fn setup_loaders(
-    id: u64,
+    session: Session,
     server: SocketAddr,
     count: u64,
-) -> Vec<JoinHandle<Result<Framed<TcpStream, LengthDelimitedCodec>, anyhow::Error>>> {
+    secret: Option<Secret>,
+    tls: Option<tls::Connector>,
+) -> Vec<JoinHandle<Result<Framed<Connection, LengthDelimitedCodec>, anyhow::Error>>> {
     (0..count)
         .map(|_| {
+            let tls = tls.clone();
             tokio::spawn(async move {
                 let stream = TcpStream::connect(server)
                     .await
                     .context("Failed connect to server for throughput connection")?;
                 stream.set_nodelay(true)?;
+                let stream = tls::connect(stream, tls.as_ref()).await?;
                 let mut stream = Framed::new(stream, codec());
-                hello_combined(&mut stream).await?;
-                send(&mut stream, &ClientMessage::Associate(id)).await?;
+                hello_combined(&mut stream, secret).await?;
+                send(&mut stream, &ClientMessage::Associate(session)).await?;
 
                 Ok(stream)
             })
@@ -676,17 +754,18 @@ fn setup_loaders(
 
 fn upload_loaders(
     all_loaders: Arc<Semaphore>,
-    id: u64,
+    session: Session,
     server: SocketAddr,
     group: u32,
     config: Config,
+    tls: Option<tls::Connector>,
     stagger_offset: Duration,
     data: Arc<Vec<u8>>,
     state_rx: watch::Receiver<(TestState, Instant)>,
     state: TestState,
     done: Sender<TestStream>,
 ) {
-    let loaders = setup_loaders(id, server, config.streams);
+    let loaders = setup_loaders(session, server, config.streams, config.secret, tls);
 
     for (i, loader) in loaders.into_iter().enumerate() {
         let mut state_rx = state_rx.clone();
@@ -722,17 +801,7 @@ fn upload_loaders(
             send(&mut stream, &ClientMessage::SendByte).await?;
 
             // Wait for a pending read byte
-            {
-                let mut stream_rx = stream.get_mut().split().0;
-                loop {
-                    let _ = stream_rx.read(&mut []).await?;
-                    match time::timeout(Duration::from_millis(10), stream_rx.peek(&mut [0])).await {
-                        Ok(Ok(1)) => break,
-                        Err(_) | Ok(Ok(_)) => (),
-                        Ok(Err(err)) => panic!("{:?}", err),
-                    }
-                }
-            }
+            stream.get_mut().wait_for_byte().await.unwrap();
 
             all_loaders.add_permits(1);
 
@@ -776,10 +845,11 @@ async fn wait_on_download_loaders(
 fn download_loaders(
     state: Arc<State>,
     all_loaders: Arc<Semaphore>,
-    id: u64,
+    session: Session,
     server: SocketAddr,
     group: u32,
     config: Config,
+    tls: Option<tls::Connector>,
     setup_start: Instant,
     state_rx: watch::Receiver<(TestState, Instant)>,
     test_state: TestState,
@@ -788,7 +858,7 @@ fn download_loaders(
     Vec<JoinHandle<Result<Vec<(u64, u64)>, anyhow::Error>>>,
 ) {
     let semaphore = Arc::new(Semaphore::new(0));
-    let loaders = setup_loaders(id, server, config.streams);
+    let loaders = setup_loaders(session, server, config.streams, config.secret, tls);
 
     let loaders = loaders
         .into_iter()
@@ -829,6 +899,7 @@ fn download_loaders(
                 };
 
                 stream.get_mut().write_u8(1).await?;
+                stream.get_mut().flush().await?;
 
                 let reply: ServerMessage = receive(&mut stream).await?;
                 match reply {
@@ -927,6 +998,8 @@ pub fn test(
     host: Option<&str>,
     latency_peer_server: Option<Option<&str>>,
     out_name: &str,
+    notes: Option<String>,
+    tags: Vec<String>,
 ) -> Result<(), anyhow::Error> {
     let rt = tokio::runtime::Runtime::new().unwrap();
     let result = rt.block_on(test_async(
@@ -935,13 +1008,14 @@ pub fn test(
         latency_peer_server,
         Arc::new(|msg| println!("{}", with_time(msg))),
     ));
-    let result = match result {
+    let mut result = match result {
         Ok(result) => result,
         Err(error) => {
             println!("{}", with_time(&format!("Client failed")));
             return Err(error);
         }
     };
+    result.annotate(notes, tags);
     let out_name = timed(out_name);
     let test_result = result.to_test_result();
     print!("\n{}", test_result.summary()?);
@@ -0,0 +1,321 @@
+use anyhow::{anyhow, bail, Context};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{self, BufReader};
+use std::net::IpAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use std::{fmt::Write, fs::File};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+/// A TCP connection to or from a server, optionally using TLS.
+pub(crate) enum Connection {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Connection {
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        match self {
+            Connection::Tcp(stream) => stream,
+            Connection::Tls(stream) => stream.get_ref().0,
+        }
+    }
+
+    /// Waits until a byte is available to read. The byte is consumed when using TLS.
+    pub(crate) async fn wait_for_byte(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => loop {
+                let _ = stream.read(&mut []).await?;
+                match time::timeout(Duration::from_millis(10), stream.peek(&mut [0])).await {
+                    Ok(Ok(1)) => return Ok(()),
+                    Err(_) | Ok(Ok(_)) => (),
+                    Ok(Err(err)) => return Err(err),
+                }
+            },
+            Connection::Tls(stream) => stream.read_u8().await.map(|_| ()),
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// How a client verifies the certificate of a TLS server.
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Verification {
+    /// Verify the certificate chain against the Mozilla root certificates.
+    WebPki,
+    /// Only accept a certificate with this SHA-256 fingerprint.
+    Fingerprint([u8; 32]),
+}
+
+impl Verification {
+    /// Pins the certificate stored in a PEM file.
+    pub fn from_certificate(path: &Path) -> Result<Self, anyhow::Error> {
+        let certs = load_certs(path)?;
+        Ok(Verification::Fingerprint(fingerprint(&certs[0])))
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, anyhow::Error> {
+    let file = File::open(path)
+        .with_context(|| format!("Unable to open certificate `{}`", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Unable to read certificate `{}`", path.display()))?;
+    if certs.is_empty() {
+        bail!("No certificates found in `{}`", path.display());
+    }
+    Ok(certs)
+}
+
+fn fingerprint(cert: &CertificateDer<'_>) -> [u8; 32] {
+    Sha256::digest(cert.as_ref()).into()
+}
+
+pub fn format_fingerprint(fingerprint: &[u8; 32]) -> String {
+    let mut result = String::with_capacity(64);
+    for byte in fingerprint {
+        write!(result, "{:02x}", byte).unwrap();
+    }
+    result
+}
+
+/// Parses a hex encoded SHA-256 fingerprint. Colons and spaces between bytes are ignored.
+pub fn parse_fingerprint(fingerprint: &str) -> Result<[u8; 32], anyhow::Error> {
+    let digits: Vec<u8> = fingerprint
+        .bytes()
+        .filter(|c| *c != b':' && !c.is_ascii_whitespace())
+        .collect();
+    let invalid = || anyhow!("Invalid fingerprint, expected 64 hex digits");
+    if digits.len() != 64 {
+        return Err(invalid());
+    }
+    let mut result = [0; 32];
+    for (byte, pair) in result.iter_mut().zip(digits.chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(result)
+}
+
+/// The certificate and key used by a TLS server.
+#[derive(Clone)]
+pub struct ServerTls {
+    acceptor: TlsAcceptor,
+    fingerprint: [u8; 32],
+}
+
+impl ServerTls {
+    pub fn load(cert: &Path, key: &Path) -> Result<Self, anyhow::Error> {
+        let certs = load_certs(cert)?;
+        let file =
+            File::open(key).with_context(|| format!("Unable to open key `{}`", key.display()))?;
+        let key = rustls_pemfile::private_key(&mut BufReader::new(file))
+            .with_context(|| format!("Unable to read key `{}`", key.display()))?
+            .ok_or_else(|| anyhow!("No private key found in `{}`", key.display()))?;
+        let fingerprint = fingerprint(&certs[0]);
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("Invalid certificate or key")?;
+        Ok(ServerTls {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            fingerprint,
+        })
+    }
+
+    /// The SHA-256 fingerprint of the server certificate.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        self.fingerprint
+    }
+}
+
+pub(crate) async fn accept(
+    stream: TcpStream,
+    tls: Option<&ServerTls>,
+) -> Result<Connection, anyhow::Error> {
+    Ok(match tls {
+        Some(tls) => Connection::Tls(Box::new(TlsStream::Server(
+            tls.acceptor
+                .accept(stream)
+                .await
+                .context("TLS handshake failed")?,
+        ))),
+        None => Connection::Tcp(stream),
+    })
+}
+
+#[derive(Debug)]
+struct FingerprintVerifier {
+    fingerprint: [u8; 32],
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "Server certificate fingerprint {} does not match the pinned fingerprint",
+                format_fingerprint(&fingerprint(end_entity))
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Establishes TLS sessions to a single server.
+#[derive(Clone)]
+pub(crate) struct Connector {
+    connector: TlsConnector,
+    name: ServerName<'static>,
+}
+
+impl Connector {
+    /// `host` is the name used to verify the server certificate. The IP address is used
+    /// if it is not a valid DNS name.
+    pub(crate) fn new(
+        verification: Verification,
+        host: Option<&str>,
+        ip: IpAddr,
+    ) -> Result<Self, anyhow::Error> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?;
+        let config = match verification {
+            Verification::WebPki => {
+                let roots = RootCertStore {
+                    roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+                };
+                builder.with_root_certificates(roots)
+            }
+            Verification::Fingerprint(fingerprint) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(FingerprintVerifier {
+                    fingerprint,
+                    provider: provider(),
+                })),
+        }
+        .with_no_client_auth();
+        let name = host
+            .and_then(|host| ServerName::try_from(host.to_owned()).ok())
+            .unwrap_or(ServerName::IpAddress(ip.into()));
+        Ok(Connector {
+            connector: TlsConnector::from(Arc::new(config)),
+            name,
+        })
+    }
+
+    pub(crate) async fn connect(&self, stream: TcpStream) -> Result<Connection, anyhow::Error> {
+        Ok(Connection::Tls(Box::new(TlsStream::Client(
+            self.connector
+                .connect(self.name.clone(), stream)
+                .await
+                .context("TLS handshake failed")?,
+        ))))
+    }
+}
+
+pub(crate) async fn connect(
+    stream: TcpStream,
+    tls: Option<&Connector>,
+) -> Result<Connection, anyhow::Error> {
+    match tls {
+        Some(tls) => tls.connect(stream).await,
+        None => Ok(Connection::Tcp(stream)),
+    }
+}
@@ -1,21 +1,39 @@
+mod serve_config;
+mod serve_ctl;
+
 use anyhow::Context;
 use clap::{Parser, Subcommand};
 use clap_num::si_number;
 #[cfg(feature = "client")]
 use crusader_lib::file_format::RawResult;
 #[cfg(feature = "client")]
-use crusader_lib::test::PlotConfig;
-use crusader_lib::{protocol, version};
-#[cfg(feature = "client")]
-use crusader_lib::{with_time, Config};
+use crusader_lib::test::{timed, PlotConfig};
+use crusader_lib::version;
 #[cfg(feature = "client")]
+use crusader_lib::{
+    auth::Secret,
+    compare::{self, Run},
+    discovery, export,
+    info::ResultInfo,
+    protocol,
+    stats::{self, Sample},
+    tls::{parse_fingerprint, Verification},
+    with_time, Config,
+};
+use crusader_lib::{serve, systemd};
+use serve_config::ServeArgs;
+use serve_ctl::ServeCtlArgs;
 use std::path::PathBuf;
 use std::process;
 #[cfg(feature = "client")]
 use {
-    anyhow::anyhow,
-    std::fs::OpenOptions,
+    clap::ValueEnum,
+    serde::Serialize,
+    std::ffi::OsStr,
+    std::fs::{self, File, OpenOptions},
+    std::io,
     std::io::{BufWriter, Write},
+    std::net::IpAddr,
     std::path::Path,
     std::time::Duration,
 };
@@ -66,109 +84,350 @@ impl PlotArgs {
     }
 }
 
+#[derive(clap::Args)]
+struct TlsArgs {
+    #[arg(
+        long,
+        help = "Use TLS and verify the server certificate against the Mozilla root certificates"
+    )]
+    tls: bool,
+    #[arg(
+        long,
+        value_name = "SHA256",
+        conflicts_with = "tls_cert",
+        help = "Use TLS and only accept a server certificate with this fingerprint"
+    )]
+    tls_fingerprint: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Use TLS and only accept the server certificate stored in this PEM file"
+    )]
+    tls_cert: Option<PathBuf>,
+}
+
+#[cfg(feature = "client")]
+fn verification(
+    tls: bool,
+    fingerprint: Option<&str>,
+    cert: Option<&std::path::Path>,
+) -> Result<Option<Verification>, anyhow::Error> {
+    Ok(if let Some(fingerprint) = fingerprint {
+        Some(Verification::Fingerprint(parse_fingerprint(fingerprint)?))
+    } else if let Some(cert) = cert {
+        Some(Verification::from_certificate(cert)?)
+    } else {
+        tls.then_some(Verification::WebPki)
+    })
+}
+
+impl TlsArgs {
+    #[cfg(feature = "client")]
+    fn verification(&self) -> Result<Option<Verification>, anyhow::Error> {
+        verification(
+            self.tls,
+            self.tls_fingerprint.as_deref(),
+            self.tls_cert.as_deref(),
+        )
+    }
+}
+
+/// How the latency peer is verified. This is separate from the server as the peer uses
+/// another certificate.
+#[derive(clap::Args)]
+struct PeerTlsArgs {
+    #[arg(
+        long,
+        help = "Use TLS for the latency peer and verify its certificate against the Mozilla root certificates"
+    )]
+    peer_tls: bool,
+    #[arg(
+        long,
+        value_name = "SHA256",
+        conflicts_with = "peer_tls_cert",
+        help = "Use TLS for the latency peer and only accept a certificate with this fingerprint"
+    )]
+    peer_tls_fingerprint: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Use TLS for the latency peer and only accept the certificate stored in this PEM file"
+    )]
+    peer_tls_cert: Option<PathBuf>,
+}
+
+impl PeerTlsArgs {
+    #[cfg(feature = "client")]
+    fn verification(&self) -> Result<Option<Verification>, anyhow::Error> {
+        verification(
+            self.peer_tls,
+            self.peer_tls_fingerprint.as_deref(),
+            self.peer_tls_cert.as_deref(),
+        )
+    }
+}
+
+#[cfg(feature = "client")]
+#[derive(clap::Args)]
+struct TestArgs {
+    server: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "server",
+        long_help = "When locating a local server, use the one with the lowest latency \
+            instead of the first one to reply"
+    )]
+    lowest_latency: bool,
+    #[arg(
+        long,
+        default_value_t = discovery::DISCOVER_PORT,
+        help = "The UDP port used to locate a local server or peer"
+    )]
+    discovery_port: u16,
+    #[arg(long, help = "Run a download test")]
+    download: bool,
+    #[arg(long, help = "Run an upload test")]
+    upload: bool,
+    #[arg(long, help = "Run a test doing both download and upload")]
+    bidirectional: bool,
+    #[arg(
+        long,
+        long_help = "Run a test only measuring latency. The duration is specified by `grace_duration`"
+    )]
+    idle: bool,
+    #[arg(long, default_value_t = protocol::PORT, help = "Specifies the TCP and UDP port used by the server")]
+    port: u16,
+    #[arg(
+        long,
+        env = "CRUSADER_TOKEN",
+        hide_env_values = true,
+        help = "The token used to authenticate with the server and peer"
+    )]
+    token: Option<String>,
+    #[command(flatten)]
+    tls: TlsArgs,
+    #[arg(
+        long,
+        default_value_t = 8,
+        help = "The number of TCP connections used to generate traffic in a single direction"
+    )]
+    streams: u64,
+    #[arg(
+        long,
+        default_value_t = 0.0,
+        value_name = "SECONDS",
+        help = "The delay between the start of each stream"
+    )]
+    stream_stagger: f64,
+    #[arg(
+        long,
+        default_value_t = 10.0,
+        value_name = "SECONDS",
+        help = "The duration in which traffic is generated"
+    )]
+    load_duration: f64,
+    #[arg(
+        long,
+        default_value_t = 2.0,
+        value_name = "SECONDS",
+        help = "The idle time between each test"
+    )]
+    grace_duration: f64,
+    #[arg(long, default_value_t = 5, value_name = "MILLISECONDS")]
+    latency_sample_interval: u64,
+    #[arg(long, default_value_t = 60, value_name = "MILLISECONDS")]
+    throughput_sample_interval: u64,
+    #[command(flatten)]
+    plot: PlotArgs,
+    #[arg(
+        long,
+        long_help = "Specifies another server (peer) which will also measure the latency to the server independently of the client"
+    )]
+    latency_peer_address: Option<String>,
+    #[arg(
+        long,
+        help = "Use another server (peer) which will also measure the latency to the server independently of the client"
+    )]
+    latency_peer: bool,
+    #[command(flatten)]
+    peer_tls: PeerTlsArgs,
+    #[arg(
+        long,
+        help = "The filename prefix used for the test result raw data and plot filenames"
+    )]
+    out_name: Option<String>,
+    #[arg(long, value_name = "TEXT", help = "Notes stored with the result")]
+    note: Option<String>,
+    #[arg(
+        long,
+        value_name = "TAG",
+        help = "A tag stored with the result. Can be given multiple times"
+    )]
+    tag: Vec<String>,
+}
+
+#[cfg(feature = "client")]
+#[derive(Copy, Clone, ValueEnum)]
+enum ExportFormat {
+    /// The data of the result file as JSON, which `import` can convert back to a result file
+    Json,
+    /// The result with the summary of each test as JSON, in a stable format described by
+    /// `export.schema.json`
+    Summary,
+    Csv,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     #[command(about = "Runs the server")]
-    Serve {
-        #[arg(long, default_value_t = protocol::PORT, help = "Specifies the TCP and UDP port used by the server")]
-        port: u16,
-        #[arg(long, help = "Allow use and discovery as a peer")]
-        peer: bool,
-    },
+    Serve(Box<ServeArgs>),
+    #[command(about = "Controls a running server through its admin interface")]
+    ServeCtl(ServeCtlArgs),
     #[command(
         long_about = "Runs a test client against a specified server and saves the result to the current directory. \
         By default this does a download test, an upload test, and a test doing both download and upload while measuring the latency to the server"
     )]
     #[cfg(feature = "client")]
-    Test {
-        server: Option<String>,
-        #[arg(long, help = "Run a download test")]
-        download: bool,
-        #[arg(long, help = "Run an upload test")]
-        upload: bool,
-        #[arg(long, help = "Run a test doing both download and upload")]
-        bidirectional: bool,
-        #[arg(
-            long,
-            long_help = "Run a test only measuring latency. The duration is specified by `grace_duration`"
-        )]
-        idle: bool,
-        #[arg(long, default_value_t = protocol::PORT, help = "Specifies the TCP and UDP port used by the server")]
-        port: u16,
+    Test(Box<TestArgs>),
+    #[cfg(feature = "client")]
+    #[command(about = "Lists the servers found on the local network")]
+    Discover {
+        #[arg(long, help = "List latency peers instead of servers")]
+        peer: bool,
         #[arg(
             long,
-            default_value_t = 8,
-            help = "The number of TCP connections used to generate traffic in a single direction"
+            default_value_t = discovery::DISCOVER_PORT,
+            help = "The UDP port servers receive discovery requests on"
         )]
-        streams: u64,
+        discovery_port: u16,
+    },
+    #[cfg(feature = "client")]
+    #[command(about = "Plots a previous result")]
+    Plot {
+        data: PathBuf,
+        #[command(flatten)]
+        plot: PlotArgs,
+    },
+    #[cfg(feature = "client")]
+    #[command(about = "Allows the client to be controlled over a web server")]
+    Remote {
         #[arg(
             long,
-            default_value_t = 0.0,
-            value_name = "SECONDS",
-            help = "The delay between the start of each stream"
+            default_value_t = protocol::PORT + 1,
+            help = "Specifies the HTTP port used by the server"
         )]
-        stream_stagger: f64,
+        port: u16,
         #[arg(
             long,
-            default_value_t = 10.0,
-            value_name = "SECONDS",
-            help = "The duration in which traffic is generated"
+            value_name = "IP",
+            long_help = "Only listen on this address. Can be given multiple times. \
+                By default the server listens on all addresses"
         )]
-        load_duration: f64,
+        listen: Vec<IpAddr>,
+    },
+    #[cfg(feature = "client")]
+    #[command(about = "Converts a result file to JSON or CSV")]
+    Export {
+        data: PathBuf,
         #[arg(
             long,
-            default_value_t = 2.0,
-            value_name = "SECONDS",
-            help = "The idle time between each test"
+            short('o'),
+            long_help = "The path where the output JSON will be stored. \
+                For CSV this is the prefix of the `.latency.csv` and `.throughput.csv` files"
         )]
-        grace_duration: f64,
-        #[arg(long, default_value_t = 5, value_name = "MILLISECONDS")]
-        latency_sample_interval: u64,
-        #[arg(long, default_value_t = 60, value_name = "MILLISECONDS")]
-        throughput_sample_interval: u64,
+        output: Option<PathBuf>,
+        #[arg(long, short('f'), help = "Overwrite the file if it exists")]
+        force: bool,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+    },
+    #[cfg(feature = "client")]
+    #[command(
+        long_about = "Compares the summaries of results and plots their throughput and latency together. \
+        The differences are relative to the first result"
+    )]
+    Compare {
+        #[arg(required = true, num_args = 2..)]
+        files: Vec<PathBuf>,
         #[command(flatten)]
         plot: PlotArgs,
+    },
+    #[cfg(feature = "client")]
+    #[command(
+        long_about = "Shows the distribution of the throughput and latency of many results \
+        and how they changed over time"
+    )]
+    Stats {
         #[arg(
-            long,
-            long_help = "Specifies another server (peer) which will also measure the latency to the server independently of the client"
-        )]
-        latency_peer_address: Option<String>,
-        #[arg(
-            long,
-            help = "Use another server (peer) which will also measure the latency to the server independently of the client"
+            required = true,
+            value_name = "PATH",
+            help = "Result files or directories with `.crr` files"
         )]
-        latency_peer: bool,
+        paths: Vec<PathBuf>,
+        #[arg(long, help = "Save a plot of the throughput and latency over time")]
+        plot: bool,
+    },
+    #[cfg(feature = "client")]
+    #[command(
+        long_about = "Saves the part of a result within a time window as a new result. \
+        Times are in seconds from the start of the test, as shown on plots"
+    )]
+    Trim {
+        data: PathBuf,
+        #[arg(long, value_name = "SECONDS", help = "The start of the window")]
+        from: Option<f64>,
+        #[arg(long, value_name = "SECONDS", help = "The end of the window")]
+        to: Option<f64>,
         #[arg(
             long,
-            help = "The filename prefix used for the test result raw data and plot filenames"
+            short('o'),
+            help = "The path where the result file will be stored"
         )]
-        out_name: Option<String>,
+        output: Option<PathBuf>,
+        #[arg(long, short('f'), help = "Overwrite the file if it exists")]
+        force: bool,
     },
     #[cfg(feature = "client")]
-    #[command(about = "Plots a previous result")]
-    Plot {
+    #[command(
+        visible_alias = "anonymise",
+        long_about = "Saves a copy of a result for sharing. Addresses and host names are replaced \
+        by hashes, and build details and the interface name are removed. Measurements, notes and tags are kept"
+    )]
+    Anonymize {
         data: PathBuf,
-        #[command(flatten)]
-        plot: PlotArgs,
-    },
-    #[cfg(feature = "client")]
-    #[command(about = "Allows the client to be controlled over a web server")]
-    Remote {
         #[arg(
             long,
-            default_value_t = protocol::PORT + 1,
-            help = "Specifies the HTTP port used by the server"
+            short('o'),
+            help = "The path where the result file will be stored"
         )]
-        port: u16,
+        output: Option<PathBuf>,
+        #[arg(long, short('f'), help = "Overwrite the file if it exists")]
+        force: bool,
+        #[arg(
+            long,
+            value_name = "TEXT",
+            long_help = "A secret used to hash addresses and host names. \
+                Results anonymized with the same key use the same names for the same hosts. \
+                By default a random key is used"
+        )]
+        key: Option<String>,
     },
     #[cfg(feature = "client")]
-    #[command(about = "Converts a result file to JSON")]
-    Export {
+    #[command(about = "Describes result files and checks them for consistency")]
+    Info {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        #[arg(long, help = "Print the descriptions as JSON")]
+        json: bool,
+    },
+    #[cfg(feature = "client")]
+    #[command(about = "Converts a result exported as JSON back to a result file")]
+    Import {
         data: PathBuf,
         #[arg(
             long,
             short('o'),
-            help = "The path where the output JSON will be stored"
+            help = "The path where the result file will be stored"
         )]
         output: Option<PathBuf>,
         #[arg(long, short('f'), help = "Overwrite the file if it exists")]
@@ -181,24 +440,33 @@ fn run() -> Result<(), anyhow::Error> {
 
     match &cli.command {
         #[cfg(feature = "client")]
-        &Commands::Test {
-            ref server,
-            download,
-            upload,
-            bidirectional,
-            idle,
-            throughput_sample_interval,
-            latency_sample_interval,
-            ref plot,
-            port,
-            streams,
-            stream_stagger,
-            grace_duration,
-            load_duration,
-            ref latency_peer_address,
-            latency_peer,
-            ref out_name,
-        } => {
+        Commands::Test(args) => {
+            let TestArgs {
+                ref server,
+                lowest_latency,
+                discovery_port,
+                download,
+                upload,
+                bidirectional,
+                idle,
+                throughput_sample_interval,
+                latency_sample_interval,
+                ref plot,
+                port,
+                ref token,
+                ref tls,
+                streams,
+                stream_stagger,
+                grace_duration,
+                load_duration,
+                ref latency_peer_address,
+                latency_peer,
+                ref peer_tls,
+                ref out_name,
+                ref note,
+                ref tag,
+            } = **args;
+
             let mut config = Config {
                 port,
                 streams,
@@ -210,6 +478,11 @@ fn run() -> Result<(), anyhow::Error> {
                 bidirectional: !idle,
                 ping_interval: Duration::from_millis(latency_sample_interval),
                 throughput_interval: Duration::from_millis(throughput_sample_interval),
+                secret: token.as_deref().and_then(Secret::from_token),
+                tls: tls.verification()?,
+                peer_tls: peer_tls.verification()?,
+                lowest_latency,
+                discovery_port,
             };
 
             if download || upload || bidirectional {
@@ -229,16 +502,63 @@ fn run() -> Result<(), anyhow::Error> {
                 (latency_peer || latency_peer_address.is_some())
                     .then_some(latency_peer_address.as_deref()),
                 out_name.as_deref().unwrap_or("test"),
+                note.clone(),
+                tag.clone(),
             )
         }
-        &Commands::Serve { port, peer } => crusader_lib::serve::serve(port, peer),
+        Commands::Serve(args) => {
+            // Sockets passed by systemd are taken before the runtime starts any threads
+            let listeners = systemd::listeners()?;
+            serve::serve(args.config(listeners)?)
+        }
+        Commands::ServeCtl(args) => serve_ctl::run(args),
+
+        #[cfg(feature = "client")]
+        Commands::Discover {
+            peer,
+            discovery_port,
+        } => {
+            let servers = discovery::discover(*peer, *discovery_port)?;
+            if servers.is_empty() {
+                println!("No {} found", if *peer { "peers" } else { "servers" });
+            }
+            for server in servers {
+                println!(
+                    "{}{}",
+                    server.at,
+                    server
+                        .label
+                        .as_ref()
+                        .map(|label| format!(" ({label})"))
+                        .unwrap_or_default()
+                );
+                println!(
+                    "  Version: {} (protocol {}, {})",
+                    server.software_version,
+                    server.protocol_version,
+                    if server.compatible() {
+                        "compatible"
+                    } else {
+                        "incompatible"
+                    }
+                );
+                let addresses: Vec<_> = server.addresses.iter().map(|ip| ip.to_string()).collect();
+                println!("  Addresses: {}", addresses.join(", "));
+                println!(
+                    "  Latency: {:.02} ms",
+                    server.latency.as_secs_f64() * 1000.0
+                );
+            }
+            Ok(())
+        }
 
         #[cfg(feature = "client")]
-        Commands::Remote { port } => crusader_lib::remote::run(*port),
+        Commands::Remote { port, listen } => crusader_lib::remote::run(*port, listen.clone()),
 
         #[cfg(feature = "client")]
         Commands::Plot { data, plot } => {
-            let result = RawResult::load(data).ok_or(anyhow!("Unable to load data"))?;
+            let result = RawResult::load(data)
+                .with_context(|| format!("Unable to load {}", data.display()))?;
             let root = data.parent().unwrap_or(Path::new(""));
             let file = crusader_lib::plot::save_graph(
                 &plot.config(),
@@ -259,8 +579,25 @@ fn run() -> Result<(), anyhow::Error> {
             data,
             output,
             force,
+            format: ExportFormat::Csv,
         } => {
-            let result = RawResult::load(data).ok_or(anyhow!("Unable to load data"))?;
+            let result = RawResult::load(data)
+                .with_context(|| format!("Unable to load {}", data.display()))?;
+            let stem = output.clone().unwrap_or_else(|| data.with_extension(""));
+            for file in export::save_csv(&result.to_test_result(), &stem, *force)? {
+                println!("{}", with_time(&format!("Saved {}", file.display())));
+            }
+            Ok(())
+        }
+        #[cfg(feature = "client")]
+        Commands::Export {
+            data,
+            output,
+            force,
+            format: format @ (ExportFormat::Json | ExportFormat::Summary),
+        } => {
+            let result = RawResult::load(data)
+                .with_context(|| format!("Unable to load {}", data.display()))?;
             let output = output
                 .clone()
                 .unwrap_or_else(|| data.with_extension("json"));
@@ -272,14 +609,258 @@ fn run() -> Result<(), anyhow::Error> {
                 .open(output)
                 .context("Failed to create output file")?;
             let mut file = BufWriter::new(file);
-            serde_json::to_writer_pretty(&mut file, &result).context("Failed to serialize data")?;
+            match format {
+                ExportFormat::Summary => serde_json::to_writer_pretty(
+                    &mut file,
+                    &export::Export::new(&result.to_test_result()),
+                ),
+                _ => serde_json::to_writer_pretty(&mut file, &result),
+            }
+            .context("Failed to serialize data")?;
             file.flush().context("Failed to flush output")?;
 
             Ok(())
         }
+        #[cfg(feature = "client")]
+        Commands::Import {
+            data,
+            output,
+            force,
+        } => {
+            let input = File::open(data).context("Failed to open input file")?;
+            let result = RawResult::from_json(input)?;
+            let output = output.clone().unwrap_or_else(|| data.with_extension("crr"));
+            let file = OpenOptions::new()
+                .create_new(!*force)
+                .create(*force)
+                .truncate(true)
+                .write(true)
+                .open(output)
+                .context("Failed to create output file")?;
+            result.save_to_writer(file)?;
+
+            Ok(())
+        }
+        #[cfg(feature = "client")]
+        Commands::Compare { files, plot } => {
+            let results = files
+                .iter()
+                .map(|file| {
+                    RawResult::load(file)
+                        .map(|result| result.to_test_result())
+                        .with_context(|| format!("Unable to load {}", file.display()))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let runs: Vec<_> = files
+                .iter()
+                .zip(&results)
+                .map(|(file, result)| Run {
+                    name: file
+                        .file_stem()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned(),
+                    result,
+                })
+                .collect();
+
+            print!("{}", compare::table(&runs)?);
+
+            let root = files[0].parent().unwrap_or(Path::new(""));
+            let file = crusader_lib::plot::save_comparison_graph(
+                &plot.config(),
+                &runs,
+                &timed("compare"),
+                root,
+            )?;
+            println!();
+            println!(
+                "{}",
+                with_time(&format!("Saved plot as {}", root.join(file).display()))
+            );
+            Ok(())
+        }
+        #[cfg(feature = "client")]
+        Commands::Stats { paths, plot } => {
+            let mut files = Vec::new();
+            for path in paths {
+                if path.is_dir() {
+                    let mut entries: Vec<_> = fs::read_dir(path)
+                        .with_context(|| format!("Unable to read {}", path.display()))?
+                        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                        .filter(|file| file.extension() == Some(OsStr::new("crr")))
+                        .collect();
+                    entries.sort();
+                    files.extend(entries);
+                } else {
+                    files.push(path.clone());
+                }
+            }
+
+            let mut samples = Vec::new();
+            for file in &files {
+                match RawResult::load(file) {
+                    Ok(result) => samples.push(Sample::new(
+                        file.file_stem()
+                            .unwrap_or_default()
+                            .to_string_lossy()
+                            .into_owned(),
+                        &result.to_test_result(),
+                    )),
+                    Err(error) => {
+                        eprintln!("Warning: Unable to load {}: {}", file.display(), error)
+                    }
+                }
+            }
+            if samples.is_empty() {
+                anyhow::bail!("No results found");
+            }
+            stats::sort_by_time(&mut samples);
+
+            print!("{}", stats::report(&samples)?);
+
+            if *plot {
+                let root = if paths[0].is_dir() {
+                    paths[0].as_path()
+                } else {
+                    paths[0].parent().unwrap_or(Path::new(""))
+                };
+                let file = crusader_lib::plot::save_trend_graph(
+                    &PlotConfig::default(),
+                    &samples,
+                    &timed("stats"),
+                    root,
+                )?;
+                println!();
+                println!(
+                    "{}",
+                    with_time(&format!("Saved plot as {}", root.join(file).display()))
+                );
+            }
+            Ok(())
+        }
+        #[cfg(feature = "client")]
+        Commands::Trim {
+            data,
+            from,
+            to,
+            output,
+            force,
+        } => {
+            let result = RawResult::load(data)
+                .with_context(|| format!("Unable to load {}", data.display()))?;
+            let seconds = |value: f64| {
+                Duration::try_from_secs_f64(value)
+                    .with_context(|| format!("Invalid time {}", value))
+            };
+            let result = result.trim(
+                seconds(from.unwrap_or(0.0))?,
+                to.map(seconds).transpose()?.unwrap_or(result.duration),
+            )?;
+            let output = output.clone().unwrap_or_else(|| {
+                let stem = data.file_stem().unwrap_or_default().to_string_lossy();
+                data.with_file_name(format!("{} trimmed.crr", stem))
+            });
+            let file = OpenOptions::new()
+                .create_new(!*force)
+                .create(*force)
+                .truncate(true)
+                .write(true)
+                .open(&output)
+                .context("Failed to create output file")?;
+            result.save_to_writer(file)?;
+            println!(
+                "{}",
+                with_time(&format!("Saved trimmed result as {}", output.display()))
+            );
+
+            Ok(())
+        }
+        #[cfg(feature = "client")]
+        Commands::Anonymize {
+            data,
+            output,
+            force,
+            key,
+        } => {
+            let result = RawResult::load(data)
+                .with_context(|| format!("Unable to load {}", data.display()))?;
+            let output = output.clone().unwrap_or_else(|| {
+                let stem = data.file_stem().unwrap_or_default().to_string_lossy();
+                data.with_file_name(format!("{} anonymized.crr", stem))
+            });
+            let file = OpenOptions::new()
+                .create_new(!*force)
+                .create(*force)
+                .truncate(true)
+                .write(true)
+                .open(&output)
+                .context("Failed to create output file")?;
+            result.anonymize(key.as_deref()).save_to_writer(file)?;
+            println!(
+                "{}",
+                with_time(&format!("Saved anonymized result as {}", output.display()))
+            );
+
+            Ok(())
+        }
+        #[cfg(feature = "client")]
+        Commands::Info { files, json } => {
+            let infos: Vec<_> = files
+                .iter()
+                .map(|file| match RawResult::load(file) {
+                    Ok(result) => FileInfo {
+                        file,
+                        info: Some(ResultInfo::new(&result)),
+                        error: None,
+                    },
+                    Err(error) => FileInfo {
+                        file,
+                        info: None,
+                        error: Some(error.to_string()),
+                    },
+                })
+                .collect();
+
+            if *json {
+                serde_json::to_writer_pretty(io::stdout(), &infos)
+                    .context("Failed to serialize data")?;
+                println!();
+            } else {
+                for (i, info) in infos.iter().enumerate() {
+                    if i > 0 {
+                        println!();
+                    }
+                    println!("== {} ==", info.file.display());
+                    if let Some(error) = &info.error {
+                        println!("Error: {}", error);
+                    }
+                    if let Some(info) = &info.info {
+                        print!("{}", info);
+                    }
+                }
+            }
+
+            let failed = infos.iter().filter(|info| info.error.is_some()).count();
+            if failed > 0 {
+                anyhow::bail!("Unable to load {} of {} files", failed, infos.len());
+            }
+            Ok(())
+        }
     }
 }
 
+/// The description of a result file printed by the `info` command.
+#[cfg(feature = "client")]
+#[derive(Serialize)]
+struct FileInfo<'a> {
+    file: &'a Path,
+    #[serde(flatten)]
+    info: Option<ResultInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
 fn main() {
     env_logger::init();
 
@@ -0,0 +1,313 @@
+use anyhow::{anyhow, Context};
+use clap::ValueEnum;
+use crusader_lib::access::{parse_net, Access, AccessList, IpNet};
+use crusader_lib::auth::Secret;
+use crusader_lib::serve;
+use crusader_lib::tls::ServerTls;
+use serde::Deserialize;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Options for the `serve` command. These override the values in the configuration file.
+#[derive(clap::Args)]
+pub struct ServeArgs {
+    #[arg(
+        long,
+        value_name = "PATH",
+        long_help = "Read the server configuration from this TOML file. \
+            Command line options override the values in the file"
+    )]
+    config: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "IP",
+        long_help = "Only listen on this address. Can be given multiple times. \
+            By default the server listens on all addresses"
+    )]
+    listen: Vec<IpAddr>,
+    #[arg(
+        long,
+        help = "Specifies the TCP port used by the server [default: 35481]"
+    )]
+    port: Option<u16>,
+    #[arg(
+        long,
+        help = "Specifies the UDP port used for latency measurements [default: the TCP port]"
+    )]
+    udp_port: Option<u16>,
+    #[arg(
+        long,
+        long_help = "Specifies the UDP port used for local discovery. \
+            Clients must be given the same port with `--discovery-port` [default: 35483]"
+    )]
+    discovery_port: Option<u16>,
+    #[arg(
+        long,
+        overrides_with = "no_discovery",
+        help = "Respond to discovery requests on the local network [default]"
+    )]
+    discovery: bool,
+    #[arg(
+        long,
+        overrides_with = "discovery",
+        help = "Don't respond to discovery requests on the local network"
+    )]
+    no_discovery: bool,
+    #[arg(long, help = "A label sent to clients discovering the server")]
+    label: Option<String>,
+    #[arg(
+        long,
+        overrides_with = "no_peer",
+        help = "Allow use and discovery as a peer"
+    )]
+    peer: bool,
+    #[arg(
+        long,
+        overrides_with = "peer",
+        help = "Don't allow use as a peer [default]"
+    )]
+    no_peer: bool,
+    #[arg(
+        long,
+        help = "The maximum number of clients which can use the server at once [default: 1000]"
+    )]
+    max_clients: Option<usize>,
+    #[arg(
+        long,
+        env = "CRUSADER_TOKEN",
+        hide_env_values = true,
+        long_help = "Require clients to authenticate with this token. \
+            The token is also used when connecting to a server as a peer"
+    )]
+    token: Option<String>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "tls_key",
+        help = "Use TLS with the certificate chain stored in this PEM file"
+    )]
+    tls_cert: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "tls_cert",
+        help = "The private key for the TLS certificate in PEM format"
+    )]
+    tls_key: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "CIDR",
+        value_parser = parse_net,
+        long_help = "Only allow clients from this network. Can be given multiple times"
+    )]
+    allow: Vec<IpNet>,
+    #[arg(
+        long,
+        value_name = "CIDR",
+        value_parser = parse_net,
+        long_help = "Reject clients from this network. Can be given multiple times"
+    )]
+    deny: Vec<IpNet>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        long_help = "Read additional `allow <CIDR>` and `deny <CIDR>` rules from this file, \
+            one per line. The file is reloaded when it changes"
+    )]
+    access_list: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        long_help = "Serve Prometheus metrics over HTTP at `/metrics` on this address, \
+            for example `127.0.0.1:9090`"
+    )]
+    metrics: Option<SocketAddr>,
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        long_help = "Accept admin connections on this address, for example `127.0.0.1:35484`. \
+            Use `crusader serve-ctl` to list and terminate sessions. \
+            Addresses other than loopback addresses require `--admin-token`"
+    )]
+    admin: Option<SocketAddr>,
+    #[arg(
+        long,
+        env = "CRUSADER_ADMIN_TOKEN",
+        hide_env_values = true,
+        long_help = "Require admin connections to authenticate with this token. \
+            This is separate from the token used by clients"
+    )]
+    admin_token: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        long_help = "The format of the server log. `json` writes one JSON record per line \
+            for each event, such as tests, load streams and errors [default: text]"
+    )]
+    log_format: Option<LogFormat>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        long_help = "How long to wait for running tests to complete when the server is stopped \
+            with Ctrl-C or SIGTERM. A second signal exits immediately [default: 30]"
+    )]
+    shutdown_timeout: Option<u64>,
+}
+
+/// The server configuration file. All keys are optional.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    listen: Vec<IpAddr>,
+    port: Option<u16>,
+    udp_port: Option<u16>,
+    discovery_port: Option<u16>,
+    discovery: Option<bool>,
+    label: Option<String>,
+    peer: Option<bool>,
+    max_clients: Option<usize>,
+    token: Option<String>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    access_list: Option<PathBuf>,
+    metrics: Option<SocketAddr>,
+    admin: Option<SocketAddr>,
+    admin_token: Option<String>,
+    log_format: Option<LogFormat>,
+    shutdown_timeout: Option<u64>,
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read config file `{}`", path.display()))?;
+        let mut file: ConfigFile = toml::from_str(&data)
+            .with_context(|| format!("Invalid config file `{}`", path.display()))?;
+
+        // Paths in the file are relative to the file itself
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for path in [&mut file.tls_cert, &mut file.tls_key, &mut file.access_list]
+            .into_iter()
+            .flatten()
+        {
+            *path = dir.join(&*path);
+        }
+
+        Ok(file)
+    }
+}
+
+/// Resolves a pair of `--<flag>` and `--no-<flag>` options. The last one given wins.
+fn flag(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_nets(nets: &[String]) -> Result<Vec<IpNet>, anyhow::Error> {
+    nets.iter().map(|net| parse_net(net)).collect()
+}
+
+impl ServeArgs {
+    /// Combines the configuration file with the command line options.
+    /// The TCP port of `listeners` takes precedence over the configured port.
+    pub fn config(
+        &self,
+        listeners: Vec<std::net::TcpListener>,
+    ) -> Result<serve::Config, anyhow::Error> {
+        let file = match &self.config {
+            Some(path) => ConfigFile::load(path)?,
+            None => ConfigFile::default(),
+        };
+
+        let tls_cert = self.tls_cert.as_ref().or(file.tls_cert.as_ref());
+        let tls_key = self.tls_key.as_ref().or(file.tls_key.as_ref());
+        let tls = match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => Some(ServerTls::load(cert, key)?),
+            (None, None) => None,
+            _ => return Err(anyhow!("Both a TLS certificate and key are required")),
+        };
+
+        let allow = if self.allow.is_empty() {
+            parse_nets(&file.allow).context("Invalid `allow` in config file")?
+        } else {
+            self.allow.clone()
+        };
+        let deny = if self.deny.is_empty() {
+            parse_nets(&file.deny).context("Invalid `deny` in config file")?
+        } else {
+            self.deny.clone()
+        };
+
+        let defaults = serve::Config::default();
+        let port = match listeners.first() {
+            Some(listener) => listener.local_addr()?.port(),
+            None => self.port.or(file.port).unwrap_or(defaults.port),
+        };
+
+        Ok(serve::Config {
+            listen: if self.listen.is_empty() {
+                file.listen
+            } else {
+                self.listen.clone()
+            },
+            listeners,
+            port,
+            udp_port: self.udp_port.or(file.udp_port).unwrap_or(port),
+            discovery: flag(self.discovery, self.no_discovery)
+                .or(file.discovery)
+                .unwrap_or(defaults.discovery),
+            discovery_port: self
+                .discovery_port
+                .or(file.discovery_port)
+                .unwrap_or(defaults.discovery_port),
+            label: self.label.clone().or(file.label),
+            max_clients: self
+                .max_clients
+                .or(file.max_clients)
+                .unwrap_or(defaults.max_clients),
+            peer_server: flag(self.peer, self.no_peer)
+                .or(file.peer)
+                .unwrap_or(defaults.peer_server),
+            secret: self
+                .token
+                .as_deref()
+                .or(file.token.as_deref())
+                .and_then(Secret::from_token),
+            tls,
+            access: Access {
+                list: AccessList { allow, deny },
+                file: self.access_list.clone().or(file.access_list),
+            },
+            metrics: self.metrics.or(file.metrics),
+            admin: self.admin.or(file.admin),
+            admin_secret: self
+                .admin_token
+                .as_deref()
+                .or(file.admin_token.as_deref())
+                .and_then(Secret::from_token),
+            log_format: match self.log_format.or(file.log_format) {
+                Some(LogFormat::Json) => serve::LogFormat::Json,
+                Some(LogFormat::Text) | None => serve::LogFormat::Text,
+            },
+            shutdown_timeout: self
+                .shutdown_timeout
+                .or(file.shutdown_timeout)
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.shutdown_timeout),
+        })
+    }
+}
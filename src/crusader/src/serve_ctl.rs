@@ -0,0 +1,91 @@
+use anyhow::bail;
+use clap::Subcommand;
+use crusader_lib::admin::{self, AdminRequest, AdminResponse};
+use crusader_lib::auth::Secret;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Options for the `serve-ctl` command.
+#[derive(clap::Args)]
+pub struct ServeCtlArgs {
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        default_value = admin::DEFAULT_ADDRESS,
+        help = "The address of the admin interface of the server"
+    )]
+    admin: SocketAddr,
+    #[arg(
+        long,
+        env = "CRUSADER_ADMIN_TOKEN",
+        hide_env_values = true,
+        help = "The admin token of the server"
+    )]
+    token: Option<String>,
+    #[command(subcommand)]
+    command: ServeCtlCommand,
+}
+
+#[derive(Subcommand)]
+enum ServeCtlCommand {
+    #[command(about = "Lists the clients and peer sessions of the server")]
+    Status,
+    #[command(about = "Terminates the test of the client using a slot")]
+    Kill {
+        slot: u64,
+        #[arg(long, help = "Terminate the peer session with this id instead")]
+        peer: bool,
+    },
+}
+
+fn running_for(started: u64) -> String {
+    let started = UNIX_EPOCH + Duration::from_millis(started);
+    let running = SystemTime::now()
+        .duration_since(started)
+        .unwrap_or_default();
+    format!("running for {} s", running.as_secs())
+}
+
+pub fn run(args: &ServeCtlArgs) -> Result<(), anyhow::Error> {
+    let secret = args.token.as_deref().and_then(Secret::from_token);
+    let request = match args.command {
+        ServeCtlCommand::Status => AdminRequest::Status,
+        ServeCtlCommand::Kill { slot, peer: false } => AdminRequest::Kill { slot },
+        ServeCtlCommand::Kill { slot, peer: true } => AdminRequest::KillPeer { id: slot },
+    };
+
+    match admin::request(args.admin, secret, request)? {
+        AdminResponse::Status(status) => {
+            if status.clients.is_empty() {
+                println!("No active clients");
+            } else {
+                println!("Clients:");
+            }
+            for client in status.clients {
+                println!(
+                    "  Slot {}: {}, {}, {} active load streams{}",
+                    client.slot,
+                    client.addr,
+                    running_for(client.started),
+                    client.loads,
+                    if client.overload { ", overloaded" } else { "" }
+                );
+            }
+            if !status.peers.is_empty() {
+                println!("Peer sessions:");
+            }
+            for peer in status.peers {
+                println!(
+                    "  Peer {}: {} measuring latency to {}, {}",
+                    peer.id,
+                    peer.addr,
+                    peer.server,
+                    running_for(peer.started)
+                );
+            }
+        }
+        AdminResponse::Killed => println!("Session terminated"),
+        AdminResponse::NotFound => bail!("No such session"),
+    }
+    Ok(())
+}